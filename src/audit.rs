@@ -0,0 +1,192 @@
+use anyhow::{Context, Result};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// `prev_hash` of the first record in a chained log.
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+pub struct Record<'a> {
+    pub action: &'a str,
+    pub volume: &'a str,
+    pub nas: &'a str,
+    pub success: bool,
+    pub finished_at: SystemTime,
+}
+
+/// The run whose records are still to be written; see [`begin`].
+static RUN: Mutex<Option<Run>> = Mutex::new(None);
+
+struct Run {
+    path: PathBuf,
+    hash_chain: bool,
+    action: &'static str,
+    volumes: Vec<Volume>,
+}
+
+struct Volume {
+    name: String,
+    nas: String,
+    /// Whether it succeeded and when it finished; `None` while it has not.
+    outcome: Option<(bool, SystemTime)>,
+}
+
+/// Starts collecting the audit records of `action` on `volumes`, given as
+/// volume and NAS names, to be written to `path` by [`finish`].
+pub fn begin(path: &Path, hash_chain: bool, action: &'static str, volumes: Vec<(String, String)>) {
+    let volumes = volumes
+        .into_iter()
+        .map(|(name, nas)| Volume {
+            name,
+            nas,
+            outcome: None,
+        })
+        .collect();
+    *RUN.lock().unwrap_or_else(|e| e.into_inner()) = Some(Run {
+        path: path.to_path_buf(),
+        hash_chain,
+        action,
+        volumes,
+    });
+}
+
+/// Notes the outcome of `volume` in the run started with [`begin`].
+pub fn settle(volume: &str, success: bool) {
+    let mut run = RUN.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(run) = run.as_mut() else {
+        return;
+    };
+    for entry in run.volumes.iter_mut().filter(|entry| entry.name == volume) {
+        entry.outcome = Some((success, SystemTime::now()));
+    }
+}
+
+/// Writes the records of the run started with [`begin`], counting volumes
+/// that have not finished as failures, and ends it, so a second call writes
+/// nothing. Called when the run ends, and by the watchdog before it exits
+/// the process on `--timeout` or `--timeout-total`.
+pub fn finish() -> Result<()> {
+    let mut run = RUN.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(run) = run.take() else {
+        return Ok(());
+    };
+    let now = SystemTime::now();
+    let records: Vec<Record<'_>> = run
+        .volumes
+        .iter()
+        .map(|volume| {
+            let (success, finished_at) = volume.outcome.unwrap_or((false, now));
+            Record {
+                action: run.action,
+                volume: &volume.name,
+                nas: &volume.nas,
+                success,
+                finished_at,
+            }
+        })
+        .collect();
+    append(&run.path, &records, run.hash_chain)
+}
+
+/// Appends one JSON line per record. With `hash_chain`, every line carries
+/// `prev_hash`, the SHA-256 of the previous line, so editing or removing an
+/// earlier line breaks the chain from that point on. The file is locked
+/// while the last line is read and the new ones written, so runs that finish
+/// at the same time do not fork the chain.
+pub fn append(path: &Path, records: &[Record<'_>], hash_chain: bool) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open audit log {}", path.display()))?;
+    file.lock()
+        .with_context(|| format!("failed to lock audit log {}", path.display()))?;
+
+    let mut prev_hash = if hash_chain {
+        let mut content = String::new();
+        file.read_to_string(&mut content)
+            .with_context(|| format!("failed to read audit log {}", path.display()))?;
+        Some(last_line_hash(&content))
+    } else {
+        None
+    };
+
+    let mut out = String::new();
+    for record in records {
+        let mut line = json!({
+            "timestamp": humantime::format_rfc3339_seconds(record.finished_at).to_string(),
+            "action": record.action,
+            "volume": record.volume,
+            "nas": record.nas,
+            "outcome": if record.success { "success" } else { "failure" },
+        });
+        if let Some(prev) = &prev_hash {
+            line["prev_hash"] = json!(prev);
+        }
+        let line = line.to_string();
+        if prev_hash.is_some() {
+            prev_hash = Some(hex::encode(Sha256::digest(line.as_bytes())));
+        }
+        out.push_str(&line);
+        out.push('\n');
+    }
+
+    file.write_all(out.as_bytes())
+        .with_context(|| format!("failed to write audit log {}", path.display()))
+}
+
+fn last_line_hash(content: &str) -> String {
+    match content.lines().rev().find(|line| !line.trim().is_empty()) {
+        Some(line) => hex::encode(Sha256::digest(line.as_bytes())),
+        None => GENESIS_HASH.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn record<'a>(volume: &'a str, success: bool) -> Record<'a> {
+        Record {
+            action: "unlock",
+            volume,
+            nas: "home",
+            success,
+            finished_at: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    #[test]
+    fn hash_chain_links_every_line_to_the_one_before() {
+        let path = std::env::temp_dir().join(format!("naslock-audit-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        append(
+            &path,
+            &[record("media", true), record("backup", false)],
+            true,
+        )
+        .unwrap();
+        append(&path, &[record("media", true)], true).unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 3);
+        let mut expected = GENESIS_HASH.to_string();
+        for line in &lines {
+            let value: Value = serde_json::from_str(line).unwrap();
+            assert_eq!(value["prev_hash"], expected.as_str(), "{}", line);
+            expected = hex::encode(Sha256::digest(line.as_bytes()));
+        }
+        let second: Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(second["volume"], "backup");
+        assert_eq!(second["outcome"], "failure");
+        assert_eq!(second["timestamp"], "1970-01-01T00:00:00Z");
+    }
+}
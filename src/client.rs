@@ -0,0 +1,453 @@
+use crate::config::{self, Config, NasConfig, VolumeConfig};
+use crate::error::{self, ErrorKind};
+use crate::secrets::{self, Stores};
+use crate::truenas::{self, JobInfo, LockResult, UnlockResult};
+use crate::websocket;
+use anyhow::{Context, Result, bail};
+use std::path::Path;
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+/// Unlocks and locks the volumes of a loaded [`Config`] from another
+/// program, reading secrets and checking results the way `naslock` does.
+///
+/// KeePass databases are opened once, in [`Client::open`]; each call then
+/// connects to the volume's NAS, performs the operation and waits for the
+/// job to finish. Nothing is printed apart from the warnings `naslock` itself
+/// prints to stderr.
+pub struct Client<'a> {
+    cfg: &'a Config,
+    stores: Stores,
+}
+
+impl<'a> Client<'a> {
+    /// Opens the KeePass databases that the named volumes need, calling
+    /// `password` for each database's master password.
+    pub fn open(
+        cfg: &'a Config,
+        volumes: &[impl AsRef<str>],
+        password: impl FnMut(&Path) -> Result<Zeroizing<String>>,
+    ) -> Result<Self> {
+        let stores = Stores::open(&secrets::databases(cfg, volumes, true), password)?;
+        Ok(Client { cfg, stores })
+    }
+
+    /// Unlocks the volume's dataset and its `children`. Fails if the NAS
+    /// reports any dataset it could not unlock, or, with `verify`, if one is
+    /// still locked afterwards. With `wait_for_mount`, it returns only once
+    /// the filesystems are mounted. The volume's `pre_unlock_command` runs first
+    /// and its `post_unlock_command` only after a successful unlock.
+    pub fn unlock(&self, volume_name: &str) -> Result<Unlocked> {
+        let (volume, nas) = self.cfg.resolve_volume(volume_name)?;
+        let auth = self.stores.auth(self.cfg, nas)?;
+        let secret = self.stores.unlock_secret(self.cfg, nas, volume)?;
+        let child_secrets = self.stores.child_secrets(self.cfg, nas, volume)?;
+
+        if let Some(command) = &volume.pre_unlock_command {
+            run_hook("pre_unlock_command", command, volume_name, volume)?;
+        }
+        let mut transport = open_transport(nas, client_options(nas), auth.as_auth())?;
+        let unlocked = unlock_dataset(
+            transport.as_mut(),
+            volume,
+            &secret,
+            &child_secrets,
+            self.wait_options(),
+        )?;
+        check_unlock_failures(&unlocked.result)?;
+        if self.cfg.verify_unlock(volume) {
+            verify_unlocked(transport.as_mut(), volume)?;
+        }
+        if self.cfg.wait_for_mount(volume) {
+            wait_for_mount(transport.as_mut(), volume, self.wait_options())?;
+        }
+        if let Some(command) = &volume.post_unlock_command {
+            run_hook("post_unlock_command", command, volume_name, volume).with_context(|| {
+                format!(
+                    "{} was unlocked, but its post_unlock_command failed",
+                    volume.dataset
+                )
+            })?;
+        }
+        Ok(unlocked)
+    }
+
+    /// Locks the volume's dataset, with its `lock_force_umount` setting.
+    pub fn lock(&self, volume_name: &str) -> Result<Locked> {
+        let (volume, nas) = self.cfg.resolve_volume(volume_name)?;
+        let auth = self.stores.auth(self.cfg, nas)?;
+        let mut transport = open_transport(nas, client_options(nas), auth.as_auth())?;
+        lock_dataset(transport.as_mut(), volume, self.wait_options())
+    }
+
+    /// Looks up whether the volume's dataset is encrypted and locked.
+    pub fn dataset(&self, volume_name: &str) -> Result<truenas::DatasetInfo> {
+        let (volume, nas) = self.cfg.resolve_volume(volume_name)?;
+        let auth = self.stores.auth(self.cfg, nas)?;
+        let mut transport = open_transport(nas, client_options(nas), auth.as_auth())?;
+        transport.dataset(&volume.dataset)
+    }
+
+    fn wait_options(&self) -> truenas::WaitOptions {
+        truenas::WaitOptions {
+            show_progress: false,
+            poll_interval: Duration::from_secs(self.cfg.job_poll_interval_secs),
+            timeout: self.cfg.job_timeout_secs.map(Duration::from_secs),
+        }
+    }
+}
+
+/// The outcome of an unlock: the NAS's answer, taken from the job's result
+/// when the NAS ran the unlock as a job.
+pub struct Unlocked {
+    pub result: UnlockResult,
+    pub job: Option<JobInfo>,
+}
+
+/// The outcome of a lock and the job the NAS ran it as, if any.
+pub struct Locked {
+    pub result: LockResult,
+    pub job: Option<JobInfo>,
+}
+
+/// Client settings for the NAS as configured.
+pub fn client_options(nas: &NasConfig) -> truenas::ClientOptions<'_> {
+    truenas::ClientOptions {
+        skip_tls_verify: nas.skip_tls_verify,
+        max_redirects: nas.max_redirects,
+        allow_cross_host_redirect: nas.allow_cross_host_redirect,
+        ca_cert: nas.ca_cert.as_deref(),
+        proxy: nas.proxy.as_deref(),
+        client_identity: nas.client_cert.as_deref().zip(nas.client_key.as_deref()),
+        timeout: nas
+            .timeout_secs
+            .map_or(truenas::DEFAULT_TIMEOUT, Duration::from_secs),
+        connect_timeout: nas
+            .connect_timeout_secs
+            .map_or(truenas::DEFAULT_CONNECT_TIMEOUT, Duration::from_secs),
+        user_agent: nas
+            .user_agent
+            .as_deref()
+            .unwrap_or(truenas::DEFAULT_USER_AGENT),
+    }
+}
+
+/// Connects to the NAS over its configured transport. The websocket
+/// transport connects and logs in here; REST sends nothing until the first
+/// call.
+pub fn open_transport<'a>(
+    nas: &NasConfig,
+    options: truenas::ClientOptions<'_>,
+    auth: truenas::Auth<'a>,
+) -> Result<Box<dyn truenas::Transport + 'a>> {
+    let base_url = truenas::parse_base_url(&nas.host)?;
+    Ok(match nas.transport {
+        config::Transport::Rest => {
+            tracing::debug!(%base_url, "using the REST API");
+            Box::new(truenas::Rest {
+                client: truenas::build_client(options)?,
+                base_url,
+                auth,
+                flavor: nas.flavor,
+                max_retries: nas.max_retries,
+                session: truenas::RestSession::Pending,
+            })
+        }
+        config::Transport::Websocket => {
+            Box::new(websocket::Session::connect(&base_url, options, auth)?)
+        }
+    })
+}
+
+/// Sends the unlock request and waits for any resulting job. Datasets the
+/// NAS could not unlock are in the result's `failed`, not an error.
+pub fn unlock_dataset(
+    transport: &mut dyn truenas::Transport,
+    volume: &VolumeConfig,
+    secret: &str,
+    child_secrets: &[Zeroizing<String>],
+    wait: truenas::WaitOptions,
+) -> Result<Unlocked> {
+    let options = detect_unlock_options(transport, volume);
+    let result = transport.unlock(
+        &volume.dataset,
+        unlock_secret(volume, secret),
+        &child_unlock_secrets(volume, child_secrets),
+        options,
+    )?;
+    let Some(job_id) = result.job_id.filter(|_| result.failed.is_empty()) else {
+        return Ok(Unlocked { result, job: None });
+    };
+
+    tracing::debug!(job_id, "waiting for unlock job");
+    let job = transport.wait_for_job(job_id, wait)?;
+    let result = job
+        .result
+        .as_ref()
+        .map(truenas::unlock_details)
+        .unwrap_or_default();
+    Ok(Unlocked {
+        result,
+        job: Some(job),
+    })
+}
+
+/// Runs a volume's `pre_unlock_command` or `post_unlock_command` directly,
+/// without a shell, with the volume named in `NASLOCK_VOLUME`,
+/// `NASLOCK_DATASET` and `NASLOCK_NAS`. A non-zero exit is an error that
+/// carries the command's stderr; its output is only logged.
+pub fn run_hook(
+    option: &str,
+    command: &[String],
+    volume_name: &str,
+    volume: &VolumeConfig,
+) -> Result<()> {
+    let Some((program, args)) = command.split_first() else {
+        bail!("{} must not be empty", option);
+    };
+    tracing::info!(program, "running {}", option);
+    let output = std::process::Command::new(program)
+        .args(args)
+        .env("NASLOCK_VOLUME", volume_name)
+        .env("NASLOCK_DATASET", &volume.dataset)
+        .env("NASLOCK_NAS", &volume.nas)
+        .stdin(std::process::Stdio::null())
+        .output()
+        .with_context(|| format!("failed to run {} '{}'", option, program))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for (stream, text) in [("stdout", stdout.trim()), ("stderr", stderr.trim())] {
+        if !text.is_empty() {
+            tracing::info!("{} {}: {}", option, stream, text);
+        }
+    }
+    if !output.status.success() {
+        if stderr.trim().is_empty() {
+            bail!("{} '{}' exited with {}", option, program, output.status);
+        }
+        bail!(
+            "{} '{}' exited with {}: {}",
+            option,
+            program,
+            output.status,
+            stderr.trim()
+        );
+    }
+    Ok(())
+}
+
+/// Changes the dataset's passphrase or key to `new_secret`, read in the
+/// volume's `unlock_mode`, and waits for the job. The dataset must be
+/// unlocked.
+pub fn change_key(
+    transport: &mut dyn truenas::Transport,
+    volume: &VolumeConfig,
+    new_secret: &str,
+    wait: truenas::WaitOptions,
+) -> Result<truenas::JobInfo> {
+    let job_id = transport.change_key(&volume.dataset, unlock_secret(volume, new_secret))?;
+    tracing::debug!(job_id, "waiting for change_key job");
+    transport.wait_for_job(job_id, wait)
+}
+
+/// Sends the lock request and waits for any resulting job.
+pub fn lock_dataset(
+    transport: &mut dyn truenas::Transport,
+    volume: &VolumeConfig,
+    wait: truenas::WaitOptions,
+) -> Result<Locked> {
+    let result = transport.lock(&volume.dataset, volume.lock_force_umount)?;
+    let Some(job_id) = result.job_id else {
+        return Ok(Locked { result, job: None });
+    };
+
+    tracing::debug!(job_id, "waiting for lock job");
+    let job = transport.wait_for_job(job_id, wait)?;
+    Ok(Locked {
+        result,
+        job: Some(job),
+    })
+}
+
+/// One line per dataset the NAS could not unlock, with the reason.
+pub fn unlock_failures(result: &UnlockResult) -> Vec<String> {
+    result
+        .failed
+        .iter()
+        .map(|(name, reason)| format!("failed to unlock {}: {}", name, reason))
+        .collect()
+}
+
+/// Fails with the reasons if the NAS reports datasets it could not unlock.
+pub fn check_unlock_failures(result: &UnlockResult) -> Result<()> {
+    if result.failed.is_empty() {
+        return Ok(());
+    }
+    Err(error::tag(
+        ErrorKind::Rejected,
+        anyhow::anyhow!(unlock_failures(result).join("; ")).context("unlock failed"),
+    ))
+}
+
+/// Queries the volume's dataset and its `children` after an unlock and fails
+/// if any of them is still locked, which the job result alone can miss. For a
+/// recursive unlock every encrypted descendant the NAS returns with the
+/// dataset is checked too.
+pub fn verify_unlocked(
+    transport: &mut dyn truenas::Transport,
+    volume: &VolumeConfig,
+) -> Result<()> {
+    let datasets =
+        std::iter::once(&volume.dataset).chain(volume.children.iter().map(|child| &child.dataset));
+    let mut still_locked = Vec::new();
+    for dataset in datasets {
+        let info = transport
+            .dataset(dataset)
+            .with_context(|| format!("failed to verify that {} is unlocked", dataset))?;
+        if info.locked {
+            still_locked.push(dataset.clone());
+        }
+        if volume.recursive {
+            still_locked.extend(info.locked_descendants);
+        }
+    }
+    still_locked.sort();
+    still_locked.dedup();
+    if !still_locked.is_empty() {
+        return Err(error::tag(
+            ErrorKind::Rejected,
+            anyhow::anyhow!(
+                "unlock reported success, but these datasets are still locked: {}",
+                still_locked.join(", ")
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// How long [`wait_for_mount`] waits when `job_timeout_secs` is not set.
+pub const DEFAULT_MOUNT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Polls the volume's dataset and its `children` after an unlock until each
+/// filesystem reports being mounted, with the job poll interval and timeout
+/// from `wait`. Zvols are skipped, since they are never mounted.
+pub fn wait_for_mount(
+    transport: &mut dyn truenas::Transport,
+    volume: &VolumeConfig,
+    wait: truenas::WaitOptions,
+) -> Result<()> {
+    let mut pending: Vec<&str> = std::iter::once(&volume.dataset)
+        .chain(volume.children.iter().map(|child| &child.dataset))
+        .map(String::as_str)
+        .collect();
+    let timeout = wait.timeout.unwrap_or(DEFAULT_MOUNT_TIMEOUT);
+    let started = std::time::Instant::now();
+    loop {
+        let mut still_unmounted = Vec::new();
+        for dataset in pending {
+            let info = transport
+                .dataset(dataset)
+                .with_context(|| format!("failed to check whether {} is mounted", dataset))?;
+            match info.mounted {
+                _ if info.zvol => {}
+                Some(true) => tracing::debug!(dataset, "mounted"),
+                Some(false) => still_unmounted.push(dataset),
+                None => tracing::warn!(
+                    "the NAS does not report whether {} is mounted; not waiting for it",
+                    dataset
+                ),
+            }
+        }
+        if still_unmounted.is_empty() {
+            return Ok(());
+        }
+        if started.elapsed() >= timeout {
+            return Err(error::tag(
+                ErrorKind::Timeout,
+                anyhow::anyhow!(
+                    "unlocked, but these datasets were not mounted after {} seconds: {}",
+                    timeout.as_secs(),
+                    still_unmounted.join(", ")
+                ),
+            ));
+        }
+        tracing::info!("waiting for {} to be mounted", still_unmounted.join(", "));
+        std::thread::sleep(wait.poll_interval);
+        pending = still_unmounted;
+    }
+}
+
+/// The volume's secret in the form its `unlock_mode` sends it.
+pub fn unlock_secret<'a>(volume: &VolumeConfig, secret: &'a str) -> truenas::UnlockSecret<'a> {
+    match volume.unlock_mode {
+        config::UnlockMode::Passphrase | config::UnlockMode::ChallengeResponse => {
+            truenas::UnlockSecret::Passphrase(secret)
+        }
+        config::UnlockMode::Key | config::UnlockMode::KeyFileAttachment => {
+            truenas::UnlockSecret::Key(secret)
+        }
+    }
+}
+
+/// Pairs each of the volume's `children` with its secret from `secrets`.
+pub fn child_unlock_secrets<'a>(
+    volume: &'a VolumeConfig,
+    secrets: &'a [Zeroizing<String>],
+) -> Vec<(&'a str, truenas::UnlockSecret<'a>)> {
+    volume
+        .children
+        .iter()
+        .zip(secrets)
+        .map(|(child, secret)| (child.dataset.as_str(), unlock_secret(volume, secret)))
+        .collect()
+}
+
+/// The unlock options the volume is configured with.
+pub fn unlock_options(volume: &VolumeConfig) -> truenas::UnlockOptions {
+    truenas::UnlockOptions {
+        recursive: volume.recursive,
+        force: volume.force,
+        toggle_attachments: volume.toggle_attachments(),
+    }
+}
+
+/// Unlock options for a request that is only rendered, as for `--dry-run`
+/// or `render-request`, where the dataset is not looked up. When the volume
+/// leaves its type undeclared the real unlock may still turn
+/// `toggle_attachments` off for a zvol, which is logged at debug level.
+pub fn rendered_unlock_options(volume: &VolumeConfig) -> truenas::UnlockOptions {
+    if volume.toggle_attachments.is_none() && volume.dataset_type.is_none() {
+        tracing::debug!(
+            dataset = volume.dataset.as_str(),
+            "dataset type not declared; the unlock sends toggle_attachments: false if it is a zvol"
+        );
+    }
+    unlock_options(volume)
+}
+
+/// Unlock options for the request itself. When neither `toggle_attachments`
+/// nor `dataset_type` is configured, the dataset is looked up first so a
+/// zvol is not detached from its iSCSI initiators. That adds a
+/// `pool/dataset` query before every unlock of such a volume; declaring
+/// `dataset_type` avoids it.
+fn detect_unlock_options(
+    transport: &mut dyn truenas::Transport,
+    volume: &VolumeConfig,
+) -> truenas::UnlockOptions {
+    let mut options = unlock_options(volume);
+    if volume.toggle_attachments.is_some() || volume.dataset_type.is_some() {
+        return options;
+    }
+    match transport.dataset(&volume.dataset) {
+        Ok(info) if info.zvol => {
+            tracing::warn!(
+                "{} is a zvol; unlocking without toggle_attachments (set dataset_type = \"volume\" to skip this check)",
+                volume.dataset
+            );
+            options.toggle_attachments = false;
+        }
+        Ok(_) => {}
+        Err(err) => tracing::debug!("could not look up the dataset type: {:#}", err),
+    }
+    options
+}
@@ -1,4 +1,6 @@
-use anyhow::{Context, Result};
+use crate::error::{ErrorKind, ResultExt};
+use crate::keepass_store::{KeyFile, decode_inline_key_file};
+use anyhow::{Context, Result, bail};
 use directories::BaseDirs;
 use serde::Deserialize;
 use std::collections::HashMap;
@@ -11,6 +13,43 @@ pub struct Config {
     pub nas: HashMap<String, NasConfig>,
     #[serde(alias = "volumes")]
     pub volume: HashMap<String, VolumeConfig>,
+    /// Secret values that are rejected as unreplaced template text.
+    #[serde(default = "default_placeholder_secrets")]
+    pub placeholder_secrets: Vec<String>,
+    /// File that gets one JSON line per unlock/lock outcome.
+    #[serde(default)]
+    pub audit_log: Option<PathBuf>,
+    /// Chain audit log lines by the hash of the previous line.
+    #[serde(default)]
+    pub audit_hash_chain: bool,
+    /// Seconds between polls of a running unlock or lock job.
+    #[serde(default = "default_job_poll_interval_secs")]
+    pub job_poll_interval_secs: u64,
+    /// Give up on a job that has not finished after this many seconds.
+    #[serde(default)]
+    pub job_timeout_secs: Option<u64>,
+    /// Default for `--timeout`: the limit on unlocking or locking, from the
+    /// first request to the NAS until the job is done.
+    #[serde(default)]
+    pub operation_timeout_secs: Option<u64>,
+    /// Query each dataset again after an unlock and fail if it is still
+    /// locked. Volumes can override it with their own `verify`.
+    #[serde(default)]
+    pub verify: bool,
+    /// After an unlock, wait until each filesystem reports being mounted.
+    /// Volumes can override it with their own `wait_for_mount`.
+    #[serde(default)]
+    pub wait_for_mount: bool,
+    /// Reject keys naslock does not recognise instead of warning about them.
+    #[serde(default)]
+    pub strict_config: bool,
+    /// Keys in the config file that naslock does not recognise, such as
+    /// `volume.media.recurseive`. Filled in by [`Config::load`].
+    #[serde(skip)]
+    pub unknown_keys: Vec<String>,
+    /// The `[keepass.<name>]` tables. Filled in by [`Config::load`].
+    #[serde(skip)]
+    pub keepass_profiles: HashMap<String, KeepassProfile>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -18,6 +57,34 @@ pub struct KeepassConfig {
     pub path: PathBuf,
     #[serde(default)]
     pub key_file: Option<PathBuf>,
+    /// Key file content as hex or base64, instead of `key_file`.
+    #[serde(default)]
+    pub key_file_inline: Option<String>,
+    /// Command whose stdout is the key file content, instead of `key_file`.
+    #[serde(default)]
+    pub key_file_command: Option<Vec<String>>,
+    #[serde(default)]
+    pub base_group: Option<String>,
+    #[serde(default = "default_base_group_fallback")]
+    pub base_group_fallback: bool,
+}
+
+/// A named database under `[keepass.<name>]`, picked by a NAS or volume
+/// with `keepass = "<name>"`.
+#[derive(Debug, Deserialize)]
+pub struct KeepassProfile {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub key_file: Option<PathBuf>,
+    #[serde(default)]
+    pub key_file_inline: Option<String>,
+    #[serde(default)]
+    pub key_file_command: Option<Vec<String>>,
+    /// What problems put in front of this database's setting names:
+    /// `keepass.<name>.` for a table, or `nas.<name>.keepass_` and
+    /// `volume.<name>.keepass_` for a `keepass_path`.
+    #[serde(skip)]
+    pub key_prefix: String,
 }
 
 #[derive(Debug, Deserialize, Clone, Copy)]
@@ -26,20 +93,112 @@ pub enum AuthMethod {
     Basic,
     #[serde(alias = "api-key")]
     ApiKey,
+    /// API key sent as the basic-auth username with an empty password.
+    #[serde(alias = "api-key-basic")]
+    ApiKeyBasic,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct NasConfig {
     pub host: String,
+    #[serde(default = "default_transport")]
+    pub transport: Transport,
+    /// TrueNAS edition on the NAS, which decides the shape of query calls.
+    #[serde(default = "default_flavor")]
+    pub flavor: Flavor,
+    #[serde(default)]
     pub auth_entry: String,
+    /// Command whose stdout is the API key, used instead of `auth_entry`.
+    #[serde(default)]
+    pub auth_command: Option<Vec<String>>,
     #[serde(default = "default_auth_method")]
     pub auth_method: AuthMethod,
     #[serde(default = "default_username_field")]
     pub username_field: String,
     #[serde(default = "default_password_field")]
     pub password_field: String,
+    /// Header the API key is sent in, for gateways that expect e.g. `X-API-Key`.
+    #[serde(default)]
+    pub api_key_header: Option<String>,
+    /// Scheme in front of the API key, e.g. `Token`; `""` sends the bare key.
+    #[serde(default)]
+    pub api_key_scheme: Option<String>,
+    /// Field on `auth_entry` with the TOTP seed for two-factor logins.
+    #[serde(default)]
+    pub totp_field: Option<String>,
     #[serde(default)]
     pub skip_tls_verify: bool,
+    /// Warn on stderr whenever `skip_tls_verify` is in effect.
+    #[serde(default = "default_skip_tls_verify_warning")]
+    pub skip_tls_verify_warning: bool,
+    #[serde(default)]
+    pub base_group: Option<String>,
+    /// Name of the `[keepass.<name>]` database with this NAS's entries.
+    #[serde(default)]
+    pub keepass: Option<String>,
+    /// KeePass database with this NAS's entries instead of `[keepass]`.
+    #[serde(default)]
+    pub keepass_path: Option<PathBuf>,
+    #[serde(default)]
+    pub keepass_key_file: Option<PathBuf>,
+    #[serde(default = "default_max_redirects")]
+    pub max_redirects: usize,
+    #[serde(default)]
+    pub allow_cross_host_redirect: bool,
+    /// PEM certificate trusted as the only root for this NAS.
+    #[serde(default)]
+    pub ca_cert: Option<PathBuf>,
+    /// Proxy for the REST API, e.g. `socks5h://localhost:1080` for an SSH
+    /// tunnel. Without it the usual `HTTPS_PROXY` variables apply.
+    #[serde(default)]
+    pub proxy: Option<String>,
+    /// PEM client certificate for endpoints that require mutual TLS.
+    #[serde(default)]
+    pub client_cert: Option<PathBuf>,
+    /// PKCS#8 PEM private key for `client_cert`.
+    #[serde(default)]
+    pub client_key: Option<PathBuf>,
+    /// Retries after a connection error or 5xx response.
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// Overall time limit for one API request; 30 seconds when unset.
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// Time limit for establishing the connection; 10 seconds when unset.
+    #[serde(default)]
+    pub connect_timeout_secs: Option<u64>,
+    /// `User-Agent` sent with every request instead of `naslock/<version>`.
+    #[serde(default)]
+    pub user_agent: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    /// The REST API under `/api/v2.0`.
+    Rest,
+    /// JSON-RPC over the websocket at `/api/current`.
+    #[serde(alias = "ws")]
+    Websocket,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Flavor {
+    /// TrueNAS SCALE (and Community Edition).
+    Scale,
+    /// TrueNAS CORE, the FreeBSD-based edition.
+    Core,
+}
+
+/// What kind of ZFS dataset a volume unlocks.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DatasetType {
+    Filesystem,
+    /// A zvol, a block device usually exported over iSCSI.
+    #[serde(alias = "zvol")]
+    Volume,
 }
 
 #[derive(Debug, Deserialize, Clone, Copy)]
@@ -48,30 +207,197 @@ pub enum UnlockMode {
     Passphrase,
     #[serde(alias = "key-file")]
     Key,
+    /// Raw key file stored as a binary attachment named by `unlock_field`.
+    KeyFileAttachment,
+    /// The stored value is a challenge; the passphrase is a YubiKey's
+    /// HMAC-SHA1 response to it, from `challenge_response_command`.
+    ChallengeResponse,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct VolumeConfig {
     pub nas: String,
     pub dataset: String,
+    /// Free-form note shown next to the volume in human-readable output.
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
     pub unlock_entry: String,
+    /// Command whose stdout is the unlock secret, used instead of
+    /// `unlock_entry`.
+    #[serde(default)]
+    pub unlock_command: Option<Vec<String>>,
+    /// Command run before the unlock request, e.g. to stop a service.
+    #[serde(default)]
+    pub pre_unlock_command: Option<Vec<String>>,
+    /// Command run once the unlock has succeeded, e.g. to start it again.
+    #[serde(default)]
+    pub post_unlock_command: Option<Vec<String>>,
     #[serde(default = "default_password_field")]
     pub unlock_field: String,
     #[serde(default = "default_unlock_mode")]
     pub unlock_mode: UnlockMode,
+    /// Answers the challenge on its stdin for `unlock_mode =
+    /// "challenge_response"`.
+    #[serde(default = "default_challenge_response_command")]
+    pub challenge_response_command: Vec<String>,
     #[serde(default = "default_recursive")]
     pub recursive: bool,
     #[serde(default)]
     pub force: bool,
     #[serde(default, alias = "force_umount", alias = "lock_force")]
     pub lock_force_umount: bool,
-    #[serde(default = "default_toggle_attachments")]
-    pub toggle_attachments: bool,
+    /// Unset means on for filesystems and off for zvols; see
+    /// [`VolumeConfig::toggle_attachments`].
+    #[serde(default)]
+    pub toggle_attachments: Option<bool>,
+    /// The dataset's type when known up front; otherwise it is looked up
+    /// before unlocking if it decides `toggle_attachments`.
+    #[serde(default)]
+    pub dataset_type: Option<DatasetType>,
+
+    #[serde(default)]
+    pub derive: Option<DeriveConfig>,
+    /// Fail instead of warn when the secret does not look like `unlock_mode`.
+    #[serde(default)]
+    pub strict_secret_check: bool,
+    /// Overrides the top-level `verify` for this volume.
+    #[serde(default)]
+    pub verify: Option<bool>,
+    /// Overrides the top-level `wait_for_mount` for this volume.
+    #[serde(default)]
+    pub wait_for_mount: Option<bool>,
+    /// Child datasets unlocked in the same request, each with its own secret.
+    #[serde(default)]
+    pub children: Vec<ChildDataset>,
+    /// Name of the `[keepass.<name>]` database with the unlock entries.
+    #[serde(default)]
+    pub keepass: Option<String>,
+    /// KeePass database with the unlock entries instead of the NAS's.
+    #[serde(default)]
+    pub keepass_path: Option<PathBuf>,
+    #[serde(default)]
+    pub keepass_key_file: Option<PathBuf>,
+}
+
+impl VolumeConfig {
+    /// Whether the unlock restarts the services using the dataset: the
+    /// configured value, else on unless the volume is known to be a zvol.
+    pub fn toggle_attachments(&self) -> bool {
+        self.toggle_attachments
+            .unwrap_or(self.dataset_type != Some(DatasetType::Volume))
+    }
+}
+
+/// A KeePass database file and the key file that opens it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepassDatabase<'a> {
+    pub path: &'a Path,
+    pub key_file: Option<KeyFile<'a>>,
+}
+
+/// A child dataset with its own secret. It shares the volume's `unlock_mode`
+/// and `derive` settings.
+#[derive(Debug, Deserialize)]
+pub struct ChildDataset {
+    pub dataset: String,
+    pub unlock_entry: String,
+    #[serde(default = "default_password_field")]
+    pub unlock_field: String,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum KdfAlgorithm {
+    Argon2id,
+    Argon2i,
+    Argon2d,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum DeriveEncoding {
+    Hex,
+    Base64,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct DeriveConfig {
+    pub kdf: KdfAlgorithm,
+    pub salt: String,
+    #[serde(default = "default_derive_memory_kib")]
+    pub memory_kib: u32,
+    #[serde(default = "default_derive_iterations")]
+    pub iterations: u32,
+    #[serde(default = "default_derive_parallelism")]
+    pub parallelism: u32,
+    #[serde(default = "default_derive_length")]
+    pub length: usize,
+    #[serde(default = "default_derive_encoding")]
+    pub encoding: DeriveEncoding,
+}
+
+/// Tidies a dataset name from the config: surrounding whitespace and leading
+/// or trailing slashes are dropped, so `/tank/media/` becomes `tank/media`.
+/// Names TrueNAS would reject anyway, such as snapshots or ones with empty
+/// components, are an error here instead of an opaque API failure.
+pub fn normalize_dataset_name(name: &str) -> Result<String> {
+    let normalized = name.trim().trim_matches('/');
+    if normalized.is_empty() {
+        bail!("dataset is empty");
+    }
+    if normalized.len() > 255 {
+        bail!("dataset {:?} is longer than 255 characters", normalized);
+    }
+    if let Some(c) = normalized.chars().find(|c| matches!(c, '@' | '#')) {
+        bail!(
+            "dataset {:?} contains '{}'; name a dataset, not a snapshot or bookmark",
+            normalized,
+            c
+        );
+    }
+    for component in normalized.split('/') {
+        if component.is_empty() {
+            bail!("dataset {:?} has an empty component ('//')", normalized);
+        }
+        if component != component.trim() || component == "." || component == ".." {
+            bail!(
+                "dataset {:?} has an invalid component {:?}",
+                normalized,
+                component
+            );
+        }
+        if let Some(c) = component
+            .chars()
+            .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | ':' | '.' | ' ')))
+        {
+            bail!(
+                "dataset {:?} contains the invalid character {:?}",
+                normalized,
+                c
+            );
+        }
+    }
+    if !normalized.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        bail!(
+            "dataset {:?} must start with the pool name, which begins with a letter",
+            normalized
+        );
+    }
+    Ok(normalized.to_string())
+}
+
+/// `config.toml` in the user's config directory, or the `conf.d` directory
+/// next to it when only that exists.
 pub fn default_config_path() -> Result<PathBuf> {
     let base = BaseDirs::new().context("unable to determine home directory")?;
-    Ok(base.config_dir().join("naslock").join("config.toml"))
+    let dir = base.config_dir().join("naslock");
+    let file = dir.join("config.toml");
+    let conf_d = dir.join("conf.d");
+    if !file.exists() && conf_d.is_dir() {
+        return Ok(conf_d);
+    }
+    Ok(file)
 }
 
 pub fn expand_path(path: &Path, base_dir: Option<&Path>) -> PathBuf {
@@ -102,18 +428,961 @@ fn expand_tilde(path: &Path) -> PathBuf {
 }
 
 impl Config {
+    /// Loads the config file at `path`, or the files in it if it is a
+    /// directory (see [`Config::load_dir`]). A `.yaml`, `.yml` or `.json`
+    /// file is read in that format, and any other file as TOML.
     pub fn load(path: &Path) -> Result<Self> {
+        if path.is_dir() {
+            return Self::load_dir(path);
+        }
         let content = fs::read_to_string(path)
             .with_context(|| format!("failed to read config file {}", path.display()))?;
-        let mut cfg: Config = toml::from_str(&content)
+        let format = ConfigFormat::of(path).unwrap_or(ConfigFormat::Toml);
+        let raw = format
+            .parse(&content)
             .with_context(|| format!("failed to parse config file {}", path.display()))?;
-        let base_dir = path.parent();
+        let content = (format == ConfigFormat::Toml).then_some(content.as_str());
+        Self::from_raw(raw, content, path, path.parent())
+    }
+
+    /// Loads every `*.toml`, `*.yaml`, `*.yml` and `*.json` file in `dir`, in
+    /// file name order, merged into one config. A later file overrides the
+    /// keys of earlier ones, except that a NAS or volume defined in an earlier
+    /// file may only be changed by a definition with `override = true`.
+    /// Relative paths are resolved against `dir`.
+    pub fn load_dir(dir: &Path) -> Result<Self> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir)
+            .with_context(|| format!("failed to read config directory {}", dir.display()))?
+        {
+            let path = entry
+                .with_context(|| format!("failed to read config directory {}", dir.display()))?
+                .path();
+            if ConfigFormat::of(&path).is_some() && path.is_file() {
+                files.push(path);
+            }
+        }
+        files.sort();
+        if files.is_empty() {
+            bail!("config directory {} has no config files", dir.display());
+        }
+
+        let mut raw = toml::Table::new();
+        for path in &files {
+            let content = fs::read_to_string(path)
+                .with_context(|| format!("failed to read config file {}", path.display()))?;
+            let table = ConfigFormat::of(path)
+                .unwrap_or(ConfigFormat::Toml)
+                .parse(&content)
+                .with_context(|| format!("failed to parse config file {}", path.display()))?;
+            merge_config_file(&mut raw, table)
+                .with_context(|| format!("invalid config file {}", path.display()))?;
+        }
+        Self::from_raw(raw, None, dir, Some(dir))
+    }
+
+    /// Builds the config from the parsed file at `path`, or the merged files
+    /// of a directory. `content` is the file's text when there is a single
+    /// file, so that errors can point into it; relative paths are resolved
+    /// against `base_dir`.
+    fn from_raw(
+        mut raw: toml::Table,
+        content: Option<&str>,
+        path: &Path,
+        base_dir: Option<&Path>,
+    ) -> Result<Self> {
+        let env_changed = apply_env_overrides(&mut raw, std::env::vars());
+        let presets_changed = apply_option_presets(&mut raw)
+            .with_context(|| format!("invalid config file {}", path.display()))?;
+        let profile_tables: Vec<(String, toml::Value)> = match raw.get("keepass") {
+            Some(toml::Value::Table(keepass)) => keepass
+                .iter()
+                .filter(|(_, value)| value.is_table())
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+            _ => Vec::new(),
+        };
+        let mut unknown_keys = Vec::new();
+        let mut note_unknown = |key: serde_ignored::Path<'_>| {
+            if let Some(key) = unknown_key(&key) {
+                unknown_keys.push(key);
+            }
+        };
+        let mut cfg: Config = match content {
+            Some(content) if !env_changed && !presets_changed => {
+                serde_ignored::deserialize(toml::Deserializer::new(content), &mut note_unknown)
+                    .with_context(|| format!("failed to parse config file {}", path.display()))?
+            }
+            _ => serde_ignored::deserialize(toml::Value::Table(raw), &mut note_unknown)
+                .with_context(|| {
+                    format!(
+                        "failed to parse config file {} with overrides applied",
+                        path.display()
+                    )
+                })?,
+        };
+        unknown_keys.retain(|key| {
+            !profile_tables
+                .iter()
+                .any(|(name, _)| *key == format!("keepass.{}", name))
+        });
+        for (name, table) in profile_tables {
+            let mut profile: KeepassProfile = serde_ignored::deserialize(table, |key| {
+                if let Some(key) = unknown_key(&key) {
+                    unknown_keys.push(format!("keepass.{}.{}", name, key));
+                }
+            })
+            .with_context(|| {
+                format!(
+                    "failed to parse [keepass.{}] in config file {}",
+                    name,
+                    path.display()
+                )
+            })?;
+            profile.path = expand_path(&profile.path, base_dir);
+            if let Some(ref mut key_file) = profile.key_file {
+                *key_file = expand_path(key_file, base_dir);
+            }
+            profile.key_prefix = format!("keepass.{}.", name);
+            cfg.keepass_profiles.insert(name, profile);
+        }
+        cfg.unknown_keys = unknown_keys;
         cfg.keepass.path = expand_path(&cfg.keepass.path, base_dir);
         if let Some(ref mut key_file) = cfg.keepass.key_file {
             *key_file = expand_path(key_file, base_dir);
         }
+        if let Some(ref mut audit_log) = cfg.audit_log {
+            *audit_log = expand_path(audit_log, base_dir);
+        }
+        for nas in cfg.nas.values_mut() {
+            for path in [
+                &mut nas.ca_cert,
+                &mut nas.client_cert,
+                &mut nas.client_key,
+                &mut nas.keepass_path,
+                &mut nas.keepass_key_file,
+            ]
+            .into_iter()
+            .flatten()
+            {
+                *path = expand_path(path, base_dir);
+            }
+        }
+        for volume in cfg.volume.values_mut() {
+            for path in [&mut volume.keepass_path, &mut volume.keepass_key_file]
+                .into_iter()
+                .flatten()
+            {
+                *path = expand_path(path, base_dir);
+            }
+        }
+        cfg.adopt_keepass_paths();
+        cfg.normalize_datasets();
         Ok(cfg)
     }
+
+    /// Turns the `keepass_path` and `keepass_key_file` of each NAS and
+    /// volume into a database profile of its own, named `nas.<name>` or
+    /// `volume.<name>`, which the NAS or volume then selects as if it had
+    /// set `keepass`. One that also sets `keepass` is left for
+    /// [`Config::validate`] to reject.
+    fn adopt_keepass_paths(&mut self) {
+        let nas_paths = self.nas.iter_mut().map(|(name, nas)| {
+            (
+                format!("nas.{}", name),
+                &mut nas.keepass,
+                &mut nas.keepass_path,
+                &mut nas.keepass_key_file,
+            )
+        });
+        let volume_paths = self.volume.iter_mut().map(|(name, volume)| {
+            (
+                format!("volume.{}", name),
+                &mut volume.keepass,
+                &mut volume.keepass_path,
+                &mut volume.keepass_key_file,
+            )
+        });
+        for (name, keepass, keepass_path, keepass_key_file) in nas_paths.chain(volume_paths) {
+            if keepass.is_some() {
+                continue;
+            }
+            let Some(path) = keepass_path.take() else {
+                continue;
+            };
+            let profile = KeepassProfile {
+                path,
+                key_file: keepass_key_file.take(),
+                key_file_inline: None,
+                key_file_command: None,
+                key_prefix: format!("{}.keepass_", name),
+            };
+            *keepass = Some(name.clone());
+            self.keepass_profiles.insert(name, profile);
+        }
+    }
+
+    /// Applies [`normalize_dataset_name`] to every volume and child dataset
+    /// it accepts; [`Config::validate`] reports the ones it rejects.
+    fn normalize_datasets(&mut self) {
+        for volume in self.volume.values_mut() {
+            let children = volume.children.iter_mut().map(|child| &mut child.dataset);
+            for dataset in std::iter::once(&mut volume.dataset).chain(children) {
+                if let Ok(normalized) = normalize_dataset_name(dataset) {
+                    *dataset = normalized;
+                }
+            }
+        }
+    }
+
+    /// The volume named `volume_name` and the NAS it lives on.
+    pub fn resolve_volume(&self, volume_name: &str) -> Result<(&VolumeConfig, &NasConfig)> {
+        let volume = self
+            .volume
+            .get(volume_name)
+            .with_context(|| format!("unknown volume '{}'", volume_name))
+            .kind(ErrorKind::Config)?;
+        let nas = self
+            .nas
+            .get(&volume.nas)
+            .with_context(|| format!("unknown NAS '{}'", volume.nas))
+            .kind(ErrorKind::Config)?;
+        Ok((volume, nas))
+    }
+
+    /// Whether an unlock of `volume` is checked by reading the datasets back.
+    pub fn verify_unlock(&self, volume: &VolumeConfig) -> bool {
+        volume.verify.unwrap_or(self.verify)
+    }
+
+    /// Whether an unlock of `volume` waits for its filesystems to be mounted.
+    pub fn wait_for_mount(&self, volume: &VolumeConfig) -> bool {
+        volume.wait_for_mount.unwrap_or(self.wait_for_mount)
+    }
+
+    /// The database with the NAS's credentials: the `[keepass.<name>]` it
+    /// names, which its own `keepass_path` stands for, or the one in
+    /// `[keepass]`.
+    pub fn nas_database<'a>(&'a self, nas: &'a NasConfig) -> KeepassDatabase<'a> {
+        self.keepass_profile(nas.keepass.as_deref())
+            .unwrap_or_else(|| self.keepass_database())
+    }
+
+    /// The database in `[keepass]`.
+    pub fn keepass_database(&self) -> KeepassDatabase<'_> {
+        KeepassDatabase {
+            path: &self.keepass.path,
+            key_file: key_file_source(
+                &self.keepass.key_file,
+                &self.keepass.key_file_inline,
+                &self.keepass.key_file_command,
+            ),
+        }
+    }
+
+    /// The database with the volume's unlock entries: the
+    /// `[keepass.<name>]` it names, which its own `keepass_path` stands for,
+    /// or its NAS's database.
+    pub fn volume_database<'a>(
+        &'a self,
+        nas: &'a NasConfig,
+        volume: &'a VolumeConfig,
+    ) -> KeepassDatabase<'a> {
+        self.keepass_profile(volume.keepass.as_deref())
+            .unwrap_or_else(|| self.nas_database(nas))
+    }
+
+    fn keepass_profile(&self, name: Option<&str>) -> Option<KeepassDatabase<'_>> {
+        let profile = self.keepass_profiles.get(name?)?;
+        Some(KeepassDatabase {
+            path: &profile.path,
+            key_file: key_file_source(
+                &profile.key_file,
+                &profile.key_file_inline,
+                &profile.key_file_command,
+            ),
+        })
+    }
+
+    /// Checks what parsing cannot: that volumes name a configured NAS,
+    /// required values are not empty, referenced files exist and options fit
+    /// together. Every problem is reported, not just the first.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        if self.strict_config {
+            for key in &self.unknown_keys {
+                problems.push(format!("unknown key '{}'", key));
+            }
+        }
+        if self.job_poll_interval_secs == 0 {
+            problems.push("job_poll_interval_secs must be positive".to_string());
+        }
+        check_database(
+            "keepass.",
+            &self.keepass.path,
+            &self.keepass.key_file,
+            &self.keepass.key_file_inline,
+            &self.keepass.key_file_command,
+            &mut problems,
+        );
+        let mut profile_names: Vec<&String> = self.keepass_profiles.keys().collect();
+        profile_names.sort();
+        for name in profile_names {
+            let profile = &self.keepass_profiles[name];
+            check_database(
+                &profile.key_prefix,
+                &profile.path,
+                &profile.key_file,
+                &profile.key_file_inline,
+                &profile.key_file_command,
+                &mut problems,
+            );
+        }
+        // Each database file is opened once, so it cannot take two key files.
+        let mut databases = vec![("keepass.path".to_string(), self.keepass_database())];
+        for (name, profile) in &self.keepass_profiles {
+            if let Some(database) = self.keepass_profile(Some(name)) {
+                databases.push((format!("{}path", profile.key_prefix), database));
+            }
+        }
+        databases.sort_by(|a, b| a.0.cmp(&b.0));
+        for (i, (setting, database)) in databases.iter().enumerate() {
+            if let Some((earlier, _)) = databases[..i].iter().find(|(_, known)| {
+                known.path == database.path && known.key_file != database.key_file
+            }) {
+                problems.push(format!(
+                    "{} and {} both open {} but with different key files",
+                    earlier,
+                    setting,
+                    database.path.display()
+                ));
+            }
+        }
+
+        let mut nas_names: Vec<&String> = self.nas.keys().collect();
+        nas_names.sort();
+        for name in nas_names {
+            let nas = &self.nas[name];
+            let mut problem =
+                |message: String| problems.push(format!("nas '{}': {}", name, message));
+            if nas.host.trim().is_empty() {
+                problem("host is empty".to_string());
+            }
+            if nas.auth_entry.trim().is_empty() && nas.auth_command.is_none() {
+                problem("needs either auth_entry or auth_command".to_string());
+            }
+            if nas.ca_cert.is_some() && nas.skip_tls_verify {
+                problem("ca_cert has no effect with skip_tls_verify = true".to_string());
+            }
+            if nas.client_cert.is_some() != nas.client_key.is_some() {
+                problem("client_cert and client_key must be set together".to_string());
+            }
+            if nas.keepass_key_file.is_some() && nas.keepass_path.is_none() {
+                problem("keepass_key_file needs keepass_path".to_string());
+            }
+            if let Some(message) = self.check_keepass_profile(&nas.keepass, &nas.keepass_path) {
+                problem(message);
+            }
+            for (what, path) in [
+                ("ca_cert", &nas.ca_cert),
+                ("client_cert", &nas.client_cert),
+                ("client_key", &nas.client_key),
+            ] {
+                if let Some(path) = path.as_ref().filter(|path| !path.is_file()) {
+                    problem(format!("{} {} does not exist", what, path.display()));
+                }
+            }
+            if let Some(proxy) = &nas.proxy {
+                if nas.transport != Transport::Rest {
+                    problem("proxy needs transport = \"rest\"".to_string());
+                }
+                match url::Url::parse(proxy) {
+                    Ok(url)
+                        if matches!(
+                            url.scheme(),
+                            "http" | "https" | "socks4" | "socks4a" | "socks5" | "socks5h"
+                        ) => {}
+                    _ => problem(format!(
+                        "proxy {:?} must be an http://, https:// or socks5:// URL",
+                        proxy
+                    )),
+                }
+            }
+            if let Some(user_agent) = &nas.user_agent {
+                if user_agent.trim().is_empty() || user_agent.chars().any(char::is_control) {
+                    problem(format!(
+                        "user_agent {:?} must be non-empty and free of control characters",
+                        user_agent
+                    ));
+                }
+            }
+            if nas.flavor == Flavor::Core && nas.transport == Transport::Websocket {
+                problem(
+                    "transport = \"websocket\" needs flavor = \"scale\"; TrueNAS CORE has no /api/current"
+                        .to_string(),
+                );
+            }
+            if nas.totp_field.is_some()
+                && (!matches!(nas.auth_method, AuthMethod::Basic)
+                    || nas.transport != Transport::Websocket)
+            {
+                problem(
+                    "totp_field needs auth_method = \"basic\" and transport = \"websocket\""
+                        .to_string(),
+                );
+            }
+            if nas.api_key_header.is_some() || nas.api_key_scheme.is_some() {
+                if !matches!(nas.auth_method, AuthMethod::ApiKey)
+                    || nas.transport != Transport::Rest
+                {
+                    problem(
+                        "api_key_header and api_key_scheme need auth_method = \"api_key\" and transport = \"rest\""
+                            .to_string(),
+                    );
+                }
+                let valid_name = |name: &str| {
+                    !name.is_empty()
+                        && name
+                            .bytes()
+                            .all(|byte| byte.is_ascii_alphanumeric() || b"-_".contains(&byte))
+                };
+                if let Some(header) = nas.api_key_header.as_deref().filter(|h| !valid_name(h)) {
+                    problem(format!(
+                        "api_key_header {:?} is not a valid header name",
+                        header
+                    ));
+                }
+            }
+            if nas.timeout_secs == Some(0) || nas.connect_timeout_secs == Some(0) {
+                problem("timeout_secs and connect_timeout_secs must be positive".to_string());
+            }
+        }
+
+        let mut volume_names: Vec<&String> = self.volume.keys().collect();
+        volume_names.sort();
+        for name in volume_names {
+            let volume = &self.volume[name];
+            let mut problem =
+                |message: String| problems.push(format!("volume '{}': {}", name, message));
+            if !self.nas.contains_key(&volume.nas) {
+                problem(format!("unknown NAS '{}'", volume.nas));
+            }
+            if let Err(err) = normalize_dataset_name(&volume.dataset) {
+                problem(err.to_string());
+            }
+            match &volume.unlock_command {
+                None if volume.unlock_entry.trim().is_empty() => {
+                    problem("needs either unlock_entry or unlock_command".to_string());
+                }
+                Some(command) if command.is_empty() => {
+                    problem("unlock_command must not be empty".to_string());
+                }
+                Some(_) if !volume.unlock_entry.trim().is_empty() => {
+                    problem("unlock_entry and unlock_command cannot both be set".to_string());
+                }
+                Some(_) if matches!(volume.unlock_mode, UnlockMode::KeyFileAttachment) => {
+                    problem(
+                        "unlock_command cannot be used with unlock_mode = \"key_file_attachment\""
+                            .to_string(),
+                    );
+                }
+                _ => {}
+            }
+            if matches!(volume.unlock_mode, UnlockMode::ChallengeResponse)
+                && volume.challenge_response_command.is_empty()
+            {
+                problem("challenge_response_command must not be empty".to_string());
+            }
+            for (what, command) in [
+                ("pre_unlock_command", &volume.pre_unlock_command),
+                ("post_unlock_command", &volume.post_unlock_command),
+            ] {
+                if command.as_ref().is_some_and(Vec::is_empty) {
+                    problem(format!("{} must not be empty", what));
+                }
+            }
+            if volume.keepass_key_file.is_some() && volume.keepass_path.is_none() {
+                problem("keepass_key_file needs keepass_path".to_string());
+            }
+            if let Some(message) = self.check_keepass_profile(&volume.keepass, &volume.keepass_path)
+            {
+                problem(message);
+            }
+            if volume.dataset_type == Some(DatasetType::Volume) {
+                if !volume.children.is_empty() {
+                    problem("a zvol has no child datasets to unlock".to_string());
+                }
+                if volume.toggle_attachments == Some(true) {
+                    problem(
+                        "toggle_attachments = true on a zvol restarts the iSCSI targets using it, disrupting initiators; remove one of the two"
+                            .to_string(),
+                    );
+                }
+            }
+            for child in &volume.children {
+                if let Err(err) = normalize_dataset_name(&child.dataset) {
+                    problem(format!("child {}", err));
+                }
+                if child.unlock_entry.trim().is_empty() {
+                    problem(format!("unlock_entry of child {} is empty", child.dataset));
+                }
+            }
+        }
+
+        match problems.as_slice() {
+            [] => Ok(()),
+            [problem] => bail!("invalid config: {}", problem),
+            _ => bail!(
+                "invalid config, {} problems:\n  - {}",
+                problems.len(),
+                problems.join("\n  - ")
+            ),
+        }
+    }
+
+    /// Replaces `keepass.path` and the `[keepass]` key file with the ones
+    /// given on the command line, with `~` expanded.
+    pub fn override_keepass(&mut self, path: Option<&Path>, key_file: Option<&Path>) {
+        if let Some(path) = path {
+            self.keepass.path = expand_path(path, None);
+        }
+        if let Some(key_file) = key_file {
+            self.keepass.key_file = Some(expand_path(key_file, None));
+            self.keepass.key_file_inline = None;
+            self.keepass.key_file_command = None;
+        }
+    }
+
+    /// Checks the `keepass` profile name of a NAS or volume.
+    fn check_keepass_profile(
+        &self,
+        name: &Option<String>,
+        keepass_path: &Option<PathBuf>,
+    ) -> Option<String> {
+        let name = name.as_ref()?;
+        if keepass_path.is_some() {
+            return Some("keepass and keepass_path cannot both be set".to_string());
+        }
+        if !self.keepass_profiles.contains_key(name) {
+            return Some(format!("unknown KeePass database [keepass.{}]", name));
+        }
+        None
+    }
+
+    /// Adds a volume for `dataset` on `nas` that is not in the config file,
+    /// named after the dataset. Its secret comes from `unlock_field` (default
+    /// `Password`) of `unlock_entry`; every other setting is the default.
+    pub fn add_dataset_volume(
+        &mut self,
+        nas: &str,
+        dataset: &str,
+        unlock_entry: &str,
+        unlock_field: Option<&str>,
+    ) -> Result<()> {
+        if self.volume.contains_key(dataset) {
+            bail!("a volume named '{}' is already configured", dataset);
+        }
+        let mut raw = toml::Table::new();
+        raw.insert("nas".into(), nas.into());
+        raw.insert("dataset".into(), dataset.into());
+        raw.insert("unlock_entry".into(), unlock_entry.into());
+        if let Some(field) = unlock_field {
+            raw.insert("unlock_field".into(), field.into());
+        }
+        let volume = toml::Value::Table(raw)
+            .try_into()
+            .with_context(|| format!("failed to build volume for dataset {}", dataset))?;
+        self.volume.insert(dataset.to_string(), volume);
+        self.normalize_datasets();
+        Ok(())
+    }
+
+    /// Builds a config from a table assembled in code rather than read from a
+    /// file. Environment overrides and presets apply as in [`Config::load`].
+    pub fn from_table(mut raw: toml::Table) -> Result<Self> {
+        apply_env_overrides(&mut raw, std::env::vars());
+        apply_option_presets(&mut raw)?;
+        let mut cfg: Self = toml::Value::Table(raw)
+            .try_into()
+            .context("failed to build config")?;
+        cfg.adopt_keepass_paths();
+        cfg.normalize_datasets();
+        Ok(cfg)
+    }
+}
+
+/// The syntax of a config file, told by its extension.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ConfigFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// The format for `path`'s extension, or `None` if it is not one of
+    /// `.toml`, `.yaml`, `.yml` or `.json`.
+    fn of(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "toml" => Some(Self::Toml),
+            "yaml" | "yml" => Some(Self::Yaml),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    /// Parses `content` into the table a TOML file with the same settings
+    /// would give, so the rest of loading does not depend on the format.
+    /// TOML has no null, so YAML and JSON keys set to null are left out, as
+    /// if they were not set.
+    fn parse(self, content: &str) -> Result<toml::Table> {
+        let mut value: serde_json::Value = match self {
+            Self::Toml => return Ok(toml::from_str(content)?),
+            Self::Yaml => serde_norway::from_str(content)?,
+            Self::Json => serde_json::from_str(content)?,
+        };
+        drop_null_keys(&mut value);
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+/// Removes the keys whose value is null from every object in `value`.
+fn drop_null_keys(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            map.retain(|_, value| !value.is_null());
+            map.values_mut().for_each(drop_null_keys);
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(drop_null_keys),
+        _ => {}
+    }
+}
+
+/// The key file of a database, from whichever of its sources is set.
+fn key_file_source<'a>(
+    key_file: &'a Option<PathBuf>,
+    key_file_inline: &'a Option<String>,
+    key_file_command: &'a Option<Vec<String>>,
+) -> Option<KeyFile<'a>> {
+    match (key_file, key_file_inline, key_file_command) {
+        (Some(path), _, _) => Some(KeyFile::Path(path)),
+        (None, Some(text), _) => Some(KeyFile::Inline(text)),
+        (None, None, Some(command)) => Some(KeyFile::Command(command)),
+        (None, None, None) => None,
+    }
+}
+
+/// Checks the settings of a database: `[keepass]`, a `[keepass.<name>]`
+/// table or a `keepass_path`, whose setting names get `prefix` in the
+/// problems.
+fn check_database(
+    prefix: &str,
+    path: &Path,
+    key_file: &Option<PathBuf>,
+    key_file_inline: &Option<String>,
+    key_file_command: &Option<Vec<String>>,
+    problems: &mut Vec<String>,
+) {
+    for (what, path) in [("path", Some(path)), ("key_file", key_file.as_deref())] {
+        if let Some(path) = path.filter(|path| !path.is_file()) {
+            problems.push(format!(
+                "{}{} {} does not exist",
+                prefix,
+                what,
+                path.display()
+            ));
+        }
+    }
+    let key_file_sources = [
+        key_file.is_some(),
+        key_file_inline.is_some(),
+        key_file_command.is_some(),
+    ];
+    if key_file_sources.iter().filter(|set| **set).count() > 1 {
+        problems.push(format!(
+            "only one of {}key_file, key_file_inline and key_file_command can be set",
+            prefix
+        ));
+    } else if let Some(text) = key_file_inline {
+        if let Err(err) = decode_inline_key_file(text) {
+            problems.push(format!("{}{}", prefix, err));
+        }
+    }
+    if key_file_command
+        .as_ref()
+        .is_some_and(|command| command.is_empty())
+    {
+        problems.push(format!("{}key_file_command must not be empty", prefix));
+    }
+}
+
+/// Merges one file of a config directory into the tables of the files
+/// before it. `[nas.*]` and `[volume.*]` definitions that already exist are
+/// only merged when the new one sets `override = true`; every other key
+/// replaces the earlier value, with tables merged key by key.
+fn merge_config_file(raw: &mut toml::Table, file: toml::Table) -> Result<()> {
+    for (key, value) in file {
+        let key = if key == "volumes" {
+            "volume".to_string()
+        } else {
+            key
+        };
+        if key != "nas" && key != "volume" {
+            merge_value(raw, key, value);
+            continue;
+        }
+        let toml::Value::Table(definitions) = value else {
+            bail!("'{}' must be a table", key);
+        };
+        let section = raw
+            .entry(key.clone())
+            .or_insert_with(|| toml::Table::new().into());
+        let Some(section) = section.as_table_mut() else {
+            bail!("'{}' must be a table", key);
+        };
+        for (name, definition) in definitions {
+            let toml::Value::Table(mut definition) = definition else {
+                bail!("{}.{} must be a table", key, name);
+            };
+            let overrides = match definition.remove("override") {
+                None => false,
+                Some(toml::Value::Boolean(overrides)) => overrides,
+                Some(_) => bail!("{}.{}.override must be true or false", key, name),
+            };
+            match section.get_mut(&name) {
+                None => {
+                    section.insert(name, definition.into());
+                }
+                Some(toml::Value::Table(existing)) if overrides => {
+                    for (field, value) in definition {
+                        merge_value(existing, field, value);
+                    }
+                }
+                Some(_) => bail!(
+                    "{} '{}' is already defined in an earlier file; set override = true to change it",
+                    key,
+                    name
+                ),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sets `key` to `value`, merging into an existing table key by key.
+fn merge_value(table: &mut toml::Table, key: String, value: toml::Value) {
+    match (table.get_mut(&key), value) {
+        (Some(toml::Value::Table(existing)), toml::Value::Table(new)) => {
+            for (key, value) in new {
+                merge_value(existing, key, value);
+            }
+        }
+        (_, value) => {
+            table.insert(key, value);
+        }
+    }
+}
+
+/// Applies `NASLOCK_NAS_<NAME>_<FIELD>` and `NASLOCK_VOLUME_<NAME>_<FIELD>`
+/// overrides to the raw config table. Names are matched after uppercasing and
+/// replacing non-alphanumeric characters with `_`. Values keep the type of the
+/// key they replace; new keys are parsed as booleans or integers where they
+/// look like one and kept as strings otherwise. Returns whether anything changed.
+fn apply_env_overrides(
+    raw: &mut toml::Table,
+    vars: impl Iterator<Item = (String, String)>,
+) -> bool {
+    let mut changed = false;
+    for (key, value) in vars {
+        let (section, rest) = if let Some(rest) = key.strip_prefix("NASLOCK_NAS_") {
+            ("nas", rest)
+        } else if let Some(rest) = key.strip_prefix("NASLOCK_VOLUME_") {
+            (
+                if raw.contains_key("volumes") {
+                    "volumes"
+                } else {
+                    "volume"
+                },
+                rest,
+            )
+        } else {
+            continue;
+        };
+        let Some(toml::Value::Table(items)) = raw.get_mut(section) else {
+            continue;
+        };
+        let matched = items
+            .keys()
+            .filter_map(|name| {
+                let field = rest.strip_prefix(&env_name(name))?.strip_prefix('_')?;
+                (!field.is_empty()).then(|| (name.clone(), field.to_ascii_lowercase()))
+            })
+            .max_by_key(|(name, _)| name.len());
+        let Some((name, field)) = matched else {
+            continue;
+        };
+        if let Some(toml::Value::Table(item)) = items.get_mut(&name) {
+            let value = parse_env_value(item.get(&field), &value);
+            item.insert(field, value);
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// The dotted name of a key that deserializing the config ignored, or `None`
+/// for the `options` presets, which [`apply_option_presets`] has already
+/// used.
+fn unknown_key(path: &serde_ignored::Path<'_>) -> Option<String> {
+    let mut segments = Vec::new();
+    let mut current = path;
+    loop {
+        match current {
+            serde_ignored::Path::Root => break,
+            serde_ignored::Path::Seq { parent, index } => {
+                segments.push(index.to_string());
+                current = parent;
+            }
+            serde_ignored::Path::Map { parent, key } => {
+                segments.push(key.clone());
+                current = parent;
+            }
+            serde_ignored::Path::Some { parent }
+            | serde_ignored::Path::NewtypeStruct { parent }
+            | serde_ignored::Path::NewtypeVariant { parent } => current = parent,
+        }
+    }
+    segments.reverse();
+    let preset = match segments.as_slice() {
+        [key] => key == "options",
+        [section, _, key] => matches!(section.as_str(), "volume" | "volumes") && key == "options",
+        _ => false,
+    };
+    (!preset).then(|| segments.join("."))
+}
+
+const PRESET_KEYS: &[&str] = &[
+    "recursive",
+    "force",
+    "toggle_attachments",
+    "lock_force_umount",
+    "force_umount",
+    "lock_force",
+    "strict_secret_check",
+    "verify",
+    "wait_for_mount",
+];
+
+/// Copies the keys of each volume's `options` preset into the volume table
+/// unless the volume sets them itself. Returns whether anything changed.
+fn apply_option_presets(raw: &mut toml::Table) -> Result<bool> {
+    let presets = match raw.get("options") {
+        Some(toml::Value::Table(presets)) => presets.clone(),
+        _ => toml::Table::new(),
+    };
+    for (name, preset) in &presets {
+        let Some(preset) = preset.as_table() else {
+            bail!("options preset '{}' must be a table", name);
+        };
+        if let Some(key) = preset.keys().find(|k| !PRESET_KEYS.contains(&k.as_str())) {
+            bail!("options preset '{}' has unsupported key '{}'", name, key);
+        }
+    }
+
+    let mut changed = false;
+    for section in ["volume", "volumes"] {
+        let Some(toml::Value::Table(volumes)) = raw.get_mut(section) else {
+            continue;
+        };
+        for (volume_name, volume) in volumes.iter_mut() {
+            let Some(volume) = volume.as_table_mut() else {
+                continue;
+            };
+            let Some(preset_name) = volume.get("options").and_then(|v| v.as_str()) else {
+                continue;
+            };
+            let preset = presets
+                .get(preset_name)
+                .and_then(|p| p.as_table())
+                .with_context(|| {
+                    format!(
+                        "volume '{}' references unknown options preset '{}'",
+                        volume_name, preset_name
+                    )
+                })?;
+            for (key, value) in preset {
+                if !volume.contains_key(key) {
+                    volume.insert(key.clone(), value.clone());
+                    changed = true;
+                }
+            }
+        }
+    }
+    Ok(changed)
+}
+
+fn env_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn parse_env_value(existing: Option<&toml::Value>, value: &str) -> toml::Value {
+    if let Some(toml::Value::String(_)) = existing {
+        return toml::Value::String(value.to_string());
+    }
+    let trimmed = value.trim();
+    match trimmed.to_ascii_lowercase().as_str() {
+        "true" | "yes" | "on" => return toml::Value::Boolean(true),
+        "false" | "no" | "off" => return toml::Value::Boolean(false),
+        _ => {}
+    }
+    if let Ok(number) = trimmed.parse::<i64>() {
+        return toml::Value::Integer(number);
+    }
+    toml::Value::String(value.to_string())
+}
+
+fn default_transport() -> Transport {
+    Transport::Rest
+}
+
+fn default_flavor() -> Flavor {
+    Flavor::Scale
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_job_poll_interval_secs() -> u64 {
+    1
+}
+
+fn default_placeholder_secrets() -> Vec<String> {
+    [
+        "CHANGEME",
+        "CHANGE_ME",
+        "CHANGE-ME",
+        "REPLACEME",
+        "REPLACE_ME",
+        "REPLACE-ME",
+        "PLACEHOLDER",
+        "TODO",
+        "XXX",
+        "SECRET",
+        "PASSWORD",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect()
 }
 
 fn default_auth_method() -> AuthMethod {
@@ -124,6 +1393,10 @@ fn default_unlock_mode() -> UnlockMode {
     UnlockMode::Passphrase
 }
 
+fn default_challenge_response_command() -> Vec<String> {
+    ["ykchalresp", "-2", "-i", "-"].map(String::from).to_vec()
+}
+
 fn default_username_field() -> String {
     "UserName".to_string()
 }
@@ -132,10 +1405,286 @@ fn default_password_field() -> String {
     "Password".to_string()
 }
 
-fn default_recursive() -> bool {
+fn default_skip_tls_verify_warning() -> bool {
+    true
+}
+
+fn default_max_redirects() -> usize {
+    5
+}
+
+fn default_base_group_fallback() -> bool {
     true
 }
 
-fn default_toggle_attachments() -> bool {
+fn default_recursive() -> bool {
     true
 }
+
+fn default_derive_memory_kib() -> u32 {
+    argon2::Params::DEFAULT_M_COST
+}
+
+fn default_derive_iterations() -> u32 {
+    argon2::Params::DEFAULT_T_COST
+}
+
+fn default_derive_parallelism() -> u32 {
+    argon2::Params::DEFAULT_P_COST
+}
+
+fn default_derive_length() -> usize {
+    32
+}
+
+fn default_derive_encoding() -> DeriveEncoding {
+    DeriveEncoding::Hex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_dataset_name_strips_slashes_and_whitespace() {
+        for name in ["/tank/x", "tank/x/", "/tank/x/", "  tank/x\t", " /tank/x/ "] {
+            assert_eq!(
+                normalize_dataset_name(name).unwrap(),
+                "tank/x",
+                "{:?}",
+                name
+            );
+        }
+        assert_eq!(
+            normalize_dataset_name("tank/My Media_2:old.v-1").unwrap(),
+            "tank/My Media_2:old.v-1"
+        );
+    }
+
+    #[test]
+    fn normalize_dataset_name_rejects_malformed_names() {
+        let too_long = format!("tank/{}", "x".repeat(251));
+        for name in [
+            "",
+            "   ",
+            "/",
+            "tank//x",
+            "tank/x@snap",
+            "tank/x#bm",
+            "tank/./x",
+            "tank/../x",
+            "tank/x/..",
+            "1tank/x",
+            "tank/ x",
+            "tank/x$",
+            too_long.as_str(),
+        ] {
+            assert!(
+                normalize_dataset_name(name).is_err(),
+                "{:?} was accepted",
+                name
+            );
+        }
+    }
+
+    /// A fresh directory under the system temp dir for one test's files.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("naslock-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    const YAML_CONFIG: &str = r#"
+keepass:
+  path: /secrets/shared.kdbx
+  key_file: ~
+nas:
+  home:
+    host: nas-home.local
+    auth_entry: home login
+    timeout_secs: null
+volume:
+  media:
+    nas: home
+    dataset: /tank/media
+    unlock_entry: media
+    verify: ~
+"#;
+
+    const JSON_CONFIG: &str = r#"{
+  "keepass": {"path": "/secrets/shared.kdbx", "key_file": null},
+  "nas": {"home": {"host": "nas-home.local", "auth_entry": "home login", "timeout_secs": null}},
+  "volume": {"media": {"nas": "home", "dataset": "/tank/media", "unlock_entry": "media", "verify": null}}
+}"#;
+
+    fn assert_loaded(cfg: &Config) {
+        assert_eq!(cfg.nas["home"].host, "nas-home.local");
+        assert_eq!(cfg.volume["media"].dataset, "tank/media");
+        assert_eq!(cfg.volume["media"].verify, None);
+    }
+
+    #[test]
+    fn load_reads_yaml_and_json() {
+        let dir = test_dir("load-formats");
+        for (name, content) in [
+            ("naslock.yaml", YAML_CONFIG),
+            ("naslock.yml", YAML_CONFIG),
+            ("naslock.json", JSON_CONFIG),
+        ] {
+            let path = dir.join(name);
+            fs::write(&path, content).unwrap();
+            let cfg = Config::load(&path).unwrap_or_else(|err| panic!("{}: {:#}", name, err));
+            assert_loaded(&cfg);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn load_dir_merges_yaml_and_json_files() {
+        let dir = test_dir("load-dir-formats");
+        fs::write(dir.join("10-base.yaml"), YAML_CONFIG).unwrap();
+        fs::write(
+            dir.join("20-backup.json"),
+            r#"{"volume": {"backup": {"nas": "home", "dataset": "tank/backup", "unlock_entry": "backup"}}}"#,
+        )
+        .unwrap();
+        fs::write(dir.join("30-notes.txt"), "not a config file").unwrap();
+        let cfg = Config::load(&dir).unwrap();
+        assert_loaded(&cfg);
+        assert_eq!(cfg.volume["backup"].dataset, "tank/backup");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn keepass_config(dir: &Path, nas_extra: &str) -> Config {
+        let text = format!(
+            r#"
+[keepass]
+path = "{dir}/shared.kdbx"
+key_file = "{dir}/shared.key"
+
+[keepass.work]
+path = "{dir}/work.kdbx"
+
+[nas.home]
+host = "nas-home.local"
+auth_entry = "home login"
+{nas_extra}
+
+[volume.media]
+nas = "home"
+dataset = "tank/media"
+unlock_entry = "media"
+keepass_path = "{dir}/media.kdbx"
+"#,
+            dir = dir.display(),
+            nas_extra = nas_extra
+        );
+        Config::from_table(toml::from_str(&text).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn keepass_path_is_a_database_profile() {
+        let dir = test_dir("keepass-path");
+        for name in [
+            "shared.kdbx",
+            "shared.key",
+            "work.kdbx",
+            "media.kdbx",
+            "home.kdbx",
+            "home.key",
+        ] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let cfg = keepass_config(
+            &dir,
+            &format!(
+                "keepass_path = \"{0}/home.kdbx\"\nkeepass_key_file = \"{0}/home.key\"",
+                dir.display()
+            ),
+        );
+        cfg.validate().unwrap();
+        let nas = &cfg.nas["home"];
+        let volume = &cfg.volume["media"];
+        assert_eq!(nas.keepass.as_deref(), Some("nas.home"));
+        let home_key = dir.join("home.key");
+        assert_eq!(
+            cfg.nas_database(nas),
+            KeepassDatabase {
+                path: &dir.join("home.kdbx"),
+                key_file: Some(KeyFile::Path(&home_key)),
+            }
+        );
+        assert_eq!(
+            cfg.volume_database(nas, volume).path,
+            dir.join("media.kdbx")
+        );
+        assert_eq!(cfg.volume_database(nas, volume).key_file, None);
+
+        let err = keepass_config(
+            &dir,
+            "keepass = \"work\"\nkeepass_path = \"/elsewhere.kdbx\"",
+        )
+        .validate()
+        .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("keepass and keepass_path cannot both be set"),
+            "{:#}",
+            err
+        );
+        let err = keepass_config(&dir, "keepass_path = \"/missing.kdbx\"")
+            .validate()
+            .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("nas.home.keepass_path /missing.kdbx does not exist"),
+            "{:#}",
+            err
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn validate_rejects_one_database_with_two_key_files() {
+        let dir = test_dir("two-key-files");
+        for name in [
+            "shared.kdbx",
+            "shared.key",
+            "work.kdbx",
+            "media.kdbx",
+            "other.key",
+        ] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let shared = dir.join("shared.kdbx");
+        let same_key = format!(
+            "keepass_path = \"{0}/shared.kdbx\"\nkeepass_key_file = \"{0}/shared.key\"",
+            dir.display()
+        );
+        keepass_config(&dir, &same_key).validate().unwrap();
+
+        for nas_extra in [
+            format!("keepass_path = \"{}/shared.kdbx\"", dir.display()),
+            format!(
+                "keepass_path = \"{0}/shared.kdbx\"\nkeepass_key_file = \"{0}/other.key\"",
+                dir.display()
+            ),
+        ] {
+            let err = keepass_config(&dir, &nas_extra).validate().unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "invalid config: keepass.path and nas.home.keepass_path both open {} but with different key files",
+                    shared.display()
+                )
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn normalize_dataset_name_allows_the_maximum_length() {
+        let name = format!("tank/{}", "x".repeat(250));
+        assert_eq!(name.len(), 255);
+        assert_eq!(normalize_dataset_name(&name).unwrap(), name);
+    }
+}
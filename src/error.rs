@@ -0,0 +1,128 @@
+use serde_json::json;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Config,
+    KeePass,
+    Network,
+    Auth,
+    Api,
+    Rejected,
+    Job,
+    /// The operation did not finish within `--timeout`, or the run exceeded
+    /// `--timeout-total`.
+    Timeout,
+    Other,
+}
+
+impl ErrorKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::Config => "config",
+            ErrorKind::KeePass => "keepass",
+            ErrorKind::Network => "network",
+            ErrorKind::Auth => "auth",
+            ErrorKind::Api => "api",
+            ErrorKind::Rejected => "rejected",
+            ErrorKind::Job => "job",
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::Other => "other",
+        }
+    }
+
+    /// Process exit status for a run that failed with this kind, so scripts
+    /// can tell a wrong password from an unreachable NAS.
+    pub fn exit_code(self) -> u8 {
+        match self {
+            ErrorKind::Other => 1,
+            ErrorKind::Config => 2,
+            ErrorKind::KeePass => 3,
+            ErrorKind::Auth => 4,
+            ErrorKind::Rejected => 5,
+            ErrorKind::Job => 6,
+            ErrorKind::Network => 7,
+            ErrorKind::Api => 8,
+            ErrorKind::Timeout => 9,
+        }
+    }
+}
+
+/// Carries an explicit kind for an error chain. Displays exactly like the
+/// wrapped error so human-readable output is unaffected.
+#[derive(Debug)]
+struct Tagged {
+    kind: ErrorKind,
+    inner: anyhow::Error,
+}
+
+impl fmt::Display for Tagged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.inner, f)
+    }
+}
+
+impl std::error::Error for Tagged {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.inner.source()
+    }
+}
+
+pub fn tag(kind: ErrorKind, err: anyhow::Error) -> anyhow::Error {
+    anyhow::Error::new(Tagged { kind, inner: err })
+}
+
+pub trait ResultExt<T> {
+    fn kind(self, kind: ErrorKind) -> anyhow::Result<T>;
+}
+
+impl<T> ResultExt<T> for anyhow::Result<T> {
+    fn kind(self, kind: ErrorKind) -> anyhow::Result<T> {
+        self.map_err(|err| tag(kind, err))
+    }
+}
+
+pub fn classify(err: &anyhow::Error) -> ErrorKind {
+    for cause in err.chain() {
+        if let Some(tagged) = cause.downcast_ref::<Tagged>() {
+            return tagged.kind;
+        }
+        if let Some(api) = cause.downcast_ref::<crate::truenas::ApiError>() {
+            return match api.status.as_u16() {
+                401 | 403 => ErrorKind::Auth,
+                _ => ErrorKind::Api,
+            };
+        }
+        if let Some(rpc) = cause.downcast_ref::<crate::websocket::RpcError>() {
+            return if rpc.is_auth() {
+                ErrorKind::Auth
+            } else {
+                ErrorKind::Api
+            };
+        }
+        if cause.is::<reqwest::Error>() || cause.is::<tungstenite::Error>() {
+            return ErrorKind::Network;
+        }
+        if cause.is::<keepass::error::DatabaseOpenError>() {
+            return ErrorKind::KeePass;
+        }
+        if cause.is::<toml::de::Error>() {
+            return ErrorKind::Config;
+        }
+    }
+    ErrorKind::Other
+}
+
+pub fn to_json(err: &anyhow::Error) -> serde_json::Value {
+    let context: Vec<String> = err.chain().skip(1).map(|cause| cause.to_string()).collect();
+    let kind = classify(err);
+    json!({
+        "status": "error",
+        "error": {
+            "kind": kind.as_str(),
+            "exit_code": kind.exit_code(),
+            "message": err.to_string(),
+            "context": context,
+        }
+    })
+}
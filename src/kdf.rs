@@ -0,0 +1,41 @@
+use crate::config::{DeriveConfig, DeriveEncoding, KdfAlgorithm};
+use anyhow::{Result, anyhow, bail};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::Engine;
+use zeroize::Zeroizing;
+
+/// Derives the dataset passphrase from the stored secret, encoding the raw
+/// KDF output as text so it can be sent as a passphrase.
+pub fn derive_passphrase(cfg: &DeriveConfig, secret: &str) -> Result<Zeroizing<String>> {
+    if cfg.salt.len() < argon2::MIN_SALT_LEN {
+        bail!(
+            "derive.salt must be at least {} bytes long",
+            argon2::MIN_SALT_LEN
+        );
+    }
+    let params = Params::new(
+        cfg.memory_kib,
+        cfg.iterations,
+        cfg.parallelism,
+        Some(cfg.length),
+    )
+    .map_err(|err| anyhow!("invalid derive parameters: {}", err))?;
+    let algorithm = match cfg.kdf {
+        KdfAlgorithm::Argon2id => Algorithm::Argon2id,
+        KdfAlgorithm::Argon2i => Algorithm::Argon2i,
+        KdfAlgorithm::Argon2d => Algorithm::Argon2d,
+    };
+
+    let mut output = Zeroizing::new(vec![0u8; cfg.length]);
+    Argon2::new(algorithm, Version::V0x13, params)
+        .hash_password_into(secret.as_bytes(), cfg.salt.as_bytes(), &mut output)
+        .map_err(|err| anyhow!("failed to derive passphrase: {}", err))?;
+
+    let encoded = match cfg.encoding {
+        DeriveEncoding::Hex => hex::encode(output.as_slice()),
+        DeriveEncoding::Base64 => {
+            base64::engine::general_purpose::STANDARD.encode(output.as_slice())
+        }
+    };
+    Ok(Zeroizing::new(encoded))
+}
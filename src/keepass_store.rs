@@ -1,8 +1,13 @@
 use anyhow::{Context, Result, bail};
-use keepass::db::{Entry, NodeRef};
+use base64::Engine;
+use keepass::db::{Entry, Group, NodeRef};
 use keepass::{Database, DatabaseKey};
-use std::fs::File;
-use std::path::Path;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use totp_rs::{Algorithm, Secret, TOTP};
+use url::Url;
 use uuid::Uuid;
 use zeroize::Zeroizing;
 
@@ -10,79 +15,397 @@ pub struct KeePassStore {
     db: Database,
 }
 
+/// Where bare selectors are resolved: inside `base_group` first, then across
+/// the whole database when `fallback` is set.
+#[derive(Clone, Copy, Default)]
+pub struct SearchRoot<'a> {
+    pub base_group: Option<&'a str>,
+    pub fallback: bool,
+}
+
+/// The key file that opens a database: on disk, given inline in the config
+/// as its hex- or base64-encoded content, or printed by a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyFile<'a> {
+    Path(&'a Path),
+    Inline(&'a str),
+    Command(&'a [String]),
+}
+
 impl KeePassStore {
-    pub fn open(path: &Path, key_file: Option<&Path>, password: &str) -> Result<Self> {
-        let mut db_file = File::open(path)
-            .with_context(|| format!("failed to open KeePass DB {}", path.display()))?;
+    pub fn open(path: &Path, key_file: Option<KeyFile<'_>>, password: &str) -> Result<Self> {
+        if let Some(lock_file) = lock_file(path) {
+            tracing::debug!(
+                "{} exists; the KeePass DB may be open in another program",
+                lock_file.display()
+            );
+        }
+        let content = read_database(path)?;
 
         let mut key = DatabaseKey::new().with_password(password);
-        if let Some(key_file_path) = key_file {
-            let mut key_file = File::open(key_file_path)
-                .with_context(|| format!("failed to open key file {}", key_file_path.display()))?;
-            key = key.with_keyfile(&mut key_file)?;
+        match key_file {
+            Some(KeyFile::Path(key_file_path)) => {
+                let mut key_file = File::open(key_file_path).with_context(|| {
+                    format!("failed to open key file {}", key_file_path.display())
+                })?;
+                key = key.with_keyfile(&mut key_file)?;
+            }
+            Some(KeyFile::Inline(text)) => {
+                let content = decode_inline_key_file(text)?;
+                key = key.with_keyfile(&mut content.as_slice())?;
+            }
+            Some(KeyFile::Command(command)) => {
+                let content = key_file_from_command(command)?;
+                key = key.with_keyfile(&mut content.as_slice())?;
+            }
+            None => {}
         }
 
-        let db = Database::open(&mut db_file, key)
+        let db = Database::open(&mut content.as_slice(), key)
             .with_context(|| format!("failed to open KeePass DB {}", path.display()))?;
         Ok(Self { db })
     }
 
-    pub fn find_entry<'a>(&'a self, selector: &str) -> Option<&'a Entry> {
-        let selector = selector.trim();
-        let (mode, token) = parse_selector(selector);
-        match mode {
-            SelectorMode::Uuid => {
-                let uuid = parse_uuid(token)?;
-                for node in &self.db.root {
-                    if let NodeRef::Entry(entry) = node {
-                        if entry.get_uuid() == &uuid {
-                            return Some(entry);
-                        }
-                    }
-                }
-                None
-            }
-            SelectorMode::Title => {
-                for node in &self.db.root {
-                    if let NodeRef::Entry(entry) = node {
-                        if let Some(title) = entry.get_title() {
-                            if title == token {
-                                return Some(entry);
-                            }
-                        }
-                    }
-                }
-                None
+    /// Every entry in the database that matches `selector`.
+    pub fn find_entries<'a>(&'a self, selector: &str) -> Vec<&'a Entry> {
+        find_entries_under(&self.db.root, selector)
+    }
+
+    /// Every entry that matches `selector`, searched under `root`'s base
+    /// group first. A `group:<path>:<selector>` selector is searched only
+    /// under that group, ignoring the base group and its fallback.
+    pub fn find_entries_from<'a>(
+        &'a self,
+        root: SearchRoot<'_>,
+        selector: &str,
+    ) -> Result<Vec<&'a Entry>> {
+        if let Some((path, scoped)) = parse_group_scope(selector) {
+            if scoped.is_empty() {
+                bail!(
+                    "KeePass selector {:?} names no entry; write it as group:<group>:<selector>",
+                    selector
+                );
             }
-            SelectorMode::Auto => {
-                let uuid = parse_uuid(token);
-                for node in &self.db.root {
-                    if let NodeRef::Entry(entry) = node {
-                        if let Some(uuid) = uuid {
-                            if entry.get_uuid() == &uuid {
-                                return Some(entry);
-                            }
-                        }
-                        if let Some(title) = entry.get_title() {
-                            if title == token {
-                                return Some(entry);
-                            }
-                        }
-                    }
+            let group = self
+                .find_group(path)
+                .with_context(|| format!("KeePass group not found: {}", path))?;
+            return Ok(find_entries_under(group, scoped));
+        }
+        let Some(base_group) = root.base_group else {
+            return Ok(self.find_entries(selector));
+        };
+        let group = self
+            .find_group(base_group)
+            .with_context(|| format!("KeePass group not found: {}", base_group))?;
+        let entries = find_entries_under(group, selector);
+        if entries.is_empty() && root.fallback {
+            return Ok(self.find_entries(selector));
+        }
+        Ok(entries)
+    }
+
+    /// Returns the content of the binary attachment `name` on `entry`.
+    pub fn attachment<'a>(&'a self, entry: &Entry, name: &str) -> Option<&'a [u8]> {
+        let reference = entry.binary_refs.get(name)?;
+        if let Some(binary) = self
+            .db
+            .meta
+            .binaries
+            .binaries
+            .iter()
+            .find(|binary| binary.identifier.as_deref() == Some(reference.as_str()))
+        {
+            return Some(&binary.content);
+        }
+        let index: usize = reference.parse().ok()?;
+        self.db
+            .header_attachments
+            .get(index)
+            .map(|attachment| attachment.content.as_slice())
+    }
+
+    /// Resolves a `/`-separated group path relative to the root group. A
+    /// leading segment naming the root group itself is accepted as well.
+    pub fn find_group<'a>(&'a self, path: &str) -> Option<&'a Group> {
+        let segments: Vec<&str> = path
+            .split('/')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .collect();
+        let lookup = |segments: &[&str]| match self.db.root.get(segments) {
+            Some(NodeRef::Group(group)) => Some(group),
+            _ => None,
+        };
+        lookup(&segments).or_else(|| match segments.split_first() {
+            Some((first, rest)) if *first == self.db.root.name => lookup(rest),
+            _ => None,
+        })
+    }
+}
+
+/// Every entry under `group` that matches `selector`. A bare selector that
+/// parses as a UUID and matches one only returns that entry.
+fn find_entries_under<'a>(group: &'a Group, selector: &str) -> Vec<&'a Entry> {
+    let selector = selector.trim();
+    let (mode, token) = parse_selector(selector);
+    let entries = || {
+        group.into_iter().filter_map(|node| match node {
+            NodeRef::Entry(entry) => Some(entry),
+            NodeRef::Group(_) => None,
+        })
+    };
+    let with_title = || {
+        entries()
+            .filter(|entry| entry.get_title() == Some(token))
+            .collect()
+    };
+    match mode {
+        SelectorMode::Uuid => match parse_uuid(token) {
+            Some(uuid) => entries()
+                .filter(|entry| entry.get_uuid() == &uuid)
+                .collect(),
+            None => Vec::new(),
+        },
+        SelectorMode::Title => with_title(),
+        SelectorMode::Path => {
+            let segments: Vec<&str> = token
+                .split('/')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .collect();
+            let lookup = |segments: &[&str]| match group.get(segments) {
+                Some(NodeRef::Entry(entry)) => Some(entry),
+                _ => None,
+            };
+            lookup(&segments)
+                .or_else(|| match segments.split_first() {
+                    Some((first, rest)) if *first == group.name => lookup(rest),
+                    _ => None,
+                })
+                .into_iter()
+                .collect()
+        }
+        SelectorMode::Url => {
+            let wanted = normalize_url(token);
+            entries()
+                .filter(|entry| {
+                    entry_field(entry, "url").is_some_and(|url| normalize_url(url) == wanted)
+                })
+                .collect()
+        }
+        SelectorMode::Auto => {
+            if let Some(uuid) = parse_uuid(token) {
+                if let Some(entry) = entries().find(|entry| entry.get_uuid() == &uuid) {
+                    return vec![entry];
                 }
-                None
             }
+            with_title()
         }
     }
 }
 
-pub fn required_field(entry: &Entry, field: &str, entry_label: &str) -> Result<Zeroizing<String>> {
+/// Reads `field` from `entry`, replacing any `{REF:...}` field references in
+/// the value with the fields they point at.
+pub fn required_field(
+    store: &KeePassStore,
+    entry: &Entry,
+    field: &str,
+    entry_label: &str,
+) -> Result<Zeroizing<String>> {
     let value = entry_field(entry, field)
         .with_context(|| format!("missing field '{}' in KeePass entry {}", field, entry_label))?;
-    Ok(Zeroizing::new(value.to_string()))
+    resolve_references(store, value, 0).with_context(|| {
+        format!(
+            "failed to resolve a field reference in '{}' of KeePass entry {}",
+            field, entry_label
+        )
+    })
+}
+
+/// How many references deep a chain may go before it is treated as a cycle.
+const MAX_REFERENCE_DEPTH: usize = 10;
+
+/// Replaces every `{REF:<field>@<search-in>:<text>}` in `value` with the
+/// referenced field of the one entry the search finds, as KeePass does.
+fn resolve_references(
+    store: &KeePassStore,
+    value: &str,
+    depth: usize,
+) -> Result<Zeroizing<String>> {
+    let mut resolved = Zeroizing::new(String::with_capacity(value.len()));
+    let mut rest = value;
+    while let Some(start) = rest.find("{REF:") {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        resolved.push_str(&rest[..start]);
+        let reference = &rest[start + 5..start + len];
+        if depth >= MAX_REFERENCE_DEPTH {
+            bail!(
+                "field reference {{REF:{}}} is nested more than {} levels deep",
+                reference,
+                MAX_REFERENCE_DEPTH
+            );
+        }
+        resolved.push_str(&resolve_reference(store, reference, depth)?);
+        rest = &rest[start + len + 1..];
+    }
+    resolved.push_str(rest);
+    Ok(resolved)
+}
+
+/// Resolves the inside of one `{REF:...}`: `<field>@<search-in>:<text>`.
+fn resolve_reference(
+    store: &KeePassStore,
+    reference: &str,
+    depth: usize,
+) -> Result<Zeroizing<String>> {
+    let invalid = || format!("invalid field reference {{REF:{}}}", reference);
+    let (wanted, search) = reference.split_once('@').with_context(invalid)?;
+    let (search_in, text) = search.split_once(':').with_context(invalid)?;
+    let code = |part: &str| match part.as_bytes() {
+        [c] => Ok(c.to_ascii_uppercase()),
+        _ => Err(anyhow::anyhow!(invalid())),
+    };
+    let (wanted, search_in) = (code(wanted)?, code(search_in)?);
+
+    let target = reference_target(store, search_in, text)
+        .with_context(|| format!("field reference {{REF:{}}}", reference))?;
+    let uuid;
+    let value = match wanted {
+        b'I' => {
+            uuid = target.get_uuid().simple().to_string().to_ascii_uppercase();
+            Some(uuid.as_str())
+        }
+        _ => {
+            let name = reference_field(wanted).with_context(|| {
+                format!(
+                    "unknown field '{}' in {{REF:{}}}",
+                    wanted as char, reference
+                )
+            })?;
+            target.get(name)
+        }
+    };
+    resolve_references(store, value.unwrap_or_default(), depth + 1)
+}
+
+/// The standard KeePass fields, by the letter a reference names them with.
+const STANDARD_FIELDS: [&str; 5] = ["Title", "UserName", "Password", "URL", "Notes"];
+
+fn reference_field(code: u8) -> Option<&'static str> {
+    let index = b"TUPAN".iter().position(|c| *c == code)?;
+    Some(STANDARD_FIELDS[index])
+}
+
+/// The one entry a reference searches for: by UUID, or by a field
+/// containing `text`, case-insensitively. `O` searches the custom fields.
+fn reference_target<'a>(store: &'a KeePassStore, search_in: u8, text: &str) -> Result<&'a Entry> {
+    let entries = store.db.root.into_iter().filter_map(|node| match node {
+        NodeRef::Entry(entry) => Some(entry),
+        NodeRef::Group(_) => None,
+    });
+    let needle = text.to_lowercase();
+    let contains = |value: Option<&str>| value.is_some_and(|v| v.to_lowercase().contains(&needle));
+    let matches: Vec<&Entry> = match search_in {
+        b'I' => {
+            let uuid = parse_uuid(text).with_context(|| format!("invalid UUID '{}'", text))?;
+            entries.filter(|entry| entry.get_uuid() == &uuid).collect()
+        }
+        b'O' => entries
+            .filter(|entry| {
+                entry
+                    .fields
+                    .keys()
+                    .filter(|key| !STANDARD_FIELDS.contains(&key.as_str()))
+                    .any(|key| contains(entry.get(key)))
+            })
+            .collect(),
+        code => {
+            let name = reference_field(code)
+                .with_context(|| format!("unknown search field '{}'", code as char))?;
+            entries.filter(|entry| contains(entry.get(name))).collect()
+        }
+    };
+    match matches.as_slice() {
+        [entry] => Ok(entry),
+        [] => bail!("no KeePass entry matches"),
+        _ => bail!(
+            "{} KeePass entries match; reference the entry by UUID (@I:) instead",
+            matches.len()
+        ),
+    }
 }
 
+/// Reads the TOTP seed in `field`: an `otpauth://` URI, or a bare base32
+/// secret for 6-digit SHA-1 codes with a 30-second step. The code itself is
+/// computed only when logging in, so it cannot expire while hooks run.
+pub fn totp_seed(
+    store: &KeePassStore,
+    entry: &Entry,
+    field: &str,
+    entry_label: &str,
+) -> Result<TOTP> {
+    let seed = required_field(store, entry, field, entry_label)?;
+    let seed = seed.trim();
+    let totp = if seed.starts_with("otpauth://") {
+        TOTP::from_url_unchecked(seed)
+            .with_context(|| format!("invalid TOTP URI in KeePass entry {}", entry_label))?
+    } else {
+        let encoded: String = seed
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '=')
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+        let secret = Zeroizing::new(
+            Secret::Encoded(encoded)
+                .to_bytes()
+                .with_context(|| format!("invalid TOTP seed in KeePass entry {}", entry_label))?,
+        );
+        TOTP::new_unchecked(
+            Algorithm::SHA1,
+            6,
+            1,
+            30,
+            secret.to_vec(),
+            None,
+            String::new(),
+        )
+    };
+    Ok(totp)
+}
+
+/// The names of `entry`'s fields: the standard ones in the order KeePass
+/// shows them, then the custom fields sorted by name.
+pub fn field_names(entry: &Entry) -> Vec<&str> {
+    let mut custom: Vec<&str> = entry
+        .fields
+        .keys()
+        .map(String::as_str)
+        .filter(|key| !STANDARD_FIELDS.contains(key))
+        .collect();
+    custom.sort_unstable();
+    STANDARD_FIELDS
+        .into_iter()
+        .filter(|key| entry.fields.contains_key(*key))
+        .chain(custom)
+        .collect()
+}
+
+/// The names of `entry`'s attachments, sorted.
+pub fn attachment_names(entry: &Entry) -> Vec<&str> {
+    let mut names: Vec<&str> = entry.binary_refs.keys().map(String::as_str).collect();
+    names.sort_unstable();
+    names
+}
+
+/// Reads `field` from `entry`. Standard fields are matched case-insensitively
+/// and under common aliases (`user`, `pass`); a `field:` prefix skips that and
+/// reads the custom field with exactly the given name.
 pub fn entry_field<'a>(entry: &'a Entry, field: &str) -> Option<&'a str> {
+    if let Some(prefix) = field.get(..6).filter(|p| p.eq_ignore_ascii_case("field:")) {
+        return entry.get(&field[prefix.len()..]);
+    }
     let field_trimmed = field.trim();
     let field_lower = field_trimmed.to_ascii_lowercase();
     match field_lower.as_str() {
@@ -94,6 +417,18 @@ pub fn entry_field<'a>(entry: &'a Entry, field: &str) -> Option<&'a str> {
     }
 }
 
+/// Splits a `group:<group path>:<selector>` selector into the group path and
+/// the selector searched under it.
+fn parse_group_scope(input: &str) -> Option<(&str, &str)> {
+    let input = input.trim();
+    let rest = input
+        .get(..6)
+        .filter(|prefix| prefix.eq_ignore_ascii_case("group:"))
+        .map(|_| &input[6..])?;
+    let (path, selector) = rest.split_once(':').unwrap_or((rest, ""));
+    Some((path.trim(), selector.trim()))
+}
+
 fn parse_selector(input: &str) -> (SelectorMode, &str) {
     let lowered = input.to_ascii_lowercase();
     if let Some(rest) = lowered.strip_prefix("uuid:") {
@@ -104,9 +439,27 @@ fn parse_selector(input: &str) -> (SelectorMode, &str) {
         let original = &input[input.len() - rest.len()..];
         return (SelectorMode::Title, original.trim());
     }
+    if let Some(rest) = lowered.strip_prefix("url:") {
+        let original = &input[input.len() - rest.len()..];
+        return (SelectorMode::Url, original.trim());
+    }
+    if let Some(rest) = lowered.strip_prefix("path:") {
+        let original = &input[input.len() - rest.len()..];
+        return (SelectorMode::Path, original.trim());
+    }
     (SelectorMode::Auto, input)
 }
 
+/// Canonicalizes a URL for comparison: trailing slashes are ignored and the
+/// scheme and host are compared case-insensitively when the value parses.
+fn normalize_url(input: &str) -> String {
+    let trimmed = input.trim().trim_end_matches('/');
+    match Url::parse(trimmed) {
+        Ok(url) => url.as_str().trim_end_matches('/').to_string(),
+        Err(_) => trimmed.to_string(),
+    }
+}
+
 fn parse_uuid(input: &str) -> Option<Uuid> {
     let trimmed = input.trim().trim_matches('{').trim_matches('}');
     if let Ok(uuid) = Uuid::parse_str(trimmed) {
@@ -125,12 +478,191 @@ enum SelectorMode {
     Auto,
     Title,
     Uuid,
+    Url,
+    /// `/`-separated group names ending in the entry title.
+    Path,
 }
 
-pub fn require_entry<'a>(store: &'a KeePassStore, selector: &str) -> Result<&'a Entry> {
-    store
-        .find_entry(selector)
-        .with_context(|| format!("KeePass entry not found: {}", selector))
+pub fn require_entry<'a>(
+    store: &'a KeePassStore,
+    root: SearchRoot<'_>,
+    selector: &str,
+) -> Result<&'a Entry> {
+    let entries = store.find_entries_from(root, selector)?;
+    match entries.as_slice() {
+        [entry] => {
+            tracing::debug!(selector, uuid = %entry.get_uuid(), "matched KeePass entry");
+            Ok(entry)
+        }
+        [] => match root.base_group {
+            Some(group) if !root.fallback => {
+                bail!("KeePass entry not found in group {}: {}", group, selector)
+            }
+            _ => bail!("KeePass entry not found: {}", selector),
+        },
+        _ => {
+            let uuids: Vec<String> = entries
+                .iter()
+                .map(|entry| entry.get_uuid().to_string())
+                .collect();
+            bail!(
+                "KeePass selector {:?} matches {} entries ({}); use uuid:, path: or group: to pick one",
+                selector,
+                entries.len(),
+                uuids.join(", ")
+            )
+        }
+    }
+}
+
+/// Runs `key_file_command` and returns its stdout, byte for byte, as the key
+/// file content. The command's stderr is passed through.
+fn key_file_from_command(command: &[String]) -> Result<Zeroizing<Vec<u8>>> {
+    let Some((program, args)) = command.split_first() else {
+        bail!("key_file_command must not be empty");
+    };
+    let output = std::process::Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .with_context(|| format!("failed to run key_file_command '{}'", program))?;
+    let stdout = Zeroizing::new(output.stdout);
+    if !output.status.success() {
+        bail!(
+            "key_file_command '{}' exited with {}",
+            program,
+            output.status
+        );
+    }
+    if stdout.is_empty() {
+        bail!("key_file_command '{}' printed nothing", program);
+    }
+    Ok(stdout)
+}
+
+/// How often reading the database is attempted while it is locked or
+/// changes underneath, as during a save or a sync, and the pause in between.
+const READ_ATTEMPTS: u32 = 5;
+const READ_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// Reads the whole database into memory, so it is parsed from one consistent
+/// copy even when another program saves over the file at the same time.
+fn read_database(path: &Path) -> Result<Vec<u8>> {
+    let mut attempt = 1;
+    loop {
+        match read_snapshot(path) {
+            Ok(Some(content)) => return Ok(content),
+            Ok(None) if attempt < READ_ATTEMPTS => {
+                tracing::debug!(
+                    attempt,
+                    "KeePass DB changed while it was read; reading again"
+                );
+            }
+            Ok(None) => bail!(
+                "KeePass DB {} kept changing while it was read; is it being saved or synced?",
+                path.display()
+            ),
+            Err(err) if is_lock_error(&err) && attempt < READ_ATTEMPTS => {
+                tracing::debug!(attempt, "KeePass DB is locked: {}", err);
+            }
+            Err(err) if is_lock_error(&err) => {
+                return Err(err).with_context(|| {
+                    format!(
+                        "failed to open KeePass DB {}: the file is locked by another program; \
+                         close the database there or wait for the sync to finish",
+                        path.display()
+                    )
+                });
+            }
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("failed to open KeePass DB {}", path.display()));
+            }
+        }
+        if attempt == 1 {
+            warn_if_open_elsewhere(path);
+        }
+        attempt += 1;
+        std::thread::sleep(READ_RETRY_DELAY);
+    }
+}
+
+/// Reads the file once. Returns `None` if its size or modification time
+/// changed while it was read, which means the copy may be half-written.
+fn read_snapshot(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    let mut options = OpenOptions::new();
+    options.read(true);
+    // Let other programs keep reading, writing and replacing the file while
+    // it is open here, so naslock never causes a sharing violation itself.
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        const FILE_SHARE_READ_WRITE_DELETE: u32 = 0x1 | 0x2 | 0x4;
+        options.share_mode(FILE_SHARE_READ_WRITE_DELETE);
+    }
+    let mut file = options.open(path)?;
+    let before = file.metadata()?;
+    let mut content = Vec::with_capacity(before.len() as usize);
+    file.read_to_end(&mut content)?;
+    let after = std::fs::metadata(path)?;
+    let unchanged = content.len() as u64 == before.len()
+        && after.len() == before.len()
+        && after.modified().ok() == before.modified().ok();
+    Ok(unchanged.then_some(content))
+}
+
+/// Whether opening or reading failed because another program holds the
+/// file: a sharing or lock violation on Windows.
+fn is_lock_error(err: &io::Error) -> bool {
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    const ERROR_LOCK_VIOLATION: i32 = 33;
+    cfg!(windows)
+        && matches!(
+            err.raw_os_error(),
+            Some(ERROR_SHARING_VIOLATION | ERROR_LOCK_VIOLATION)
+        )
+}
+
+/// The lock file KeePass (`<name>.lock`) or KeePassXC (`.<name>.lock`)
+/// keeps next to a database it has open, if there is one.
+fn lock_file(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    [
+        path.with_file_name(format!("{}.lock", name)),
+        path.with_file_name(format!(".{}.lock", name)),
+    ]
+    .into_iter()
+    .find(|lock_file| lock_file.exists())
+}
+
+/// Warns, once a read has had to be retried, when a lock file shows why:
+/// naslock only reads the database, but a save from another program can
+/// land mid-read. A lock file alone is only logged at debug level, since
+/// keeping the database open in KeePass is fine.
+fn warn_if_open_elsewhere(path: &Path) {
+    if let Some(lock_file) = lock_file(path) {
+        tracing::warn!(
+            "{} exists; the KeePass DB is probably open in another program",
+            lock_file.display()
+        );
+    }
+}
+
+/// Decodes `key_file_inline`: the bytes of a key file (XML, 32-byte binary or
+/// any other file) written as hex or, failing that, standard base64.
+pub fn decode_inline_key_file(text: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let text = text.trim();
+    if text.is_empty() {
+        bail!("key_file_inline is empty");
+    }
+    if let Ok(bytes) = hex::decode(text) {
+        return Ok(Zeroizing::new(bytes));
+    }
+    base64::engine::general_purpose::STANDARD
+        .decode(text)
+        .map(Zeroizing::new)
+        .context("key_file_inline is neither hex nor base64")
 }
 
 pub fn ensure_non_empty(secret: &str, label: &str) -> Result<()> {
@@ -139,3 +671,99 @@ pub fn ensure_non_empty(secret: &str, label: &str) -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keepass::db::Value;
+
+    fn entry(title: &str, password: &str) -> Entry {
+        let mut entry = Entry::new();
+        for (key, value) in [("Title", title), ("Password", password)] {
+            entry
+                .fields
+                .insert(key.to_string(), Value::Unprotected(value.to_string()));
+        }
+        entry
+    }
+
+    fn store(entries: Vec<Entry>) -> KeePassStore {
+        let mut db = Database::new(Default::default());
+        let mut group = Group::new("nas");
+        for entry in entries {
+            group.add_child(entry);
+        }
+        db.root.add_child(group);
+        KeePassStore { db }
+    }
+
+    fn resolve(store: &KeePassStore, value: &str) -> Result<String> {
+        resolve_references(store, value, 0).map(|resolved| resolved.to_string())
+    }
+
+    #[test]
+    fn resolves_a_reference() {
+        let store = store(vec![entry("media", "hunter2")]);
+        assert_eq!(resolve(&store, "{REF:P@T:media}").unwrap(), "hunter2");
+        assert_eq!(
+            resolve(&store, "pre-{REF:p@t:MEDIA}-post").unwrap(),
+            "pre-hunter2-post"
+        );
+        assert_eq!(resolve(&store, "{REF:T@P:hunter}").unwrap(), "media");
+    }
+
+    #[test]
+    fn resolves_nested_references() {
+        let store = store(vec![
+            entry("media", "{REF:P@T:backup}"),
+            entry("backup", "{REF:P@T:shared}"),
+            entry("shared", "hunter2"),
+        ]);
+        assert_eq!(resolve(&store, "{REF:P@T:media}").unwrap(), "hunter2");
+    }
+
+    #[test]
+    fn reference_cycles_stop_at_the_depth_limit() {
+        let store = store(vec![entry("media", "{REF:P@T:media}")]);
+        let err = format!("{:#}", resolve(&store, "{REF:P@T:media}").unwrap_err());
+        assert!(err.contains("nested more than 10 levels deep"), "{}", err);
+    }
+
+    #[test]
+    fn unterminated_reference_is_kept_verbatim() {
+        let store = store(vec![entry("media", "hunter2")]);
+        assert_eq!(
+            resolve(&store, "{REF:P@T:media}{REF:P@T:media").unwrap(),
+            "hunter2{REF:P@T:media"
+        );
+    }
+
+    #[test]
+    fn ambiguous_reference_is_an_error() {
+        let store = store(vec![entry("media", "one"), entry("media-old", "two")]);
+        let err = format!("{:#}", resolve(&store, "{REF:P@T:media}").unwrap_err());
+        assert!(err.contains("2 KeePass entries match"), "{}", err);
+        assert!(err.contains("{REF:P@T:media}"), "{}", err);
+    }
+
+    #[test]
+    fn resolves_by_uuid() {
+        let target = entry("media", "one");
+        let uuid = target.uuid;
+        let store = store(vec![target, entry("media-old", "two")]);
+        let simple = uuid.simple().to_string();
+        assert_eq!(
+            resolve(&store, &format!("{{REF:P@I:{}}}", simple)).unwrap(),
+            "one"
+        );
+        assert_eq!(
+            resolve(&store, &format!("{{REF:P@I:{}}}", uuid.hyphenated())).unwrap(),
+            "one"
+        );
+        assert_eq!(resolve(&store, "{REF:I@T:media-old}").unwrap().len(), 32);
+        assert_eq!(
+            resolve(&store, &format!("{{REF:I@I:{}}}", simple)).unwrap(),
+            simple.to_ascii_uppercase()
+        );
+    }
+}
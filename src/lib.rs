@@ -0,0 +1,17 @@
+//! Unlocks and locks encrypted TrueNAS datasets with secrets from KeePass.
+//!
+//! [`Config::load`](config::Config::load) reads a config file, and
+//! [`Client`] unlocks or locks its volumes the way the `naslock` command
+//! does. The lower-level pieces, such as the TrueNAS API in [`truenas`] and
+//! the KeePass lookups in [`keepass_store`], are public as well.
+
+pub mod client;
+pub mod config;
+pub mod error;
+pub mod kdf;
+pub mod keepass_store;
+pub mod secrets;
+pub mod truenas;
+pub mod websocket;
+
+pub use client::{Client, Locked, Unlocked};
@@ -1,11 +1,24 @@
-mod config;
-mod keepass_store;
-mod truenas;
+mod audit;
+mod metrics;
+mod script;
+mod since_boot;
+mod watchdog;
 
 use anyhow::{Context, Result, bail};
-use clap::{Parser, Subcommand};
-use keepass_store::{ensure_non_empty, require_entry, required_field};
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::{ArgValueCandidates, CompletionCandidate};
+use naslock::client::{child_unlock_secrets, unlock_options, unlock_secret};
+use naslock::error::{self, ErrorKind, ResultExt};
+use naslock::keepass_store::{self, SearchRoot, ensure_non_empty, require_entry};
+use naslock::secrets::{self, StoredAuth, Stores};
+use naslock::{client, config, truenas};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::io::{IsTerminal, Write};
 use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
 use zeroize::Zeroizing;
 
 #[derive(Parser)]
@@ -17,46 +30,816 @@ use zeroize::Zeroizing;
 struct Cli {
     #[arg(short, long, env = "NASLOCK_CONFIG")]
     config: Option<PathBuf>,
+    /// Sleep a random duration up to this many seconds before contacting the NAS
+    #[arg(long, value_name = "SECONDS", global = true)]
+    splay: Option<u64>,
+    /// Log what naslock is doing to stderr; repeat for more detail (-vv debug, -vvv trace)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Print results and errors as JSON objects
+    #[arg(long, global = true)]
+    json: bool,
+    /// Maximum number of HTTP redirects to follow (overrides max_redirects)
+    #[arg(long, value_name = "N", global = true)]
+    max_redirects: Option<usize>,
+    /// Write Prometheus metrics for this run to a textfile-collector file
+    #[arg(long, value_name = "PATH", global = true)]
+    prom_textfile: Option<PathBuf>,
+    /// Skip volumes that naslock already unlocked since the last boot
+    #[arg(long, global = true)]
+    since_boot: bool,
+    /// With --since-boot, unlock again even if already done this boot
+    #[arg(long, global = true, requires = "since_boot")]
+    force_rerun: bool,
+    /// Abort if the whole run takes longer than this many seconds (prompts excluded)
+    #[arg(long, value_name = "SECONDS", global = true)]
+    timeout_total: Option<u64>,
+    /// Abort if unlocking or locking takes longer than this many seconds, from the first request to the NAS (overrides operation_timeout_secs)
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        global = true,
+        conflicts_with = "timeout_total"
+    )]
+    timeout: Option<u64>,
+    /// Before locking, require the dataset name to be typed back
+    #[arg(long, global = true)]
+    confirm_name: bool,
+    /// Skip confirmation prompts, including the one before each lock
+    #[arg(short, long, global = true)]
+    yes: bool,
+    /// Resolve secrets and build each request, but print it instead of sending it
+    #[arg(long, global = true)]
+    dry_run: bool,
+    /// Take the NAS, dataset and secrets from flags and the environment, without a config file or KeePass
+    #[arg(long, global = true, requires_all = ["host", "dataset"])]
+    no_config: bool,
+    /// Send requests to this URL instead of the configured host of the volumes' NAS
+    #[arg(long, value_name = "URL", global = true, conflicts_with = "no_config")]
+    nas_host: Option<String>,
+    /// Open this KeePass database instead of keepass.path
+    #[arg(long, value_name = "PATH", global = true, conflicts_with = "no_config")]
+    keepass: Option<PathBuf>,
+    /// Use this key file for the KeePass database instead of the configured one
+    #[arg(long, value_name = "PATH", global = true, conflicts_with = "no_config")]
+    key_file: Option<PathBuf>,
+    /// With --no-config: URL of the NAS
+    #[arg(long, value_name = "URL", global = true, requires = "no_config")]
+    host: Option<String>,
+    /// With --no-config: dataset to unlock or lock; with unlock --nas: dataset to unlock without a volume
+    #[arg(long, value_name = "DATASET", global = true)]
+    dataset: Option<String>,
+    /// With --no-config: auth method (basic, api_key or api_key_basic; default api_key)
+    #[arg(long, value_name = "METHOD", global = true, requires = "no_config")]
+    auth: Option<String>,
+    /// With --no-config: username for basic auth
+    #[arg(long, value_name = "NAME", global = true, requires = "no_config")]
+    username: Option<String>,
+    /// With --no-config: environment variable holding the basic-auth password
+    #[arg(long, value_name = "VAR", global = true, requires = "no_config")]
+    password_env: Option<String>,
+    /// With --no-config: environment variable holding the API key
+    #[arg(long, value_name = "VAR", global = true, requires = "no_config")]
+    api_key_env: Option<String>,
+    /// With --no-config: environment variable holding the dataset passphrase or key
+    #[arg(long, value_name = "VAR", global = true, requires = "no_config")]
+    unlock_secret_env: Option<String>,
+    /// With --no-config: passphrase or key (default passphrase)
+    #[arg(long, value_name = "MODE", global = true, requires = "no_config")]
+    unlock_mode: Option<String>,
     #[command(subcommand)]
     command: Command,
 }
 
 #[derive(Subcommand)]
 enum Command {
-    Unlock { volume: String },
-    Lock { volume: String },
-}
-
-enum StoredAuth {
-    Basic {
-        username: Zeroizing<String>,
-        password: Zeroizing<String>,
+    Unlock {
+        /// Volumes to unlock; on a terminal, leave out to pick one from a list
+        #[arg(add = ArgValueCandidates::new(volume_names))]
+        volumes: Vec<String>,
+        /// Unlock every configured volume, after showing the plan
+        #[arg(long, conflicts_with = "volumes")]
+        all: bool,
+        /// With --all, only the volumes on this NAS; with --dataset, the NAS the dataset is on
+        #[arg(
+            long,
+            value_name = "NAME",
+            conflicts_with = "volumes",
+            add = ArgValueCandidates::new(nas_names)
+        )]
+        nas: Option<String>,
+        /// With --dataset: KeePass entry with the dataset's passphrase or key
+        #[arg(
+            long,
+            value_name = "ENTRY",
+            conflicts_with_all = ["volumes", "all", "no_config"]
+        )]
+        unlock_entry: Option<String>,
+        /// With --dataset: field of --unlock-entry with the secret (default Password)
+        #[arg(long, value_name = "FIELD", requires = "unlock_entry")]
+        unlock_field: Option<String>,
+        /// With --all, unlock up to this many volumes at once
+        #[arg(long, value_name = "N", requires = "all", value_parser = parse_parallel)]
+        parallel: Option<usize>,
+        /// Continue with the remaining volumes when one fails (always on for wildcard names)
+        #[arg(long)]
+        keep_going: bool,
+        /// Fail when a dataset was already unlocked instead of counting it as done
+        #[arg(long)]
+        strict: bool,
+    },
+    Lock {
+        #[arg(required_unless_present = "all", add = ArgValueCandidates::new(volume_names))]
+        volumes: Vec<String>,
+        /// Lock every configured volume, after showing the plan
+        #[arg(long, conflicts_with = "volumes")]
+        all: bool,
+        /// With --all, only the volumes on this NAS
+        #[arg(
+            long,
+            value_name = "NAME",
+            requires = "all",
+            conflicts_with = "volumes",
+            add = ArgValueCandidates::new(nas_names)
+        )]
+        nas: Option<String>,
+        /// Continue with the remaining volumes when one fails (always on for wildcard names)
+        #[arg(long)]
+        keep_going: bool,
+    },
+    /// Unlock every configured volume, continuing past failures (unlock --all --keep-going)
+    UnlockAll {
+        /// Only the volumes on this NAS
+        #[arg(long, value_name = "NAME", add = ArgValueCandidates::new(nas_names))]
+        nas: Option<String>,
+        /// Unlock up to this many volumes at once
+        #[arg(long, value_name = "N", value_parser = parse_parallel)]
+        parallel: Option<usize>,
+        /// Fail when a dataset was already unlocked instead of counting it as done
+        #[arg(long)]
+        strict: bool,
+    },
+    /// Lock every configured volume, continuing past failures (lock --all --keep-going)
+    LockAll {
+        /// Only the volumes on this NAS
+        #[arg(long, value_name = "NAME", add = ArgValueCandidates::new(nas_names))]
+        nas: Option<String>,
+    },
+    /// Unlock a locked dataset with its stored secret, then lock it again
+    VerifySecret {
+        #[arg(add = ArgValueCandidates::new(volume_names))]
+        volume: String,
+    },
+    /// Unlock a volume, then change its dataset's passphrase or key to one from another entry
+    Rotate {
+        #[arg(add = ArgValueCandidates::new(volume_names))]
+        volume: String,
+        /// KeePass entry holding the new passphrase or key
+        #[arg(long, value_name = "ENTRY")]
+        new_entry: String,
+        /// Field of --new-entry with the secret (default: the volume's unlock_field)
+        #[arg(long, value_name = "FIELD")]
+        new_field: Option<String>,
     },
-    ApiKey {
-        key: Zeroizing<String>,
+    /// Print the request that would be sent, with secrets redacted, without sending it
+    RenderRequest {
+        #[arg(add = ArgValueCandidates::new(volume_names))]
+        volume: String,
+        /// Render the lock request instead of the unlock request
+        #[arg(long)]
+        lock: bool,
     },
+    /// Show whether volumes are locked
+    Status {
+        #[arg(required_unless_present = "all", add = ArgValueCandidates::new(volume_names))]
+        volume: Option<String>,
+        /// Query every configured volume, one thread per NAS
+        #[arg(long, conflicts_with = "volume")]
+        all: bool,
+    },
+    /// Validate the config and report every problem found
+    Check,
+    /// List the configured volumes without opening KeePass or contacting a NAS
+    List {
+        /// Only the volumes on this NAS
+        #[arg(long, value_name = "NAME", add = ArgValueCandidates::new(nas_names))]
+        nas: Option<String>,
+    },
+    /// List the field names of a KeePass entry; values are never shown
+    Fields {
+        /// The entry, as in `unlock_entry`
+        entry: String,
+        /// Resolve the entry as this NAS's entries are: in its database and base group
+        #[arg(long, value_name = "NAME", add = ArgValueCandidates::new(nas_names))]
+        nas: Option<String>,
+    },
+    /// Print a standalone script that performs the unlock via the TrueNAS API
+    GenScript {
+        #[arg(add = ArgValueCandidates::new(volume_names))]
+        volume: String,
+        #[arg(long, value_enum, default_value = "sh")]
+        lang: script::ScriptLanguage,
+    },
+    /// Print a completion script for subcommands and flags
+    Completions { shell: clap_complete::Shell },
+    /// Print the config path naslock would read, without loading it
+    ConfigPath,
 }
 
-impl StoredAuth {
-    fn as_auth(&self) -> truenas::Auth<'_> {
-        match self {
-            StoredAuth::Basic { username, password } => truenas::Auth::Basic {
-                username: username.as_str(),
-                password: password.as_str(),
-            },
-            StoredAuth::ApiKey { key } => truenas::Auth::ApiKey { key: key.as_str() },
+/// Volume names for dynamic shell completion, with the dataset as help text.
+/// The config is found as without `--config`, honouring `NASLOCK_CONFIG`; any
+/// error yields no candidates.
+fn volume_names() -> Vec<CompletionCandidate> {
+    let Some(cfg) = completion_config() else {
+        return Vec::new();
+    };
+    let mut names: Vec<_> = cfg
+        .volume
+        .iter()
+        .map(|(name, volume)| {
+            CompletionCandidate::new(name).help(Some(volume.dataset.clone().into()))
+        })
+        .collect();
+    names.sort_by(|a, b| a.get_value().cmp(b.get_value()));
+    names
+}
+
+/// NAS names for dynamic shell completion, with the host as help text.
+fn nas_names() -> Vec<CompletionCandidate> {
+    let Some(cfg) = completion_config() else {
+        return Vec::new();
+    };
+    let mut names: Vec<_> = cfg
+        .nas
+        .iter()
+        .map(|(name, nas)| CompletionCandidate::new(name).help(Some(nas.host.clone().into())))
+        .collect();
+    names.sort_by(|a, b| a.get_value().cmp(b.get_value()));
+    names
+}
+
+fn completion_config() -> Option<config::Config> {
+    let path = resolve_config_path(std::env::var_os("NASLOCK_CONFIG").map(PathBuf::from)).ok()?;
+    config::Config::load(&path).ok()
+}
+
+struct RunOptions {
+    splay: Option<Duration>,
+    json: bool,
+    max_redirects: Option<usize>,
+    since_boot: bool,
+    force_rerun: bool,
+    confirm_name: bool,
+    yes: bool,
+    dry_run: bool,
+    job_poll_interval: Duration,
+    job_timeout: Option<Duration>,
+    /// Seconds from the first request to the NAS until the run is aborted.
+    timeout: Option<u64>,
+    /// Volumes unlocked at once by `unlock --all`; 1 unlocks them in turn.
+    parallel: usize,
+    /// `unlock --strict`: an already unlocked dataset fails the volume.
+    strict_unlock: bool,
+    env_secrets: Option<EnvSecrets>,
+    /// Hosts already warned about for `skip_tls_verify`.
+    insecure_warned: Mutex<HashSet<String>>,
+}
+
+impl RunOptions {
+    fn wait_options(&self) -> truenas::WaitOptions {
+        truenas::WaitOptions {
+            // Progress from several jobs at once would overwrite each other.
+            show_progress: !self.json && self.parallel == 1,
+            poll_interval: self.job_poll_interval,
+            timeout: self.job_timeout,
+        }
+    }
+
+    fn report(&self, action: &str, volume: &str, outcome: &Outcome) {
+        if self.json {
+            let mut value = json!({
+                "status": "ok",
+                "action": action,
+                "volume": volume,
+                "message": outcome.message,
+            });
+            if let Some(job) = &outcome.job {
+                value["job"] = json!({
+                    "id": job.id,
+                    "time_started": job.time_started.map(format_time),
+                    "time_finished": job.time_finished.map(format_time),
+                    "duration_seconds": job_duration(job).map(|d| d.as_secs_f64()),
+                    "waited_seconds": job.waited.as_secs_f64(),
+                });
+            }
+            println!("{}", value);
+        } else {
+            println!("{}", outcome.message);
+            if let Some(job) = &outcome.job {
+                print_job_timing(job);
+            }
         }
     }
 }
 
-fn main() -> Result<()> {
+/// The result of an operation: a one-line summary plus the job it waited on,
+/// if the NAS ran the operation as a job.
+struct Outcome {
+    message: String,
+    job: Option<truenas::JobInfo>,
+}
+
+impl From<String> for Outcome {
+    fn from(message: String) -> Self {
+        Outcome { message, job: None }
+    }
+}
+
+fn format_time(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+fn job_duration(job: &truenas::JobInfo) -> Option<Duration> {
+    job.time_finished?.duration_since(job.time_started?).ok()
+}
+
+fn print_job_timing(job: &truenas::JobInfo) {
+    let mut parts = Vec::new();
+    if let Some(started) = job.time_started {
+        parts.push(format!("started {}", format_time(started)));
+    }
+    if let Some(finished) = job.time_finished {
+        parts.push(format!("finished {}", format_time(finished)));
+    }
+    if let Some(duration) = job_duration(job) {
+        parts.push(format!("took {:.1}s", duration.as_secs_f64()));
+    }
+    parts.push(format!("waited {:.1}s", job.waited.as_secs_f64()));
+    println!("job {}: {}", job.id, parts.join(", "));
+}
+
+fn main() -> ExitCode {
+    clap_complete::CompleteEnv::with_factory(Cli::command).complete();
     let cli = Cli::parse();
-    let config_path = resolve_config_path(cli.config)?;
-    let cfg = config::Config::load(&config_path)?;
+    init_logging(cli.verbose);
+    let json = cli.json;
+    if let Some(secs) = cli.timeout_total {
+        watchdog::start(Duration::from_secs(secs), move || {
+            print_error(
+                &error::tag(
+                    ErrorKind::Timeout,
+                    anyhow::anyhow!("run exceeded --timeout-total of {} seconds", secs),
+                ),
+                json,
+            );
+            finish_audit();
+            ErrorKind::Timeout.exit_code()
+        });
+    }
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            print_error(&err, json);
+            ExitCode::from(error::classify(&err).exit_code())
+        }
+    }
+}
+
+/// Sends naslock's own log events to stderr: only warnings, as
+/// `warning: ...` lines, by default, then info with `-v`, debug with `-vv`
+/// and trace with `-vvv`. Secret values are never logged.
+fn init_logging(verbosity: u8) {
+    use tracing_subscriber::filter::{LevelFilter, Targets};
+    use tracing_subscriber::prelude::*;
+
+    let level = match verbosity {
+        0 => {
+            tracing_subscriber::registry()
+                .with(
+                    tracing_subscriber::fmt::layer()
+                        .with_writer(std::io::stderr)
+                        .event_format(WarningFormat),
+                )
+                .with(Targets::new().with_target("naslock", LevelFilter::WARN))
+                .init();
+            return;
+        }
+        1 => LevelFilter::INFO,
+        2 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    };
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(std::io::stderr)
+                .with_target(false),
+        )
+        .with(Targets::new().with_target("naslock", level))
+        .init();
+}
+
+/// Prints a log event as `warning: <message>`, the way naslock's own
+/// warnings look when no `-v` is given.
+struct WarningFormat;
+
+impl<S, N> tracing_subscriber::fmt::FormatEvent<S, N> for WarningFormat
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    N: for<'a> tracing_subscriber::fmt::FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &tracing_subscriber::fmt::FmtContext<'_, S, N>,
+        mut writer: tracing_subscriber::fmt::format::Writer<'_>,
+        event: &tracing::Event<'_>,
+    ) -> std::fmt::Result {
+        use tracing_subscriber::fmt::FormatFields as _;
+
+        write!(writer, "warning: ")?;
+        ctx.format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+fn print_error(err: &anyhow::Error, json: bool) {
+    if json {
+        eprintln!("{}", error::to_json(err));
+    } else {
+        eprintln!("Error: {:?}", err);
+    }
+}
+
+fn run(cli: Cli) -> Result<()> {
+    if let Command::Completions { shell } = cli.command {
+        clap_complete::generate(
+            shell,
+            &mut Cli::command(),
+            "naslock",
+            &mut std::io::stdout(),
+        );
+        return Ok(());
+    }
+    if let Command::ConfigPath = cli.command {
+        return print_config_path(cli.config, cli.json);
+    }
+    check_unlock_flags(&cli.command, cli.dataset.is_some() && !cli.no_config)
+        .kind(ErrorKind::Config)?;
+    let mut cfg = if cli.no_config {
+        stateless_config(&cli).kind(ErrorKind::Config)?
+    } else {
+        let config_path = resolve_config_path(cli.config).kind(ErrorKind::Config)?;
+        tracing::info!(path = %config_path.display(), "loading config");
+        let mut cfg = config::Config::load(&config_path).kind(ErrorKind::Config)?;
+        if let (
+            Some(dataset),
+            Command::Unlock {
+                nas: Some(nas),
+                unlock_entry: Some(entry),
+                unlock_field,
+                ..
+            },
+        ) = (&cli.dataset, &cli.command)
+        {
+            cfg.add_dataset_volume(nas, dataset, entry, unlock_field.as_deref())
+                .kind(ErrorKind::Config)?;
+        }
+        cfg.override_keepass(cli.keepass.as_deref(), cli.key_file.as_deref());
+        cfg.validate().kind(ErrorKind::Config)?;
+        if !cfg.strict_config {
+            for key in &cfg.unknown_keys {
+                eprintln!("warning: unknown config key '{}' is ignored", key);
+            }
+        }
+        cfg
+    };
+    let env_secrets = cli.no_config.then_some(EnvSecrets {
+        username: cli.username,
+        password_env: cli.password_env,
+        api_key_env: cli.api_key_env,
+        unlock_secret_env: cli.unlock_secret_env,
+    });
+    if let (Some(_), Some(secs)) = (cli.timeout_total, cfg.operation_timeout_secs) {
+        eprintln!(
+            "warning: operation_timeout_secs = {} is ignored because --timeout-total is set",
+            secs
+        );
+    }
+    let opts = RunOptions {
+        splay: cli.splay.map(Duration::from_secs),
+        json: cli.json,
+        max_redirects: cli.max_redirects,
+        since_boot: cli.since_boot,
+        force_rerun: cli.force_rerun,
+        confirm_name: cli.confirm_name && !cli.yes,
+        yes: cli.yes,
+        dry_run: cli.dry_run,
+        job_poll_interval: Duration::from_secs(cfg.job_poll_interval_secs),
+        job_timeout: cfg.job_timeout_secs.map(Duration::from_secs),
+        // Only one watchdog can run; --timeout-total takes precedence.
+        timeout: cli
+            .timeout
+            .or(cfg.operation_timeout_secs)
+            .filter(|_| cli.timeout_total.is_none()),
+        parallel: match &cli.command {
+            Command::Unlock { parallel, .. } | Command::UnlockAll { parallel, .. } => {
+                parallel.unwrap_or(1)
+            }
+            _ => 1,
+        },
+        strict_unlock: matches!(
+            cli.command,
+            Command::Unlock { strict: true, .. } | Command::UnlockAll { strict: true, .. }
+        ),
+        env_secrets,
+        insecure_warned: Mutex::default(),
+    };
+    let prom_textfile = cli
+        .prom_textfile
+        .map(|path| config::expand_path(&path, None));
+
+    let started = Instant::now();
+    let mut outcomes = Vec::new();
+    let command = match cli.command {
+        Command::UnlockAll {
+            nas,
+            parallel,
+            strict,
+        } => Command::Unlock {
+            volumes: Vec::new(),
+            all: true,
+            nas,
+            unlock_entry: None,
+            unlock_field: None,
+            parallel,
+            keep_going: true,
+            strict,
+        },
+        Command::LockAll { nas } => Command::Lock {
+            volumes: Vec::new(),
+            all: true,
+            nas,
+            keep_going: true,
+        },
+        command => command,
+    };
+    if let Some(host) = &cli.nas_host {
+        override_nas_host(&mut cfg, &command, host).kind(ErrorKind::Config)?;
+    }
+    let (action, result) = match command {
+        Command::Unlock {
+            volumes,
+            all,
+            nas,
+            unlock_entry,
+            keep_going,
+            ..
+        } => {
+            let (volumes, matched) = if all {
+                (all_volumes(&cfg, nas.as_deref())?, false)
+            } else if volumes.is_empty() && unlock_entry.is_some() {
+                // `unlock --dataset`: the volume is named after the dataset.
+                (cli.dataset.into_iter().collect(), false)
+            } else if volumes.is_empty() {
+                (vec![pick_volume(&cfg, &opts)?], false)
+            } else {
+                expand_volume_patterns(&cfg, volumes)?
+            };
+            // A pattern picks volumes like --all does, so it carries on past failures too.
+            let keep_going = keep_going || matched;
+            begin_audit(&cfg, &opts, "unlock", &volumes);
+            let result = plan_and_confirm(&cfg, &opts, "unlock", &volumes, all || matched)
+                .and_then(|secrets| {
+                    unlock_volumes(&cfg, &opts, &volumes, keep_going, secrets, &mut outcomes)
+                });
+            ("unlock", result)
+        }
+        Command::Lock {
+            volumes,
+            all,
+            nas,
+            keep_going,
+        } => {
+            let (volumes, matched) = if all {
+                (all_volumes(&cfg, nas.as_deref())?, false)
+            } else {
+                expand_volume_patterns(&cfg, volumes)?
+            };
+            let keep_going = keep_going || matched;
+            begin_audit(&cfg, &opts, "lock", &volumes);
+            let planned = all || matched;
+            let result =
+                plan_and_confirm(&cfg, &opts, "lock", &volumes, planned).and_then(|secrets| {
+                    lock_volumes(
+                        &cfg,
+                        &opts,
+                        &volumes,
+                        keep_going,
+                        !planned,
+                        secrets,
+                        &mut outcomes,
+                    )
+                });
+            ("lock", result)
+        }
+        Command::UnlockAll { .. } => unreachable!("rewritten to unlock --all above"),
+        Command::LockAll { .. } => unreachable!("rewritten to lock --all above"),
+        Command::VerifySecret { volume } => {
+            let result = verify_secret(&cfg, &opts, &volume);
+            outcomes.push((volume, result.is_ok()));
+            ("verify-secret", result)
+        }
+        Command::Rotate {
+            volume,
+            new_entry,
+            new_field,
+        } => {
+            begin_audit(&cfg, &opts, "rotate", std::slice::from_ref(&volume));
+            let result = rotate(&cfg, &opts, &volume, &new_entry, new_field.as_deref());
+            audit::settle(&volume, result.is_ok());
+            outcomes.push((volume, result.is_ok()));
+            ("rotate", result)
+        }
+        Command::RenderRequest { volume, lock } => {
+            return render_request(&cfg, &opts, &volume, lock);
+        }
+        Command::GenScript { volume, lang } => {
+            return gen_script(&cfg, &volume, lang);
+        }
+        Command::Status { volume, all } => {
+            return status(&cfg, &opts, volume.as_deref(), all);
+        }
+        Command::Check => {
+            // The config was validated when it was loaded.
+            let message = format!(
+                "config OK: {} volume(s) on {} NAS",
+                cfg.volume.len(),
+                cfg.nas.len()
+            );
+            if opts.json {
+                println!("{}", json!({ "status": "ok", "message": message }));
+            } else {
+                println!("{}", message);
+            }
+            return Ok(());
+        }
+        Command::List { nas } => {
+            return list(&cfg, &opts, nas.as_deref());
+        }
+        Command::Fields { entry, nas } => {
+            return list_fields(&cfg, &opts, &entry, nas.as_deref());
+        }
+        Command::Completions { .. } | Command::ConfigPath => {
+            unreachable!("handled before loading the config")
+        }
+    };
+
+    if let Some(path) = prom_textfile.filter(|_| !opts.dry_run) {
+        let duration = started.elapsed();
+        let finished_at = SystemTime::now();
+        let samples: Vec<metrics::Sample<'_>> = outcomes
+            .iter()
+            .map(|(volume, success)| metrics::Sample {
+                action,
+                volume,
+                nas: cfg.volume.get(volume).map(|v| v.nas.as_str()).unwrap_or(""),
+                success: *success,
+                duration,
+                finished_at,
+            })
+            .collect();
+        if let Err(err) = metrics::write_textfile(&path, &samples) {
+            eprintln!("warning: {:#}", err);
+        }
+    }
+
+    finish_audit();
+    result
+}
+
+/// Starts collecting the audit records of `action` on `volumes` when
+/// `audit_log` is set and this is not a dry run. They are written by
+/// [`finish_audit`] at the end of the run, or by the watchdog if it ends the
+/// run first.
+fn begin_audit(cfg: &config::Config, opts: &RunOptions, action: &'static str, volumes: &[String]) {
+    let Some(path) = cfg.audit_log.as_ref().filter(|_| !opts.dry_run) else {
+        return;
+    };
+    let volumes = volumes
+        .iter()
+        .map(|volume| {
+            let nas = cfg.volume.get(volume).map(|v| v.nas.clone());
+            (volume.clone(), nas.unwrap_or_default())
+        })
+        .collect();
+    audit::begin(path, cfg.audit_hash_chain, action, volumes);
+}
+
+/// Writes the audit records collected since [`begin_audit`], if any.
+fn finish_audit() {
+    if let Err(err) = audit::finish() {
+        eprintln!("warning: {:#}", err);
+    }
+}
+
+/// Builds the config for `--no-config`: one NAS named `cli` from the flags,
+/// and a volume for each name on the command line pointing at `--dataset`.
+/// Environment overrides apply as usual.
+fn stateless_config(cli: &Cli) -> Result<config::Config> {
+    let names: Vec<&String> = match &cli.command {
+        Command::Unlock { volumes, .. } | Command::Lock { volumes, .. } => volumes.iter().collect(),
+        Command::VerifySecret { volume }
+        | Command::Rotate { volume, .. }
+        | Command::RenderRequest { volume, .. }
+        | Command::GenScript { volume, .. } => vec![volume],
+        Command::Status { volume, .. } => volume.iter().collect(),
+        Command::UnlockAll { .. }
+        | Command::LockAll { .. }
+        | Command::Check
+        | Command::List { .. }
+        | Command::Fields { .. }
+        | Command::Completions { .. }
+        | Command::ConfigPath => Vec::new(),
+    };
+
+    let mut keepass = toml::Table::new();
+    keepass.insert("path".into(), "".into());
+
+    let mut nas = toml::Table::new();
+    nas.insert("host".into(), cli.host.clone().unwrap_or_default().into());
+    nas.insert(
+        "auth_method".into(),
+        cli.auth.as_deref().unwrap_or("api_key").into(),
+    );
+
+    let mut volume = toml::Table::new();
+    volume.insert("nas".into(), "cli".into());
+    volume.insert(
+        "dataset".into(),
+        cli.dataset.clone().unwrap_or_default().into(),
+    );
+    volume.insert("unlock_entry".into(), "".into());
+    if let Some(mode) = &cli.unlock_mode {
+        volume.insert("unlock_mode".into(), mode.as_str().into());
+    }
+
+    let mut raw = toml::Table::new();
+    raw.insert("keepass".into(), keepass.into());
+    raw.insert(
+        "nas".into(),
+        toml::Table::from_iter([("cli".to_string(), nas.into())]).into(),
+    );
+    raw.insert(
+        "volume".into(),
+        toml::Table::from_iter(
+            names
+                .into_iter()
+                .map(|name| (name.clone(), volume.clone().into())),
+        )
+        .into(),
+    );
+    config::Config::from_table(raw)
+}
+
+/// Checks that `unlock` names its volumes in exactly one way: by name,
+/// with --all, or with --dataset, --nas and --unlock-entry, and that its
+/// other flags fit the way that was chosen. `dataset` is whether --dataset
+/// was given outside --no-config.
+fn check_unlock_flags(command: &Command, dataset: bool) -> Result<()> {
+    let Command::Unlock {
+        volumes,
+        all,
+        nas,
+        unlock_entry,
+        unlock_field,
+        parallel,
+        ..
+    } = command
+    else {
+        if dataset {
+            bail!("--dataset needs --no-config, or unlock with --nas and --unlock-entry");
+        }
+        return Ok(());
+    };
+    match (dataset, *all) {
+        (true, _) if !volumes.is_empty() => bail!("--dataset cannot be combined with volume names"),
+        (true, true) => bail!("--dataset cannot be combined with --all"),
+        (true, false) if nas.is_none() || unlock_entry.is_none() => {
+            bail!("unlock --dataset needs --nas and --unlock-entry")
+        }
+        (false, false) if nas.is_some() => bail!("--nas needs --all or --dataset"),
+        (false, _) if unlock_entry.is_some() || unlock_field.is_some() => {
+            bail!("--unlock-entry and --unlock-field need --dataset")
+        }
+        (_, false) if parallel.is_some() => bail!("--parallel needs --all"),
+        _ => Ok(()),
+    }
+}
 
-    match cli.command {
-        Command::Unlock { volume } => unlock_volume(&cfg, &volume),
-        Command::Lock { volume } => lock_volume(&cfg, &volume),
+fn parse_parallel(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(n) => Ok(n),
+        Err(err) => Err(format!("{}", err)),
     }
 }
 
@@ -73,151 +856,1851 @@ fn resolve_config_path(cli_path: Option<PathBuf>) -> Result<PathBuf> {
     config::default_config_path()
 }
 
-fn unlock_volume(cfg: &config::Config, volume_name: &str) -> Result<()> {
-    let volume = cfg
+/// Prints the path [`resolve_config_path`] picks and whether anything is
+/// there.
+fn print_config_path(cli_path: Option<PathBuf>, json: bool) -> Result<()> {
+    let path = resolve_config_path(cli_path).kind(ErrorKind::Config)?;
+    let kind = if path.is_dir() {
+        "directory"
+    } else if path.is_file() {
+        "file"
+    } else {
+        "missing"
+    };
+    if json {
+        println!(
+            "{}",
+            json!({ "path": path.display().to_string(), "exists": kind != "missing", "kind": kind })
+        );
+    } else if kind == "missing" {
+        println!("{} (does not exist)", path.display());
+    } else {
+        println!("{} ({})", path.display(), kind);
+    }
+    Ok(())
+}
+
+/// Replaces each name with a `*` or `?` wildcard by the configured volumes
+/// it matches, in name order, and says whether there was such a pattern.
+/// A pattern that matches nothing is an error.
+fn expand_volume_patterns(cfg: &config::Config, names: Vec<String>) -> Result<(Vec<String>, bool)> {
+    let mut configured: Vec<&String> = cfg.volume.keys().collect();
+    configured.sort_unstable();
+    let mut expanded = Vec::new();
+    let mut matched = false;
+    for name in names {
+        if !name.contains(['*', '?']) {
+            if !expanded.contains(&name) {
+                expanded.push(name);
+            }
+            continue;
+        }
+        matched = true;
+        let matches: Vec<&String> = configured
+            .iter()
+            .copied()
+            .filter(|volume| glob_match(&name, volume))
+            .collect();
+        if matches.is_empty() {
+            return Err(error::tag(
+                ErrorKind::Config,
+                anyhow::anyhow!("no volume matches '{}'", name),
+            ));
+        }
+        for volume in matches {
+            if !expanded.contains(volume) {
+                expanded.push(volume.clone());
+            }
+        }
+    }
+    Ok((expanded, matched))
+}
+
+/// Matches `text` against a shell-style `pattern` where `*` stands for any
+/// run of characters and `?` for exactly one.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // The last `*` seen and the text position it currently stands up to.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Every configured volume, or only those on `nas`, sorted by name.
+fn all_volumes(cfg: &config::Config, nas: Option<&str>) -> Result<Vec<String>> {
+    if let Some(nas) = nas {
+        if !cfg.nas.contains_key(nas) {
+            return Err(error::tag(
+                ErrorKind::Config,
+                anyhow::anyhow!("unknown NAS '{}'", nas),
+            ));
+        }
+    }
+    let mut names: Vec<String> = cfg
         .volume
-        .get(volume_name)
-        .with_context(|| format!("unknown volume '{}'", volume_name))?;
-    let nas = cfg
-        .nas
-        .get(&volume.nas)
-        .with_context(|| format!("unknown NAS '{}'", volume.nas))?;
+        .iter()
+        .filter(|(_, volume)| nas.is_none_or(|nas| volume.nas == nas))
+        .map(|(name, _)| name.clone())
+        .collect();
+    names.sort_unstable();
+    Ok(names)
+}
 
-    let master_password = Zeroizing::new(rpassword::prompt_password("KeePass password: ")?);
+/// Runs `each` for every volume. An error in the outer `Result` aborts the
+/// batch; an error in the inner one is that volume's failure. Without
+/// `keep_going` the first failure aborts the run; with it, failures are
+/// reported as they happen and summarized at the end.
+fn run_batch(
+    action: &str,
+    opts: &RunOptions,
+    names: &[String],
+    keep_going: bool,
+    outcomes: &mut Vec<(String, bool)>,
+    mut each: impl FnMut(&str) -> Result<Result<()>>,
+) -> Result<()> {
+    let mut failed = Vec::new();
+    let mut kinds = Vec::new();
 
-    let store = keepass_store::KeePassStore::open(
-        &cfg.keepass.path,
-        cfg.keepass.key_file.as_deref(),
-        master_password.as_str(),
-    )?;
+    for name in names {
+        let result = each(name)?;
+        audit::settle(name, result.is_ok());
+        outcomes.push((name.clone(), result.is_ok()));
 
-    let stored_auth = load_auth(&store, nas)?;
-    let unlock_entry = require_entry(&store, &volume.unlock_entry)?;
+        let Err(err) = result else {
+            continue;
+        };
+        let err = if names.len() > 1 {
+            err.context(format!("failed to {} volume '{}'", action, name))
+        } else {
+            err
+        };
+        if !keep_going {
+            return Err(err);
+        }
+        print_error(&err, opts.json);
+        failed.push(name.as_str());
+        kinds.push(error::classify(&err));
+    }
 
-    let unlock_secret_value =
-        required_field(unlock_entry, &volume.unlock_field, &volume.unlock_entry)?;
-    ensure_non_empty(unlock_secret_value.as_str(), "unlock secret")?;
+    if !failed.is_empty() {
+        let summary = anyhow::anyhow!(
+            "{} of {} volumes failed to {}: {}",
+            failed.len(),
+            names.len(),
+            action,
+            failed.join(", ")
+        );
+        // Keep the exit status specific when every volume failed the same way.
+        return Err(match kinds.as_slice() {
+            [first, rest @ ..] if rest.iter().all(|kind| kind == first) => {
+                error::tag(*first, summary)
+            }
+            _ => summary,
+        });
+    }
+    Ok(())
+}
 
-    let unlock_secret = match volume.unlock_mode {
-        config::UnlockMode::Passphrase => {
-            truenas::UnlockSecret::Passphrase(unlock_secret_value.as_str())
+/// Unlocks each volume in turn. The KeePass databases that all the volumes
+/// need are opened together on first use, each only once however many NAS
+/// boxes read from it. With `--parallel`, the volumes are unlocked
+/// [several at once](unlock_in_parallel) instead.
+fn unlock_volumes<'a>(
+    cfg: &config::Config,
+    opts: &'a RunOptions,
+    names: &[String],
+    keep_going: bool,
+    mut secrets: Option<Secrets<'a>>,
+    outcomes: &mut Vec<(String, bool)>,
+) -> Result<()> {
+    if opts.parallel > 1 && names.len() > 1 {
+        return unlock_in_parallel(cfg, opts, names, secrets, outcomes);
+    }
+    let mut splayed = false;
+    run_batch("unlock", opts, names, keep_going, outcomes, |name| {
+        let plan = match prepare_unlock(cfg, opts, name) {
+            Ok(Some(plan)) => plan,
+            Ok(None) => return Ok(Ok(())),
+            Err(err) => return Ok(Err(err)),
+        };
+        let secrets = match &mut secrets {
+            Some(secrets) => secrets,
+            None => secrets.insert(open_secrets(opts, &secrets::databases(cfg, names, true))?),
+        };
+        if !splayed {
+            before_first_request(opts)?;
+            splayed = true;
         }
-        config::UnlockMode::Key => truenas::UnlockSecret::Key(unlock_secret_value.as_str()),
-    };
+        Ok(finish_unlock(cfg, opts, secrets, name, plan))
+    })
+}
 
-    let client = truenas::build_client(nas.skip_tls_verify)?;
-    let base_url = truenas::parse_base_url(&nas.host)?;
+/// Unlocks the volumes on `opts.parallel` worker threads, so that at most
+/// that many unlock requests and jobs are in flight at once. Every volume is
+/// attempted even when others fail; the failures are reported together
+/// once all workers are done.
+fn unlock_in_parallel<'a>(
+    cfg: &config::Config,
+    opts: &'a RunOptions,
+    names: &[String],
+    secrets: Option<Secrets<'a>>,
+    outcomes: &mut Vec<(String, bool)>,
+) -> Result<()> {
+    let mut results: HashMap<&str, Result<()>> = HashMap::new();
+    let mut plans = Vec::new();
+    for name in names {
+        match prepare_unlock(cfg, opts, name) {
+            Ok(Some(plan)) => plans.push((name.as_str(), plan)),
+            Ok(None) => {
+                results.insert(name, Ok(()));
+            }
+            Err(err) => {
+                results.insert(name, Err(err));
+            }
+        }
+    }
 
-    let options = truenas::UnlockOptions {
-        recursive: volume.recursive,
-        force: volume.force,
-        toggle_attachments: volume.toggle_attachments,
-    };
+    if !plans.is_empty() {
+        let secrets = match secrets {
+            Some(secrets) => secrets,
+            None => open_secrets(opts, &secrets::databases(cfg, names, true))?,
+        };
+        before_first_request(opts)?;
 
-    let result = truenas::unlock_dataset(
-        &client,
-        &base_url,
-        stored_auth.as_auth(),
-        &volume.dataset,
-        unlock_secret,
-        options,
-    )?;
+        let workers = opts.parallel.min(plans.len());
+        let queue = Mutex::new(plans.into_iter());
+        let finished = Mutex::new(&mut results);
+        std::thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| {
+                    loop {
+                        let Some((name, plan)) = queue.lock().expect("queue lock").next() else {
+                            break;
+                        };
+                        let result = finish_unlock(cfg, opts, &secrets, name, plan);
+                        audit::settle(name, result.is_ok());
+                        finished.lock().expect("results lock").insert(name, result);
+                    }
+                });
+            }
+        });
+    }
+
+    run_batch("unlock", opts, names, true, outcomes, |name| {
+        Ok(results.remove(name).unwrap_or(Ok(())))
+    })
+}
 
-    if !result.failed.is_empty() {
-        for (name, reason) in &result.failed {
-            eprintln!("failed to unlock {}: {}", name, reason);
+/// Locks each volume in turn, opening the databases with the NAS credentials
+/// on first use, once for the whole run. With `confirm_each`, every lock is
+/// confirmed first unless `--yes`, `--json` or `--dry-run` is given; `--all`
+/// has already asked once for the whole plan.
+fn lock_volumes<'a>(
+    cfg: &config::Config,
+    opts: &'a RunOptions,
+    names: &[String],
+    keep_going: bool,
+    confirm_each: bool,
+    mut secrets: Option<Secrets<'a>>,
+    outcomes: &mut Vec<(String, bool)>,
+) -> Result<()> {
+    let mut splayed = false;
+    run_batch("lock", opts, names, keep_going, outcomes, |name| {
+        let (volume, nas) = match cfg.resolve_volume(name) {
+            Ok(resolved) => resolved,
+            Err(err) => return Ok(Err(err)),
+        };
+        if opts.confirm_name {
+            confirm_dataset_name(volume, &nas.host)?;
+        } else if confirm_each && !opts.yes && !opts.json && !opts.dry_run {
+            confirm_lock(volume, &nas.host)?;
+        }
+        let secrets = match &mut secrets {
+            Some(secrets) => secrets,
+            None => secrets.insert(open_secrets(opts, &secrets::databases(cfg, names, false))?),
+        };
+        if !splayed {
+            before_first_request(opts)?;
+            splayed = true;
+        }
+        Ok(lock_volume(cfg, opts, secrets, name, volume, nas))
+    })
+}
+
+struct UnlockPlan<'a> {
+    volume: &'a config::VolumeConfig,
+    nas: &'a config::NasConfig,
+    boot_marker: Option<since_boot::BootMarker>,
+}
+
+/// Resolves the volume's configuration, or returns `None` if `--since-boot`
+/// says it is already done.
+fn prepare_unlock<'a>(
+    cfg: &'a config::Config,
+    opts: &RunOptions,
+    volume_name: &str,
+) -> Result<Option<UnlockPlan<'a>>> {
+    let (volume, nas) = cfg.resolve_volume(volume_name)?;
+
+    let boot_marker = if opts.since_boot {
+        Some(since_boot::BootMarker::for_volume(volume_name)?)
+    } else {
+        None
+    };
+    if let Some(marker) = &boot_marker {
+        if marker.exists() && !opts.force_rerun {
+            opts.report(
+                "unlock",
+                volume_name,
+                &format!(
+                    "volume '{}' was already unlocked since boot; skipping",
+                    volume_name
+                )
+                .into(),
+            );
+            return Ok(None);
         }
-        bail!("unlock failed");
     }
 
-    if let Some(job_id) = result.job_id {
-        let job = truenas::wait_for_job(&client, &base_url, stored_auth.as_auth(), job_id)?;
-        println!("unlock complete (job id: {})", job.id);
+    Ok(Some(UnlockPlan {
+        volume,
+        nas,
+        boot_marker,
+    }))
+}
+
+fn finish_unlock(
+    cfg: &config::Config,
+    opts: &RunOptions,
+    secrets: &Secrets<'_>,
+    volume_name: &str,
+    plan: UnlockPlan<'_>,
+) -> Result<()> {
+    let UnlockPlan {
+        volume,
+        nas,
+        boot_marker,
+    } = plan;
+
+    let stored_auth = secrets.auth(cfg, nas)?;
+    let unlock_secret_value = secrets.unlock_secret(cfg, nas, volume)?;
+    let child_secrets = secrets.child_secrets(cfg, nas, volume)?;
+
+    if opts.dry_run {
+        let (options, base_url) = rest_endpoint(nas, opts)?;
+        let rendered = truenas::render_unlock_request(
+            options,
+            &base_url,
+            stored_auth.as_auth(),
+            &volume.dataset,
+            unlock_secret(volume, unlock_secret_value.as_str()),
+            &child_unlock_secrets(volume, &child_secrets),
+            client::rendered_unlock_options(volume),
+        )?;
+        report_dry_run(opts, "unlock", volume_name, &rendered);
         return Ok(());
     }
 
-    if !result.unlocked.is_empty() {
-        println!("unlocked datasets: {}", result.unlocked.join(", "));
+    if let Some(command) = &volume.pre_unlock_command {
+        client::run_hook("pre_unlock_command", command, volume_name, volume)?;
+    }
+    let hint = auth_failure_hint(secrets, &volume.nas, nas);
+    let mut transport = open_transport(nas, opts, stored_auth.as_auth(), hint)?;
+    let mut outcome = perform_unlock(
+        transport.as_mut(),
+        opts,
+        volume,
+        unlock_secret_value.as_str(),
+        &child_secrets,
+    )?;
+    if cfg.verify_unlock(volume) {
+        client::verify_unlocked(transport.as_mut(), volume)?;
+        outcome.message.push_str("; verified unlocked");
+    }
+    if cfg.wait_for_mount(volume) {
+        client::wait_for_mount(transport.as_mut(), volume, opts.wait_options())?;
+        outcome.message.push_str("; mounted");
+    }
+    if let Some(command) = &volume.post_unlock_command {
+        client::run_hook("post_unlock_command", command, volume_name, volume).with_context(
+            || {
+                format!(
+                    "{} was unlocked, but its post_unlock_command failed",
+                    volume.dataset
+                )
+            },
+        )?;
+    }
+    if let Some(marker) = &boot_marker {
+        marker.record()?;
+    }
+    opts.report("unlock", volume_name, &outcome);
+    Ok(())
+}
+
+fn lock_volume(
+    cfg: &config::Config,
+    opts: &RunOptions,
+    secrets: &Secrets<'_>,
+    volume_name: &str,
+    volume: &config::VolumeConfig,
+    nas: &config::NasConfig,
+) -> Result<()> {
+    let stored_auth = secrets.auth(cfg, nas)?;
+
+    if opts.dry_run {
+        let (options, base_url) = rest_endpoint(nas, opts)?;
+        let rendered = truenas::render_lock_request(
+            options,
+            &base_url,
+            stored_auth.as_auth(),
+            &volume.dataset,
+            volume.lock_force_umount,
+        )?;
+        report_dry_run(opts, "lock", volume_name, &rendered);
         return Ok(());
     }
 
-    if let Some(message) = result.message {
-        println!("{}", message);
+    let hint = auth_failure_hint(secrets, &volume.nas, nas);
+    let mut transport = open_transport(nas, opts, stored_auth.as_auth(), hint)?;
+    let outcome = perform_lock(transport.as_mut(), opts, volume)?;
+    opts.report("lock", volume_name, &outcome);
+    Ok(())
+}
+
+/// Prints the request `--dry-run` held back, with the secret and the
+/// credentials redacted.
+fn report_dry_run(
+    opts: &RunOptions,
+    action: &str,
+    volume_name: &str,
+    rendered: &truenas::RenderedRequest,
+) {
+    if opts.json {
+        println!(
+            "{}",
+            json!({
+                "status": "dry_run",
+                "action": action,
+                "volume": volume_name,
+                "method": rendered.method,
+                "url": rendered.url,
+                "body": rendered.body,
+            })
+        );
+        return;
+    }
+    println!(
+        "dry run: would {} volume '{}' with {} {}",
+        action, volume_name, rendered.method, rendered.url
+    );
+    println!("{}", rendered.body);
+}
+
+fn verify_secret(cfg: &config::Config, opts: &RunOptions, volume_name: &str) -> Result<()> {
+    let (volume, nas) = cfg.resolve_volume(volume_name)?;
+    let secrets = open_secrets(opts, &secrets::databases(cfg, &[volume_name], true))?;
+
+    let stored_auth = secrets.auth(cfg, nas)?;
+    let unlock_secret_value = secrets.unlock_secret(cfg, nas, volume)?;
+    let child_secrets = secrets.child_secrets(cfg, nas, volume)?;
+
+    if opts.dry_run {
+        let (options, base_url) = rest_endpoint(nas, opts)?;
+        let rendered = truenas::render_unlock_request(
+            options,
+            &base_url,
+            stored_auth.as_auth(),
+            &volume.dataset,
+            unlock_secret(volume, unlock_secret_value.as_str()),
+            &child_unlock_secrets(volume, &child_secrets),
+            client::rendered_unlock_options(volume),
+        )?;
+        report_dry_run(opts, "verify-secret", volume_name, &rendered);
         return Ok(());
     }
 
-    println!("unlock request accepted");
+    before_first_request(opts)?;
+
+    let hint = auth_failure_hint(&secrets, &volume.nas, nas);
+    let mut transport = open_transport(nas, opts, stored_auth.as_auth(), hint)?;
+    let before = transport.dataset(&volume.dataset)?;
+    if !before.encrypted {
+        bail!("dataset {} is not encrypted", volume.dataset);
+    }
+    if !before.locked {
+        bail!(
+            "dataset {} is already unlocked; lock it first to verify the stored secret",
+            volume.dataset
+        );
+    }
+
+    if let Err(err) = perform_unlock(
+        transport.as_mut(),
+        opts,
+        volume,
+        unlock_secret_value.as_str(),
+        &child_secrets,
+    ) {
+        return Err(err.context(format!(
+            "stored secret for volume '{}' did not unlock {}",
+            volume_name, volume.dataset
+        )));
+    }
+
+    perform_lock(transport.as_mut(), opts, volume).with_context(|| {
+        format!(
+            "stored secret for volume '{}' is valid, but re-locking {} failed; the dataset is now UNLOCKED",
+            volume_name, volume.dataset
+        )
+    })?;
+
+    opts.report(
+        "verify-secret",
+        volume_name,
+        &format!(
+            "secret verified: volume '{}' unlocks {} (dataset re-locked)",
+            volume_name, volume.dataset
+        )
+        .into(),
+    );
     Ok(())
 }
 
-fn lock_volume(cfg: &config::Config, volume_name: &str) -> Result<()> {
-    let volume = cfg
-        .volume
-        .get(volume_name)
-        .with_context(|| format!("unknown volume '{}'", volume_name))?;
-    let nas = cfg
-        .nas
-        .get(&volume.nas)
-        .with_context(|| format!("unknown NAS '{}'", volume.nas))?;
+/// Unlocks the volume with its stored secret if needed, then changes the
+/// dataset's passphrase or key to the secret in `new_entry`. The config
+/// still names the old entry afterwards; the report says what to change.
+fn rotate(
+    cfg: &config::Config,
+    opts: &RunOptions,
+    volume_name: &str,
+    new_entry: &str,
+    new_field: Option<&str>,
+) -> Result<()> {
+    if opts.env_secrets.is_some() {
+        return Err(error::tag(
+            ErrorKind::Config,
+            anyhow::anyhow!(
+                "rotate reads the new secret from KeePass and cannot be used with --no-config"
+            ),
+        ));
+    }
+    let (volume, nas) = cfg.resolve_volume(volume_name)?;
+    let mut databases = secrets::databases(cfg, &[volume_name], true);
+    let new_database = cfg.volume_database(nas, volume);
+    if !databases.contains(&new_database) {
+        databases.push(new_database);
+    }
+    let secrets = open_secrets(opts, &databases)?;
+    let Secrets::KeePass(stores) = &secrets else {
+        unreachable!("--no-config was rejected above")
+    };
 
-    let master_password = Zeroizing::new(rpassword::prompt_password("KeePass password: ")?);
+    let stored_auth = secrets.auth(cfg, nas)?;
+    let current_secret = secrets.unlock_secret(cfg, nas, volume)?;
+    let child_secrets = secrets.child_secrets(cfg, nas, volume)?;
+    let new_secret = stores.entry_secret(cfg, nas, volume, new_entry, new_field)?;
+    if new_secret == current_secret {
+        bail!(
+            "the secret in '{}' is the one volume '{}' already uses; nothing to rotate",
+            new_entry,
+            volume_name
+        );
+    }
+    let what = match unlock_secret(volume, new_secret.as_str()) {
+        truenas::UnlockSecret::Passphrase(_) => "passphrase",
+        truenas::UnlockSecret::Key(_) => "key",
+    };
 
-    let store = keepass_store::KeePassStore::open(
-        &cfg.keepass.path,
-        cfg.keepass.key_file.as_deref(),
-        master_password.as_str(),
-    )?;
+    if opts.dry_run {
+        opts.report(
+            "rotate",
+            volume_name,
+            &format!(
+                "dry run: would unlock {} on {} if locked, then change its {} to the one in '{}'",
+                volume.dataset, nas.host, what, new_entry
+            )
+            .into(),
+        );
+        return Ok(());
+    }
+    if !opts.yes {
+        let answer = prompt_line(
+            "rotate",
+            &format!(
+                "About to change the encryption {} of {} on {} to the one in KeePass entry '{}'.\n\
+                 Make sure that entry is saved: if it is lost, the dataset cannot be unlocked again.\n\
+                 Type the dataset name to confirm: ",
+                what, volume.dataset, nas.host, new_entry
+            ),
+        )?;
+        if answer != volume.dataset {
+            bail!(
+                "confirmation did not match '{}'; nothing was done",
+                volume.dataset
+            );
+        }
+    }
 
-    let stored_auth = load_auth(&store, nas)?;
+    before_first_request(opts)?;
 
-    let client = truenas::build_client(nas.skip_tls_verify)?;
-    let base_url = truenas::parse_base_url(&nas.host)?;
+    let hint = auth_failure_hint(&secrets, &volume.nas, nas);
+    let mut transport = open_transport(nas, opts, stored_auth.as_auth(), hint)?;
+    let before = transport.dataset(&volume.dataset)?;
+    if !before.encrypted {
+        bail!("dataset {} is not encrypted", volume.dataset);
+    }
+    if before.locked {
+        perform_unlock(
+            transport.as_mut(),
+            opts,
+            volume,
+            current_secret.as_str(),
+            &child_secrets,
+        )
+        .with_context(|| {
+            format!(
+                "could not unlock {} with the stored secret; its {} was not changed",
+                volume.dataset, what
+            )
+        })?;
+    }
 
-    let result = truenas::lock_dataset(
-        &client,
-        &base_url,
-        stored_auth.as_auth(),
-        &volume.dataset,
-        volume.lock_force_umount,
-    )?;
+    let job = client::change_key(
+        transport.as_mut(),
+        volume,
+        new_secret.as_str(),
+        opts.wait_options(),
+    )
+    .with_context(|| {
+        format!(
+            "changing the {} of {} failed; check which of the old and new secrets unlocks it before retrying",
+            what, volume.dataset
+        )
+    })?;
 
-    if let Some(job_id) = result.job_id {
-        let job = truenas::wait_for_job(&client, &base_url, stored_auth.as_auth(), job_id)?;
-        println!("lock complete (job id: {})", job.id);
+    opts.report(
+        "rotate",
+        volume_name,
+        &Outcome {
+            message: format!(
+                "changed the {} of {}; {}",
+                what,
+                volume.dataset,
+                rotate_follow_up(volume, volume_name, new_entry, new_field)
+            ),
+            job: Some(job),
+        },
+    );
+    Ok(())
+}
+
+/// What to change in the config after a rotation, so the volume reads the
+/// new secret from where `rotate` found it.
+fn rotate_follow_up(
+    volume: &config::VolumeConfig,
+    volume_name: &str,
+    new_entry: &str,
+    new_field: Option<&str>,
+) -> String {
+    if volume.unlock_command.is_some() {
+        return format!(
+            "make the unlock_command of volume '{}' print the secret in '{}', the old secret no longer unlocks it",
+            volume_name, new_entry
+        );
+    }
+    let field = new_field
+        .map(|field| format!(" and unlock_field = \"{}\"", field))
+        .unwrap_or_default();
+    format!(
+        "set unlock_entry = \"{}\"{} for volume '{}', the old entry no longer unlocks it",
+        new_entry, field, volume_name
+    )
+}
+
+fn render_request(
+    cfg: &config::Config,
+    opts: &RunOptions,
+    volume_name: &str,
+    lock: bool,
+) -> Result<()> {
+    let (volume, nas) = cfg.resolve_volume(volume_name)?;
+    let secrets = open_secrets(opts, &secrets::databases(cfg, &[volume_name], !lock))?;
+
+    let stored_auth = secrets.auth(cfg, nas)?;
+    let (options, base_url) = rest_endpoint(nas, opts)?;
+
+    let rendered = if lock {
+        truenas::render_lock_request(
+            options,
+            &base_url,
+            stored_auth.as_auth(),
+            &volume.dataset,
+            volume.lock_force_umount,
+        )?
+    } else {
+        let unlock_secret_value = secrets.unlock_secret(cfg, nas, volume)?;
+        let child_secrets = secrets.child_secrets(cfg, nas, volume)?;
+        truenas::render_unlock_request(
+            options,
+            &base_url,
+            stored_auth.as_auth(),
+            &volume.dataset,
+            unlock_secret(volume, unlock_secret_value.as_str()),
+            &child_unlock_secrets(volume, &child_secrets),
+            client::rendered_unlock_options(volume),
+        )?
+    };
+
+    if opts.json {
+        println!("{}", serde_json::to_string(&rendered)?);
         return Ok(());
     }
+    println!("{} {}", rendered.method, rendered.url);
+    for (name, value) in &rendered.headers {
+        println!("{}: {}", name, value);
+    }
+    println!();
+    println!("{}", rendered.body);
+    Ok(())
+}
 
-    if result.locked {
-        println!("locked dataset: {}", volume.dataset);
+struct ListRow<'a> {
+    volume: String,
+    nas: &'a str,
+    dataset: &'a str,
+    description: Option<&'a str>,
+    unlock_mode: &'static str,
+    auth_method: &'static str,
+}
+
+/// Prints each configured volume with its NAS, dataset, unlock mode and the
+/// NAS's auth method. Only the config is read.
+fn list(cfg: &config::Config, opts: &RunOptions, nas: Option<&str>) -> Result<()> {
+    let mut rows = Vec::new();
+    for name in all_volumes(cfg, nas)? {
+        let (volume, nas) = cfg.resolve_volume(&name)?;
+        rows.push(ListRow {
+            volume: name,
+            nas: &volume.nas,
+            dataset: &volume.dataset,
+            description: volume.description.as_deref(),
+            unlock_mode: match volume.unlock_mode {
+                config::UnlockMode::Passphrase => "passphrase",
+                config::UnlockMode::Key => "key",
+                config::UnlockMode::KeyFileAttachment => "key_file_attachment",
+                config::UnlockMode::ChallengeResponse => "challenge_response",
+            },
+            auth_method: auth_method_name(nas.auth_method),
+        });
+    }
+
+    if opts.json {
+        for row in &rows {
+            println!(
+                "{}",
+                json!({
+                    "volume": row.volume,
+                    "nas": row.nas,
+                    "dataset": row.dataset,
+                    "description": row.description,
+                    "unlock_mode": row.unlock_mode,
+                    "auth_method": row.auth_method,
+                })
+            );
+        }
         return Ok(());
     }
 
-    if let Some(message) = result.message {
-        println!("{}", message);
+    let width = |header: &str, column: fn(&ListRow<'_>) -> usize| {
+        rows.iter().map(column).max().unwrap_or(0).max(header.len())
+    };
+    let volume_width = width("VOLUME", |r| r.volume.len());
+    let nas_width = width("NAS", |r| r.nas.len());
+    let dataset_width = width("DATASET", |r| r.dataset.len());
+    let mode_width = width("UNLOCK", |r| r.unlock_mode.len());
+    println!(
+        "{:volume_width$}  {:nas_width$}  {:dataset_width$}  {:mode_width$}  AUTH",
+        "VOLUME", "NAS", "DATASET", "UNLOCK"
+    );
+    for row in &rows {
+        let mut line = format!(
+            "{:volume_width$}  {:nas_width$}  {:dataset_width$}  {:mode_width$}  {}",
+            row.volume, row.nas, row.dataset, row.unlock_mode, row.auth_method
+        );
+        if let Some(description) = row.description {
+            line = format!("{}  # {}", line, description);
+        }
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// Prints the names of an entry's fields and attachments. Values are never
+/// read, so this is safe to run where the output may be seen or logged.
+fn list_fields(
+    cfg: &config::Config,
+    opts: &RunOptions,
+    selector: &str,
+    nas: Option<&str>,
+) -> Result<()> {
+    if opts.env_secrets.is_some() {
+        return Err(error::tag(
+            ErrorKind::Config,
+            anyhow::anyhow!("fields reads a KeePass database and cannot be used with --no-config"),
+        ));
+    }
+    let (database, root) = match nas {
+        Some(name) => {
+            let nas = cfg
+                .nas
+                .get(name)
+                .with_context(|| format!("unknown NAS '{}'", name))
+                .kind(ErrorKind::Config)?;
+            (cfg.nas_database(nas), secrets::search_root(cfg, nas))
+        }
+        None => (
+            cfg.keepass_database(),
+            SearchRoot {
+                base_group: cfg.keepass.base_group.as_deref(),
+                fallback: cfg.keepass.base_group_fallback,
+            },
+        ),
+    };
+    let stores = Stores::open(&[database], prompt_master_password)?;
+    let entry = require_entry(stores.get(database)?, root, selector).kind(ErrorKind::KeePass)?;
+    let fields = keepass_store::field_names(entry);
+    let attachments = keepass_store::attachment_names(entry);
+
+    if opts.json {
+        println!(
+            "{}",
+            json!({
+                "entry": entry.get_title(),
+                "uuid": entry.get_uuid().to_string(),
+                "fields": fields,
+                "attachments": attachments,
+            })
+        );
+        return Ok(());
+    }
+    println!(
+        "Fields of '{}' ({}), names only, no values shown:",
+        entry.get_title().unwrap_or(selector),
+        entry.get_uuid()
+    );
+    for field in fields {
+        println!("  {}", field);
+    }
+    if !attachments.is_empty() {
+        println!("Attachments:");
+        for name in attachments {
+            println!("  {}", name);
+        }
+    }
+    Ok(())
+}
+
+struct StatusRow<'a> {
+    volume: &'a str,
+    dataset: &'a str,
+    description: Option<&'a str>,
+    state: &'static str,
+    error: Option<String>,
+}
+
+/// Prints the lock state of one or all volumes. Each NAS is queried on its own
+/// thread with a shared client; a NAS that cannot be reached marks its
+/// volumes `unreachable` instead of failing the command.
+fn status(
+    cfg: &config::Config,
+    opts: &RunOptions,
+    volume_name: Option<&str>,
+    all: bool,
+) -> Result<()> {
+    let mut names: Vec<&str> = if all {
+        cfg.volume.keys().map(String::as_str).collect()
+    } else {
+        volume_name.into_iter().collect()
+    };
+    names.sort_unstable();
+
+    let mut by_nas: Vec<(&config::NasConfig, Vec<(&str, &config::VolumeConfig)>)> = Vec::new();
+    for name in names {
+        let (volume, nas) = cfg.resolve_volume(name)?;
+        match by_nas.iter_mut().find(|(n, _)| std::ptr::eq(*n, nas)) {
+            Some((_, volumes)) => volumes.push((name, volume)),
+            None => by_nas.push((nas, vec![(name, volume)])),
+        }
+    }
+
+    let nas_databases: Vec<_> = by_nas
+        .iter()
+        .filter(|(nas, _)| nas.auth_command.is_none())
+        .map(|(nas, _)| cfg.nas_database(nas))
+        .collect();
+    let secrets = open_secrets(opts, &nas_databases)?;
+    let mut rows: Vec<StatusRow<'_>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = by_nas
+            .iter()
+            .map(|(nas, volumes)| {
+                let auth = secrets.auth(cfg, nas);
+                let hint = auth_failure_hint(&secrets, &volumes[0].1.nas, nas);
+                scope.spawn(move || nas_status(nas, opts, auth, hint, volumes))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("status thread panicked"))
+            .collect()
+    });
+    rows.sort_by(|a, b| a.volume.cmp(b.volume));
+
+    if opts.json {
+        for row in &rows {
+            println!(
+                "{}",
+                json!({
+                    "volume": row.volume,
+                    "dataset": row.dataset,
+                    "description": row.description,
+                    "state": row.state,
+                    "error": row.error,
+                })
+            );
+        }
         return Ok(());
     }
 
-    println!("lock request accepted");
+    let volume_width = rows
+        .iter()
+        .map(|r| r.volume.len())
+        .max()
+        .unwrap_or(0)
+        .max(6);
+    let dataset_width = rows
+        .iter()
+        .map(|r| r.dataset.len())
+        .max()
+        .unwrap_or(0)
+        .max(7);
+    println!(
+        "{:volume_width$}  {:dataset_width$}  STATE",
+        "VOLUME", "DATASET"
+    );
+    for row in &rows {
+        let mut state = match &row.error {
+            Some(error) => format!("{} ({})", row.state, error),
+            None => row.state.to_string(),
+        };
+        if let Some(description) = row.description {
+            state = format!("{}  # {}", state, description);
+        }
+        println!(
+            "{:volume_width$}  {:dataset_width$}  {}",
+            row.volume, row.dataset, state
+        );
+    }
     Ok(())
 }
 
-fn load_auth(store: &keepass_store::KeePassStore, nas: &config::NasConfig) -> Result<StoredAuth> {
-    let auth_entry = require_entry(store, &nas.auth_entry)?;
+fn nas_status<'a>(
+    nas: &config::NasConfig,
+    opts: &RunOptions,
+    auth: Result<StoredAuth>,
+    auth_hint: String,
+    volumes: &[(&'a str, &'a config::VolumeConfig)],
+) -> Vec<StatusRow<'a>> {
+    let row = |name: &'a str, volume: &'a config::VolumeConfig, state, error| StatusRow {
+        volume: name,
+        dataset: &volume.dataset,
+        description: volume.description.as_deref(),
+        state,
+        error,
+    };
+    let failed = |err: anyhow::Error| {
+        let state = match error::classify(&err) {
+            ErrorKind::Network => "unreachable",
+            _ => "error",
+        };
+        let error = format!("{:#}", err);
+        volumes
+            .iter()
+            .map(|(name, volume)| row(name, volume, state, Some(error.clone())))
+            .collect()
+    };
+    let auth = match auth {
+        Ok(auth) => auth,
+        Err(err) => return failed(err),
+    };
+    let mut transport = match open_transport(nas, opts, auth.as_auth(), auth_hint) {
+        Ok(transport) => transport,
+        Err(err) => return failed(err),
+    };
+
+    let mut reachable = true;
+    let mut rows = Vec::new();
+    for (name, volume) in volumes {
+        if !reachable {
+            rows.push(row(name, volume, "unreachable", None));
+            continue;
+        }
+        match transport.dataset(&volume.dataset) {
+            Ok(info) if !info.encrypted => rows.push(row(name, volume, "unencrypted", None)),
+            Ok(info) if info.locked => rows.push(row(name, volume, "locked", None)),
+            Ok(_) => rows.push(row(name, volume, "unlocked", None)),
+            Err(err) if error::classify(&err) == ErrorKind::Network => {
+                reachable = false;
+                rows.push(row(name, volume, "unreachable", Some(format!("{:#}", err))));
+            }
+            Err(err) => rows.push(row(name, volume, "error", Some(format!("{:#}", err)))),
+        }
+    }
+    rows
+}
+
+/// Prints a shell or Python script that sends the same unlock request. Only
+/// the config is used; the script reads the secrets from its environment.
+fn gen_script(cfg: &config::Config, volume_name: &str, lang: script::ScriptLanguage) -> Result<()> {
+    let (volume, nas) = cfg.resolve_volume(volume_name)?;
+    if !volume.children.is_empty() {
+        return Err(error::tag(
+            ErrorKind::Config,
+            anyhow::anyhow!(
+                "gen-script does not support volumes with child datasets (volume '{}')",
+                volume_name
+            ),
+        ));
+    }
+    // The script sends the body as is, without the zvol check an unlock
+    // makes, so an undeclared volume gets the filesystem default.
+    let warning = (volume.toggle_attachments.is_none() && volume.dataset_type.is_none())
+        .then(|| {
+            format!(
+                "{} is assumed to be a filesystem and is unlocked with toggle_attachments; if it is a zvol, set dataset_type = \"volume\" on volume '{}' and generate the script again",
+                volume.dataset, volume_name
+            )
+        });
+    if let Some(warning) = &warning {
+        eprintln!("warning: {}", warning);
+    }
+    let base_url = truenas::parse_base_url(&nas.host).kind(ErrorKind::Config)?;
+    let url = base_url
+        .join(truenas::UNLOCK_PATH)
+        .context("failed to build API URL")?;
+    let body = serde_json::to_value(truenas::build_unlock_body(
+        &volume.dataset,
+        unlock_secret(volume, ""),
+        &[],
+        unlock_options(volume),
+    ))?;
+    if volume.derive.is_some() {
+        eprintln!(
+            "warning: volume '{}' derives its passphrase; the script expects the derived value",
+            volume_name
+        );
+    }
+
+    let script = script::UnlockScript {
+        url: &url,
+        dataset: &volume.dataset,
+        auth_method: nas.auth_method,
+        api_key_header: truenas::api_key_header(
+            nas.api_key_header.as_deref(),
+            nas.api_key_scheme.as_deref(),
+        ),
+        skip_tls_verify: nas.skip_tls_verify,
+        ca_cert: nas.ca_cert.as_deref(),
+        body,
+        secret_field: match volume.unlock_mode {
+            config::UnlockMode::Passphrase | config::UnlockMode::ChallengeResponse => "passphrase",
+            config::UnlockMode::Key | config::UnlockMode::KeyFileAttachment => "key",
+        },
+        warning: warning.as_deref(),
+    };
+    print!("{}", script.generate(lang));
+    Ok(())
+}
 
-    let stored_auth = match nas.auth_method {
-        config::AuthMethod::Basic => {
-            let username = required_field(auth_entry, &nas.username_field, &nas.auth_entry)?;
-            let password = required_field(auth_entry, &nas.password_field, &nas.auth_entry)?;
-            ensure_non_empty(username.as_str(), "NAS username")?;
-            ensure_non_empty(password.as_str(), "NAS password")?;
-            StoredAuth::Basic { username, password }
+/// Points the NAS that the command's volumes live on at `host`, for
+/// `--nas-host`. The volumes must all be on one NAS, so credentials for
+/// other hosts are never sent there.
+fn override_nas_host(cfg: &mut config::Config, command: &Command, host: &str) -> Result<()> {
+    let volumes: Vec<&String> = match command {
+        Command::Unlock { nas: Some(nas), .. } | Command::Lock { nas: Some(nas), .. } => {
+            return set_nas_host(cfg, nas, host);
         }
-        config::AuthMethod::ApiKey => {
-            let key = required_field(auth_entry, &nas.password_field, &nas.auth_entry)?;
-            ensure_non_empty(key.as_str(), "API key")?;
-            StoredAuth::ApiKey { key }
+        Command::Unlock {
+            volumes,
+            all: false,
+            ..
         }
+        | Command::Lock {
+            volumes,
+            all: false,
+            ..
+        } => volumes.iter().collect(),
+        Command::VerifySecret { volume }
+        | Command::Rotate { volume, .. }
+        | Command::RenderRequest { volume, .. }
+        | Command::GenScript { volume, .. }
+        | Command::Status {
+            volume: Some(volume),
+            ..
+        } => vec![volume],
+        _ => bail!("--nas-host needs volumes named on the command line, or --all with --nas"),
     };
 
-    Ok(stored_auth)
+    let mut nas_names: Vec<&str> = volumes
+        .iter()
+        .filter_map(|name| cfg.volume.get(name.as_str()))
+        .map(|volume| volume.nas.as_str())
+        .collect();
+    nas_names.sort_unstable();
+    nas_names.dedup();
+    match nas_names.as_slice() {
+        // Unknown volumes are reported when they are resolved.
+        [] => Ok(()),
+        [nas] => {
+            let nas = nas.to_string();
+            set_nas_host(cfg, &nas, host)
+        }
+        _ => bail!(
+            "--nas-host applies to a single NAS, but the volumes are on {}",
+            nas_names.join(", ")
+        ),
+    }
+}
+
+fn set_nas_host(cfg: &mut config::Config, nas: &str, host: &str) -> Result<()> {
+    let nas_config = cfg
+        .nas
+        .get_mut(nas)
+        .with_context(|| format!("unknown NAS '{}'", nas))?;
+    truenas::parse_base_url(host).context("invalid --nas-host")?;
+    tracing::info!(
+        "using {} instead of {} for NAS '{}'",
+        host,
+        nas_config.host,
+        nas
+    );
+    nas_config.host = host.to_string();
+    Ok(())
+}
+
+/// Where NAS credentials and unlock secrets come from: the KeePass database,
+/// or with `--no-config` the environment variables named on the command line.
+enum Secrets<'a> {
+    KeePass(Stores),
+    Env(&'a EnvSecrets),
+}
+
+impl Secrets<'_> {
+    fn auth(&self, cfg: &config::Config, nas: &config::NasConfig) -> Result<StoredAuth> {
+        match self {
+            Secrets::KeePass(stores) => stores.auth(cfg, nas),
+            Secrets::Env(env) => {
+                let stored_auth = env.auth(nas).kind(ErrorKind::Config)?;
+                stored_auth.reject_placeholder(cfg)?;
+                Ok(stored_auth)
+            }
+        }
+    }
+
+    fn unlock_secret(
+        &self,
+        cfg: &config::Config,
+        nas: &config::NasConfig,
+        volume: &config::VolumeConfig,
+    ) -> Result<Zeroizing<String>> {
+        match self {
+            Secrets::KeePass(stores) => stores.unlock_secret(cfg, nas, volume),
+            Secrets::Env(env) => {
+                let secret = read_secret_env(
+                    env.unlock_secret_env.as_deref(),
+                    "--unlock-secret-env",
+                    "unlock secret",
+                )
+                .kind(ErrorKind::Config)?;
+                secrets::prepare_secret(cfg, volume, &volume.dataset, secret)
+            }
+        }
+    }
+
+    /// The secrets for the volume's `children`, in order.
+    fn child_secrets(
+        &self,
+        cfg: &config::Config,
+        nas: &config::NasConfig,
+        volume: &config::VolumeConfig,
+    ) -> Result<Vec<Zeroizing<String>>> {
+        match self {
+            Secrets::KeePass(stores) => stores.child_secrets(cfg, nas, volume),
+            Secrets::Env(_) if !volume.children.is_empty() => {
+                bail!("child datasets need their secrets from KeePass")
+            }
+            Secrets::Env(_) => Ok(Vec::new()),
+        }
+    }
+}
+
+struct EnvSecrets {
+    username: Option<String>,
+    password_env: Option<String>,
+    api_key_env: Option<String>,
+    unlock_secret_env: Option<String>,
+}
+
+impl EnvSecrets {
+    fn auth(&self, nas: &config::NasConfig) -> Result<StoredAuth> {
+        match nas.auth_method {
+            config::AuthMethod::Basic => {
+                let Some(username) = &self.username else {
+                    bail!("--username is required with --no-config and basic auth");
+                };
+                let password = read_secret_env(
+                    self.password_env.as_deref(),
+                    "--password-env",
+                    "NAS password",
+                )?;
+                Ok(StoredAuth::Basic {
+                    username: Zeroizing::new(username.clone()),
+                    password,
+                    totp: None,
+                })
+            }
+            config::AuthMethod::ApiKey => Ok(StoredAuth::api_key(
+                nas,
+                read_secret_env(self.api_key_env.as_deref(), "--api-key-env", "API key")?,
+            )),
+            config::AuthMethod::ApiKeyBasic => Ok(StoredAuth::ApiKeyBasic {
+                key: read_secret_env(self.api_key_env.as_deref(), "--api-key-env", "API key")?,
+            }),
+        }
+    }
+}
+
+fn read_secret_env(var: Option<&str>, flag: &str, what: &str) -> Result<Zeroizing<String>> {
+    let Some(var) = var else {
+        bail!("{} is required with --no-config", flag);
+    };
+    let value = Zeroizing::new(
+        std::env::var(var).with_context(|| format!("environment variable {} is not set", var))?,
+    );
+    ensure_non_empty(value.as_str(), what)?;
+    Ok(value)
+}
+
+/// Opens each of `databases`, asking for its master password once.
+fn open_secrets<'a>(
+    opts: &'a RunOptions,
+    databases: &[config::KeepassDatabase<'_>],
+) -> Result<Secrets<'a>> {
+    if let Some(env) = &opts.env_secrets {
+        return Ok(Secrets::Env(env));
+    }
+    Ok(Secrets::KeePass(Stores::open(
+        databases,
+        prompt_master_password,
+    )?))
+}
+
+/// Asks for the dataset name to be typed back before a destructive operation.
+fn confirm_dataset_name(volume: &config::VolumeConfig, host: &str) -> Result<()> {
+    let dataset = volume.dataset.as_str();
+    let intro = match &volume.description {
+        Some(description) => format!(
+            "About to lock dataset {} ({}) on {}.",
+            dataset, description, host
+        ),
+        None => format!("About to lock dataset {} on {}.", dataset, host),
+    };
+    let answer = prompt_line(
+        "--confirm-name",
+        &format!("{}\nType the dataset name to confirm: ", intro),
+    )?;
+    if answer != dataset {
+        bail!("confirmation did not match '{}'; nothing was done", dataset);
+    }
+    Ok(())
+}
+
+/// Asks "Are you sure?" before a lock, spelling out whether `force_umount`
+/// will unmount the dataset from under running services. The answer is read
+/// from stdin; anything but yes, including empty input and end of input,
+/// leaves the dataset alone.
+fn confirm_lock(volume: &config::VolumeConfig, host: &str) -> Result<()> {
+    let target = match &volume.description {
+        Some(description) => format!("{} ({}) on {}", volume.dataset, description, host),
+        None => format!("{} on {}", volume.dataset, host),
+    };
+    let umount = if volume.lock_force_umount {
+        "force_umount is ENABLED: the dataset is unmounted even while in use, interrupting anything that has it open."
+    } else {
+        "force_umount is off: the lock fails if the dataset is busy."
+    };
+    let answer = {
+        let _paused = watchdog::paused();
+        eprint!(
+            "{}\nAre you sure you want to lock {}? [y/N] ",
+            umount, target
+        );
+        std::io::stderr().flush()?;
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer)? == 0 {
+            eprintln!();
+        }
+        answer
+    };
+    if !matches!(answer.trim(), "y" | "Y" | "yes") {
+        bail!(
+            "lock of {} not confirmed; nothing was done (pass --yes to skip the confirmation)",
+            volume.dataset
+        );
+    }
+    Ok(())
+}
+
+/// Lets the user choose the volume for an `unlock` that names none, from a
+/// numbered list on the terminal. Without a terminal on both stdin and
+/// stdout, or with `--json`, a volume is required as before.
+fn pick_volume(cfg: &config::Config, opts: &RunOptions) -> Result<String> {
+    let interactive = std::io::stdin().is_terminal() && std::io::stdout().is_terminal();
+    if !interactive || opts.json || cfg.volume.is_empty() {
+        return Err(error::tag(
+            ErrorKind::Config,
+            anyhow::anyhow!("unlock needs a volume name, --all or --dataset"),
+        ));
+    }
+    let names = all_volumes(cfg, None)?;
+    let width = names.iter().map(String::len).max().unwrap_or(0);
+    let _paused = watchdog::paused();
+    eprintln!("Configured volumes:");
+    for (index, name) in names.iter().enumerate() {
+        let volume = &cfg.volume[name];
+        let mut line = format!(
+            "{:>3}) {:width$}  {} on {}",
+            index + 1,
+            name,
+            volume.dataset,
+            volume.nas
+        );
+        if let Some(description) = &volume.description {
+            line = format!("{}  # {}", line, description);
+        }
+        eprintln!("{}", line);
+    }
+    loop {
+        eprint!("Unlock which volume? [1-{}] ", names.len());
+        std::io::stderr().flush()?;
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer)? == 0 {
+            eprintln!();
+        }
+        let answer = answer.trim();
+        if answer.is_empty() {
+            bail!("no volume chosen; nothing was done");
+        }
+        let chosen = match answer.parse::<usize>() {
+            Ok(number) => number.checked_sub(1).and_then(|index| names.get(index)),
+            Err(_) => names.iter().find(|name| *name == answer),
+        };
+        match chosen {
+            Some(name) => return Ok(name.clone()),
+            None => eprintln!("'{}' is not one of the volumes above", answer),
+        }
+    }
+}
+
+/// Asks a question on the terminal and returns the answer without the line
+/// ending. `feature` names what needs the prompt in the error given when
+/// there is no terminal.
+fn prompt_line(feature: &str, prompt: &str) -> Result<String> {
+    if !std::io::stdin().is_terminal() {
+        bail!(
+            "{} needs an interactive terminal; pass --yes to skip the confirmation",
+            feature
+        );
+    }
+    let _paused = watchdog::paused();
+    eprint!("{}", prompt);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim_end_matches(['\r', '\n']).to_string())
+}
+
+struct PlanStep<'a> {
+    volume: &'a str,
+    dataset: &'a str,
+    nas: &'a str,
+    host: &'a str,
+    options: Vec<&'static str>,
+    auth: String,
+    secret: Option<String>,
+}
+
+/// For `--all`, resolves every volume up front, including its KeePass
+/// entries, and prints what will be done, naming entries and fields but never
+/// their values. Unless `--yes` or `--json` is given, asks before anything is
+/// sent. Returns the opened secrets so the batch does not prompt again.
+fn plan_and_confirm<'a>(
+    cfg: &config::Config,
+    opts: &'a RunOptions,
+    action: &str,
+    names: &[String],
+    all: bool,
+) -> Result<Option<Secrets<'a>>> {
+    if !all {
+        return Ok(None);
+    }
+    if names.is_empty() {
+        bail!("no volumes to {}", action);
+    }
+
+    let secrets = open_secrets(opts, &secrets::databases(cfg, names, action == "unlock"))?;
+    let mut steps = Vec::new();
+    let mut problems = Vec::new();
+    for name in names {
+        match plan_step(cfg, &secrets, action, name) {
+            Ok(step) => steps.push(step),
+            Err(err) => problems.push(format!("{}: {:#}", name, err)),
+        }
+    }
+    if !problems.is_empty() {
+        for problem in &problems {
+            eprintln!("plan: {}", problem);
+        }
+        return Err(error::tag(
+            ErrorKind::Config,
+            anyhow::anyhow!(
+                "{} of {} volumes could not be resolved; nothing was done",
+                problems.len(),
+                names.len()
+            ),
+        ));
+    }
+
+    if opts.json {
+        let volumes: Vec<_> = steps
+            .iter()
+            .map(|step| {
+                json!({
+                    "volume": step.volume,
+                    "dataset": step.dataset,
+                    "nas": step.nas,
+                    "host": step.host,
+                    "options": step.options,
+                    "auth": step.auth,
+                    "secret": step.secret,
+                })
+            })
+            .collect();
+        println!(
+            "{}",
+            json!({"status": "plan", "action": action, "volumes": volumes})
+        );
+        return Ok(Some(secrets));
+    }
+
+    println!("Plan: {} {} volume(s)", action, steps.len());
+    for step in &steps {
+        let options = if step.options.is_empty() {
+            String::new()
+        } else {
+            format!(" [{}]", step.options.join(", "))
+        };
+        println!(
+            "  {}: {} on {} ({}){}",
+            step.volume, step.dataset, step.nas, step.host, options
+        );
+        println!("    auth: {}", step.auth);
+        if let Some(secret) = &step.secret {
+            println!("    secret: {}", secret);
+        }
+    }
+
+    if !opts.yes && !opts.dry_run {
+        let answer = prompt_line(&format!("{} --all", action), "Proceed? [y/N] ")?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            bail!("aborted; nothing was done");
+        }
+    }
+    Ok(Some(secrets))
+}
+
+fn plan_step<'a>(
+    cfg: &'a config::Config,
+    secrets: &Secrets<'_>,
+    action: &str,
+    name: &'a str,
+) -> Result<PlanStep<'a>> {
+    let (volume, nas) = cfg.resolve_volume(name)?;
+    secrets.auth(cfg, nas)?;
+
+    let auth = match (secrets, &nas.auth_command) {
+        (Secrets::Env(_), _) => "from environment".to_string(),
+        (_, Some(command)) => format!("auth_command {}", command.join(" ")),
+        _ => match nas.auth_method {
+            config::AuthMethod::Basic => format!(
+                "entry {:?} ({}, {})",
+                nas.auth_entry, nas.username_field, nas.password_field
+            ),
+            _ => format!("entry {:?} ({})", nas.auth_entry, nas.password_field),
+        },
+    };
+
+    let mut options = Vec::new();
+    let secret = if action == "unlock" {
+        secrets.unlock_secret(cfg, nas, volume)?;
+        secrets.child_secrets(cfg, nas, volume)?;
+        for (set, option) in [
+            (volume.recursive, "recursive"),
+            (volume.force, "force"),
+            (volume.toggle_attachments(), "toggle_attachments"),
+        ] {
+            if set {
+                options.push(option);
+            }
+        }
+        let mode = match volume.unlock_mode {
+            config::UnlockMode::Passphrase => "passphrase",
+            config::UnlockMode::Key => "key",
+            config::UnlockMode::KeyFileAttachment => "key file attachment",
+            config::UnlockMode::ChallengeResponse => "challenge-response passphrase",
+        };
+        let mut secret = match (secrets, &volume.unlock_command) {
+            (Secrets::Env(_), _) => format!("{} from environment", mode),
+            (Secrets::KeePass(_), Some(command)) => {
+                format!("{} from unlock_command {}", mode, command.join(" "))
+            }
+            (Secrets::KeePass(_), None) => format!(
+                "{} from entry {:?} ({})",
+                mode, volume.unlock_entry, volume.unlock_field
+            ),
+        };
+        for child in &volume.children {
+            secret.push_str(&format!(
+                "; {} from entry {:?} ({})",
+                child.dataset, child.unlock_entry, child.unlock_field
+            ));
+        }
+        let database = cfg.volume_database(nas, volume);
+        let from_keepass = volume.unlock_command.is_none() || !volume.children.is_empty();
+        if matches!(secrets, Secrets::KeePass(_))
+            && from_keepass
+            && database.path != cfg.keepass.path
+        {
+            secret.push_str(&format!(" in {}", database.path.display()));
+        }
+        Some(secret)
+    } else {
+        if volume.lock_force_umount {
+            options.push("force_umount");
+        }
+        None
+    };
+
+    Ok(PlanStep {
+        volume: name,
+        dataset: &volume.dataset,
+        nas: &volume.nas,
+        host: &nas.host,
+        options,
+        auth,
+        secret,
+    })
+}
+
+/// Asks for the master password of the KeePass database at `path`.
+fn prompt_master_password(path: &std::path::Path) -> Result<Zeroizing<String>> {
+    let _paused = watchdog::paused();
+    Ok(Zeroizing::new(rpassword::prompt_password(format!(
+        "KeePass password for {}: ",
+        path.display()
+    ))?))
+}
+
+/// The client options and base URL for rendering a REST request to `nas`.
+fn rest_endpoint<'a>(
+    nas: &'a config::NasConfig,
+    opts: &RunOptions,
+) -> Result<(truenas::ClientOptions<'a>, url::Url)> {
+    let base_url = truenas::parse_base_url(&nas.host)?;
+    tracing::debug!(%base_url, "using the REST API");
+    Ok((client_options(nas, opts), base_url))
+}
+
+fn client_options<'a>(nas: &'a config::NasConfig, opts: &RunOptions) -> truenas::ClientOptions<'a> {
+    if nas.skip_tls_verify && nas.skip_tls_verify_warning && !opts.dry_run {
+        let mut warned = opts.insecure_warned.lock().expect("insecure_warned lock");
+        if warned.insert(nas.host.clone()) {
+            eprintln!(
+                "warning: TLS certificate verification is off for {} (skip_tls_verify = true); the connection is open to interception",
+                nas.host
+            );
+        }
+    }
+    truenas::ClientOptions {
+        max_redirects: opts.max_redirects.unwrap_or(nas.max_redirects),
+        ..client::client_options(nas)
+    }
+}
+
+/// Connects to the NAS with [`client::open_transport`]. Whenever the NAS
+/// rejects the credentials, `auth_hint` from [`auth_failure_hint`] is added
+/// to the error.
+fn open_transport<'a>(
+    nas: &config::NasConfig,
+    opts: &RunOptions,
+    auth: truenas::Auth<'a>,
+    auth_hint: String,
+) -> Result<Box<dyn truenas::Transport + 'a>> {
+    let inner = client::open_transport(nas, client_options(nas, opts), auth)
+        .map_err(|err| explain_auth_failure(err, &auth_hint))?;
+    Ok(Box::new(ExplainAuth { inner, auth_hint }))
+}
+
+/// Says which credentials the NAS rejected and where they came from, for
+/// a `401`/`403` response or a failed websocket login.
+fn auth_failure_hint(secrets: &Secrets<'_>, nas_name: &str, nas: &config::NasConfig) -> String {
+    let check = match (secrets, &nas.auth_command, nas.auth_method) {
+        (Secrets::Env(_), _, config::AuthMethod::Basic) => {
+            "check --username and the variable named by --password-env".to_string()
+        }
+        (Secrets::Env(_), _, _) => "check the variable named by --api-key-env".to_string(),
+        (_, Some(_), _) => "check the API key printed by auth_command".to_string(),
+        (_, None, config::AuthMethod::Basic) => format!(
+            "check the '{}' and '{}' fields of entry '{}'",
+            nas.username_field, nas.password_field, nas.auth_entry
+        ),
+        (_, None, _) => format!(
+            "check the '{}' field of entry '{}'",
+            nas.password_field, nas.auth_entry
+        ),
+    };
+    format!(
+        "authentication failed for NAS '{}' using {}; {}",
+        nas_name,
+        auth_method_name(nas.auth_method),
+        check
+    )
+}
+
+fn explain_auth_failure(err: anyhow::Error, auth_hint: &str) -> anyhow::Error {
+    if error::classify(&err) == ErrorKind::Auth {
+        err.context(auth_hint.to_string())
+    } else {
+        err
+    }
+}
+
+/// A transport whose credential rejections carry an [`auth_failure_hint`].
+struct ExplainAuth<'a> {
+    inner: Box<dyn truenas::Transport + 'a>,
+    auth_hint: String,
+}
+
+impl truenas::Transport for ExplainAuth<'_> {
+    fn unlock(
+        &mut self,
+        dataset: &str,
+        secret: truenas::UnlockSecret<'_>,
+        children: &[(&str, truenas::UnlockSecret<'_>)],
+        options: truenas::UnlockOptions,
+    ) -> Result<truenas::UnlockResult> {
+        let result = self.inner.unlock(dataset, secret, children, options);
+        result.map_err(|err| explain_auth_failure(err, &self.auth_hint))
+    }
+
+    fn lock(&mut self, dataset: &str, force_umount: bool) -> Result<truenas::LockResult> {
+        let result = self.inner.lock(dataset, force_umount);
+        result.map_err(|err| explain_auth_failure(err, &self.auth_hint))
+    }
+
+    fn change_key(&mut self, dataset: &str, secret: truenas::UnlockSecret<'_>) -> Result<i64> {
+        let result = self.inner.change_key(dataset, secret);
+        result.map_err(|err| explain_auth_failure(err, &self.auth_hint))
+    }
+
+    fn dataset(&mut self, dataset: &str) -> Result<truenas::DatasetInfo> {
+        let result = self.inner.dataset(dataset);
+        result.map_err(|err| explain_auth_failure(err, &self.auth_hint))
+    }
+
+    fn wait_for_job(
+        &mut self,
+        job_id: i64,
+        wait: truenas::WaitOptions,
+    ) -> Result<truenas::JobInfo> {
+        let result = self.inner.wait_for_job(job_id, wait);
+        result.map_err(|err| explain_auth_failure(err, &self.auth_hint))
+    }
+}
+
+fn auth_method_name(method: config::AuthMethod) -> &'static str {
+    match method {
+        config::AuthMethod::Basic => "basic",
+        config::AuthMethod::ApiKey => "api_key",
+        config::AuthMethod::ApiKeyBasic => "api_key_basic",
+    }
+}
+
+/// Sends the unlock request and waits for any resulting job, returning a
+/// one-line summary of the outcome.
+fn perform_unlock(
+    transport: &mut dyn truenas::Transport,
+    opts: &RunOptions,
+    volume: &config::VolumeConfig,
+    secret: &str,
+    child_secrets: &[Zeroizing<String>],
+) -> Result<Outcome> {
+    let unlocked = client::unlock_dataset(
+        transport,
+        volume,
+        secret,
+        child_secrets,
+        opts.wait_options(),
+    )?;
+    check_unlock_failures(opts, &unlocked.result)?;
+    let result = unlocked.result;
+    if opts.strict_unlock && !result.skipped.is_empty() {
+        return Err(error::tag(
+            ErrorKind::Rejected,
+            anyhow::anyhow!("already unlocked: {}", result.skipped.join(", ")),
+        ));
+    }
+
+    if let Some(job) = unlocked.job {
+        let message = match unlock_summary(&result) {
+            Some(summary) => format!("unlock complete (job id: {}); {}", job.id, summary),
+            None => format!("unlock complete (job id: {})", job.id),
+        };
+        return Ok(Outcome {
+            message,
+            job: Some(job),
+        });
+    }
+
+    if let Some(summary) = unlock_summary(&result) {
+        return Ok(summary.into());
+    }
+
+    if let Some(message) = result.message {
+        return Ok(message.into());
+    }
+
+    Ok("unlock request accepted".to_string().into())
+}
+
+/// Fails with the reasons if the NAS reports datasets it could not unlock.
+/// Outside `--json` the reasons go to stderr, one per line.
+fn check_unlock_failures(opts: &RunOptions, result: &truenas::UnlockResult) -> Result<()> {
+    if opts.json || result.failed.is_empty() {
+        return client::check_unlock_failures(result);
+    }
+    for line in client::unlock_failures(result) {
+        eprintln!("{}", line);
+    }
+    Err(error::tag(
+        ErrorKind::Rejected,
+        anyhow::anyhow!("unlock failed"),
+    ))
+}
+
+/// Lists the datasets the unlock unlocked and those that were already
+/// unlocked, if the NAS said.
+fn unlock_summary(result: &truenas::UnlockResult) -> Option<String> {
+    let mut parts = Vec::new();
+    if !result.unlocked.is_empty() {
+        parts.push(format!("unlocked datasets: {}", result.unlocked.join(", ")));
+    }
+    if !result.skipped.is_empty() {
+        parts.push(format!("already unlocked: {}", result.skipped.join(", ")));
+    }
+    (!parts.is_empty()).then(|| parts.join("; "))
+}
+
+/// Sends the lock request and waits for any resulting job, returning a
+/// one-line summary of the outcome.
+fn perform_lock(
+    transport: &mut dyn truenas::Transport,
+    opts: &RunOptions,
+    volume: &config::VolumeConfig,
+) -> Result<Outcome> {
+    let locked = client::lock_dataset(transport, volume, opts.wait_options())?;
+    let result = locked.result;
+
+    if let Some(job) = locked.job {
+        return Ok(Outcome {
+            message: format!("lock complete (job id: {})", job.id),
+            job: Some(job),
+        });
+    }
+
+    if result.already_locked {
+        return Ok(format!("dataset already locked: {}", volume.dataset).into());
+    }
+
+    if result.locked {
+        return Ok(format!("locked dataset: {}", volume.dataset).into());
+    }
+
+    if let Some(message) = result.message {
+        return Ok(message.into());
+    }
+
+    Ok("lock request accepted".to_string().into())
+}
+
+/// Runs once, right before the first request to the NAS: sleeps for the
+/// `--splay` delay, then starts the `--timeout` clock.
+fn before_first_request(opts: &RunOptions) -> Result<()> {
+    if opts.dry_run {
+        return Ok(());
+    }
+    if let Some(max) = opts.splay.filter(|max| !max.is_zero()) {
+        let random = getrandom::u64()
+            .map_err(|err| anyhow::anyhow!("failed to read random seed: {}", err))?;
+        let delay = Duration::from_millis(random % (max.as_millis() as u64 + 1));
+        tracing::info!(
+            "splay: sleeping {:.1}s before contacting the NAS",
+            delay.as_secs_f64()
+        );
+        std::thread::sleep(delay);
+    }
+    if let Some(secs) = opts.timeout {
+        let json = opts.json;
+        watchdog::start(Duration::from_secs(secs), move || {
+            let err = error::tag(
+                ErrorKind::Timeout,
+                anyhow::anyhow!("operation timed out after {} seconds", secs),
+            );
+            print_error(&err, json);
+            finish_audit();
+            ErrorKind::Timeout.exit_code()
+        });
+    }
+    Ok(())
 }
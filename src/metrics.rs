@@ -0,0 +1,108 @@
+use anyhow::{Context, Result};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub struct Sample<'a> {
+    pub action: &'a str,
+    pub volume: &'a str,
+    pub nas: &'a str,
+    pub success: bool,
+    pub duration: Duration,
+    pub finished_at: SystemTime,
+}
+
+/// Writes the samples in the Prometheus text exposition format for
+/// node_exporter's textfile collector. The file is written next to its final
+/// location and renamed into place so the collector never reads a partial file.
+pub fn write_textfile(path: &Path, samples: &[Sample<'_>]) -> Result<()> {
+    let mut out = String::new();
+    let mut actions: Vec<&str> = samples.iter().map(|s| s.action).collect();
+    actions.sort_unstable();
+    actions.dedup();
+
+    for action in actions {
+        let metric = action.replace('-', "_");
+        let of_action = || samples.iter().filter(move |s| s.action == action);
+
+        let _ = writeln!(
+            out,
+            "# HELP naslock_{metric}_success Whether the last {action} of the volume succeeded."
+        );
+        let _ = writeln!(out, "# TYPE naslock_{metric}_success gauge");
+        for sample in of_action() {
+            let _ = writeln!(
+                out,
+                "naslock_{metric}_success{} {}",
+                labels(sample, false),
+                u8::from(sample.success)
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP naslock_{metric}_duration_seconds Wall-clock duration of the last {action}."
+        );
+        let _ = writeln!(out, "# TYPE naslock_{metric}_duration_seconds gauge");
+        for sample in of_action() {
+            let _ = writeln!(
+                out,
+                "naslock_{metric}_duration_seconds{} {:.3}",
+                labels(sample, false),
+                sample.duration.as_secs_f64()
+            );
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP naslock_last_run_timestamp_seconds Unix time at which the last run finished."
+    );
+    let _ = writeln!(out, "# TYPE naslock_last_run_timestamp_seconds gauge");
+    for sample in samples {
+        let timestamp = sample
+            .finished_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let _ = writeln!(
+            out,
+            "naslock_last_run_timestamp_seconds{} {}",
+            labels(sample, true),
+            timestamp.as_secs()
+        );
+    }
+
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("invalid metrics path {}", path.display()))?;
+    let mut tmp_name = file_name.to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    fs::write(&tmp_path, out)
+        .with_context(|| format!("failed to write metrics file {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("failed to write metrics file {}", path.display()))?;
+    Ok(())
+}
+
+fn labels(sample: &Sample<'_>, with_action: bool) -> String {
+    let mut labels = String::from("{");
+    if with_action {
+        let _ = write!(labels, "action=\"{}\",", escape(sample.action));
+    }
+    let _ = write!(
+        labels,
+        "volume=\"{}\",nas=\"{}\"}}",
+        escape(sample.volume),
+        escape(sample.nas)
+    );
+    labels
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,322 @@
+use crate::config::AuthMethod;
+use serde_json::Value;
+use std::fmt::Write as _;
+use std::path::Path;
+use url::Url;
+
+/// Environment variable the generated scripts read the dataset secret from.
+const SECRET_VAR: &str = "NASLOCK_UNLOCK_SECRET";
+/// Stands in for the secret in the body until the script-specific lookup is
+/// substituted.
+const SECRET_SENTINEL: &str = "@@NASLOCK_UNLOCK_SECRET@@";
+
+/// Prints `NASLOCK_UNLOCK_SECRET` escaped for a JSON string. awk is part of
+/// every POSIX system, unlike jq, and reading the secret from `ENVIRON` keeps
+/// shell quoting and newline stripping away from it. Control characters
+/// other than newline, carriage return and tab become `\u00XX`; NUL cannot
+/// occur in an environment variable.
+const JSON_ESCAPE_AWK: &str = r#"BEGIN {
+  for (i = 1; i < 32; i++) ctrl[sprintf("%c", i)] = sprintf("\\u%04x", i)
+  s = ENVIRON["NASLOCK_UNLOCK_SECRET"]
+  out = ""
+  for (i = 1; i <= length(s); i++) {
+    c = substr(s, i, 1)
+    if (c == "\\" || c == "\"") out = out "\\" c
+    else if (c == "\n") out = out "\\n"
+    else if (c == "\r") out = out "\\r"
+    else if (c == "\t") out = out "\\t"
+    else if (c in ctrl) out = out ctrl[c]
+    else out = out c
+  }
+  printf "%s", out
+}"#;
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum ScriptLanguage {
+    Sh,
+    Python,
+}
+
+/// Everything a generated unlock script needs; the secrets themselves are
+/// read from the environment when the script runs.
+pub struct UnlockScript<'a> {
+    pub url: &'a Url,
+    pub dataset: &'a str,
+    pub auth_method: AuthMethod,
+    /// Header name and scheme for `api_key` auth, from [`crate::truenas::api_key_header`].
+    pub api_key_header: (&'a str, Option<&'a str>),
+    pub skip_tls_verify: bool,
+    /// Certificate the script trusts instead of the system roots.
+    pub ca_cert: Option<&'a Path>,
+    /// The unlock request body with the secret field set to any value.
+    pub body: Value,
+    /// Name of the secret field in the body, `passphrase` or `key`.
+    pub secret_field: &'a str,
+    /// Written into the script as a comment below its description.
+    pub warning: Option<&'a str>,
+}
+
+impl UnlockScript<'_> {
+    pub fn generate(mut self, language: ScriptLanguage) -> String {
+        if let Some(entry) = self.body["unlock_options"]["datasets"].get_mut(0) {
+            entry[self.secret_field] = Value::String(SECRET_SENTINEL.to_string());
+        }
+        match language {
+            ScriptLanguage::Sh => self.shell(),
+            ScriptLanguage::Python => self.python(),
+        }
+    }
+
+    fn env_vars(&self) -> Vec<&'static str> {
+        let mut vars = vec![SECRET_VAR];
+        match self.auth_method {
+            AuthMethod::Basic => vars.extend(["TRUENAS_USERNAME", "TRUENAS_PASSWORD"]),
+            AuthMethod::ApiKey | AuthMethod::ApiKeyBasic => vars.push("TRUENAS_API_KEY"),
+        }
+        vars
+    }
+
+    fn shell(&self) -> String {
+        let mut out = String::from("#!/bin/sh\n");
+        let _ = writeln!(
+            out,
+            "# Unlocks {} on {} (generated by naslock gen-script).",
+            self.dataset,
+            self.url.origin().ascii_serialization()
+        );
+        if let Some(warning) = self.warning {
+            let _ = writeln!(out, "# Warning: {}", warning);
+        }
+        out.push_str("set -eu\n\n");
+        for var in self.env_vars() {
+            let _ = writeln!(out, ": \"${{{var}:?set {var}}}\"");
+        }
+
+        let _ = writeln!(out, "\nsecret=$(awk '{}')", JSON_ESCAPE_AWK);
+        let body = self.body.to_string();
+        let (before, after) = body.split_once(SECRET_SENTINEL).unwrap_or((&body, ""));
+        let _ = writeln!(
+            out,
+            "body={}\"$secret\"{}\n",
+            single_quoted(before),
+            single_quoted(after)
+        );
+
+        // The body goes through stdin so the secret never shows up in the
+        // process list.
+        out.push_str("printf '%s' \"$body\" | curl -fsS -X POST");
+        if self.skip_tls_verify {
+            out.push_str(" -k");
+        }
+        if let Some(path) = self.ca_cert {
+            let _ = write!(
+                out,
+                " --cacert {}",
+                single_quoted(&path.display().to_string())
+            );
+        }
+        out.push_str(
+            " \\\n  -H 'Accept: application/json' \\\n  -H 'Content-Type: application/json' \\\n",
+        );
+        match self.auth_method {
+            AuthMethod::Basic => {
+                out.push_str("  -u \"${TRUENAS_USERNAME}:${TRUENAS_PASSWORD}\" \\\n")
+            }
+            AuthMethod::ApiKey => {
+                let (name, scheme) = self.api_key_header;
+                let prefix = scheme
+                    .map(|scheme| format!("{} ", scheme))
+                    .unwrap_or_default();
+                let _ = writeln!(
+                    out,
+                    "  -H \"{}: {}${{TRUENAS_API_KEY}}\" \\",
+                    name,
+                    double_quoted(&prefix)
+                );
+            }
+            AuthMethod::ApiKeyBasic => out.push_str("  -u \"${TRUENAS_API_KEY}:\" \\\n"),
+        }
+        let _ = writeln!(
+            out,
+            "  --data @- \\\n  {}",
+            single_quoted(self.url.as_str())
+        );
+        out
+    }
+
+    fn python(&self) -> String {
+        // A JSON string literal is also a valid Python string literal.
+        let literal = |text: &str| Value::String(text.to_string()).to_string();
+
+        let mut out = String::from("#!/usr/bin/env python3\n");
+        let _ = writeln!(
+            out,
+            "\"\"\"Unlocks {} on {} (generated by naslock gen-script).\"\"\"",
+            self.dataset,
+            self.url.origin().ascii_serialization()
+        );
+        if let Some(warning) = self.warning {
+            let _ = writeln!(out, "# Warning: {}", warning);
+        }
+        out.push_str(
+            "import base64\nimport json\nimport os\nimport ssl\nimport urllib.request\n\n",
+        );
+        let _ = writeln!(out, "URL = {}", literal(self.url.as_str()));
+        let _ = writeln!(
+            out,
+            "body = json.loads({})",
+            literal(&self.body.to_string())
+        );
+        let _ = writeln!(
+            out,
+            "body[\"unlock_options\"][\"datasets\"][0][{}] = os.environ[{}]\n",
+            literal(self.secret_field),
+            literal(SECRET_VAR)
+        );
+
+        out.push_str(
+            "request = urllib.request.Request(URL, data=json.dumps(body).encode(), method=\"POST\")\n",
+        );
+        out.push_str("request.add_header(\"Accept\", \"application/json\")\n");
+        out.push_str("request.add_header(\"Content-Type\", \"application/json\")\n");
+        match self.auth_method {
+            AuthMethod::Basic => out.push_str(
+                "credentials = os.environ[\"TRUENAS_USERNAME\"] + \":\" + os.environ[\"TRUENAS_PASSWORD\"]\n\
+                 request.add_header(\"Authorization\", \"Basic \" + base64.b64encode(credentials.encode()).decode())\n",
+            ),
+            AuthMethod::ApiKey => {
+                let (name, scheme) = self.api_key_header;
+                let prefix = scheme.map(|scheme| format!("{} ", scheme)).unwrap_or_default();
+                let _ = writeln!(
+                    out,
+                    "request.add_header({}, {} + os.environ[\"TRUENAS_API_KEY\"])",
+                    literal(name),
+                    literal(&prefix)
+                );
+            }
+            AuthMethod::ApiKeyBasic => out.push_str(
+                "credentials = os.environ[\"TRUENAS_API_KEY\"] + \":\"\n\
+                 request.add_header(\"Authorization\", \"Basic \" + base64.b64encode(credentials.encode()).decode())\n",
+            ),
+        }
+
+        if self.skip_tls_verify {
+            out.push_str("\ncontext = ssl._create_unverified_context()\n");
+        } else if let Some(path) = self.ca_cert {
+            let _ = writeln!(
+                out,
+                "\ncontext = ssl.create_default_context(cafile={})",
+                literal(&path.display().to_string())
+            );
+        } else {
+            out.push_str("\ncontext = ssl.create_default_context()\n");
+        }
+        out.push_str(
+            "with urllib.request.urlopen(request, context=context) as response:\n    print(response.read().decode())\n",
+        );
+        out
+    }
+}
+
+/// Quotes `text` as a single-quoted shell word.
+fn single_quoted(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+/// Escapes `text` for use inside a double-quoted shell string.
+fn double_quoted(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for ch in text.chars() {
+        if matches!(ch, '"' | '\\' | '$' | '`') {
+            out.push('\\');
+        }
+        out.push(ch);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::truenas::{self, UnlockOptions, UnlockSecret};
+
+    fn script(url: &Url) -> UnlockScript<'_> {
+        let body = truenas::build_unlock_body(
+            "tank/media",
+            UnlockSecret::Passphrase(""),
+            &[],
+            UnlockOptions {
+                recursive: true,
+                force: false,
+                toggle_attachments: true,
+            },
+        );
+        UnlockScript {
+            url,
+            dataset: "tank/media",
+            auth_method: AuthMethod::ApiKey,
+            api_key_header: ("Authorization", Some("Bearer")),
+            skip_tls_verify: false,
+            ca_cert: None,
+            body: serde_json::to_value(body).unwrap(),
+            secret_field: "passphrase",
+            warning: None,
+        }
+    }
+
+    /// Runs the generated shell script with `curl` replaced by a function
+    /// that prints its arguments to stderr and its stdin to stdout, and
+    /// returns the body it would send. Fails if the secret is in the
+    /// arguments.
+    #[cfg(unix)]
+    fn shell_body(secret: &str) -> Value {
+        let url = Url::parse("https://nas.local/api/v2.0/pool/dataset/unlock").unwrap();
+        let generated = script(&url).generate(ScriptLanguage::Sh);
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!(
+                "curl() {{ printf '%s\\n' \"$@\" >&2; cat; }}\n{}",
+                generated
+            ))
+            .env(SECRET_VAR, secret)
+            .env("TRUENAS_API_KEY", "1-abc")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        let args = String::from_utf8_lossy(&output.stderr);
+        assert!(args.contains("--data\n@-\n"), "{}", args);
+        assert!(!args.contains(secret.trim()), "{:?} in {}", secret, args);
+        serde_json::from_slice(&output.stdout).unwrap()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shell_script_escapes_the_secret() {
+        for secret in [
+            "plain",
+            r#"with "quotes""#,
+            r"back\slash\",
+            "it's $HOME `id` $(id)",
+            "tab\tand\nnewlines\n",
+            "bell\x07 escape\x1b[0m unit\x1f form\x0c",
+            "ünïcödé ✓",
+        ] {
+            let body = shell_body(secret);
+            assert_eq!(
+                body["unlock_options"]["datasets"][0]["passphrase"], secret,
+                "{:?}",
+                secret
+            );
+            assert_eq!(body["id"], "tank/media");
+        }
+    }
+
+    #[test]
+    fn python_script_reads_the_secret_from_the_environment() {
+        let url = Url::parse("https://nas.local/api/v2.0/pool/dataset/unlock").unwrap();
+        let generated = script(&url).generate(ScriptLanguage::Python);
+        assert!(generated.contains(
+            r#"body["unlock_options"]["datasets"][0]["passphrase"] = os.environ["NASLOCK_UNLOCK_SECRET"]"#
+        ));
+    }
+}
@@ -0,0 +1,579 @@
+use crate::config::{self, Config, KeepassDatabase, NasConfig, VolumeConfig};
+use crate::error::{ErrorKind, ResultExt};
+use crate::keepass_store::{
+    KeePassStore, SearchRoot, ensure_non_empty, require_entry, required_field, totp_seed,
+};
+use crate::{kdf, truenas};
+use anyhow::{Context, Result, bail};
+use base64::Engine;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use totp_rs::TOTP;
+use zeroize::Zeroizing;
+
+/// NAS credentials read from KeePass, a command or the environment.
+pub enum StoredAuth {
+    Basic {
+        username: Zeroizing<String>,
+        password: Zeroizing<String>,
+        totp: Option<TOTP>,
+    },
+    ApiKey {
+        key: Zeroizing<String>,
+        header: Option<String>,
+        scheme: Option<String>,
+    },
+    ApiKeyBasic {
+        key: Zeroizing<String>,
+    },
+}
+
+impl StoredAuth {
+    /// An API key sent in the header `nas` asks for.
+    pub fn api_key(nas: &NasConfig, key: Zeroizing<String>) -> Self {
+        StoredAuth::ApiKey {
+            key,
+            header: nas.api_key_header.clone(),
+            scheme: nas.api_key_scheme.clone(),
+        }
+    }
+
+    pub fn as_auth(&self) -> truenas::Auth<'_> {
+        match self {
+            StoredAuth::Basic {
+                username,
+                password,
+                totp,
+            } => truenas::Auth::Basic {
+                username: username.as_str(),
+                password: password.as_str(),
+                totp: totp.as_ref(),
+            },
+            StoredAuth::ApiKey {
+                key,
+                header,
+                scheme,
+            } => truenas::Auth::ApiKey {
+                key: key.as_str(),
+                header: header.as_deref(),
+                scheme: scheme.as_deref(),
+            },
+            StoredAuth::ApiKeyBasic { key } => truenas::Auth::ApiKeyBasic { key: key.as_str() },
+        }
+    }
+
+    /// Refuses a password or API key that is still one of the config's
+    /// `placeholder_secrets`.
+    pub fn reject_placeholder(&self, cfg: &Config) -> Result<()> {
+        let (what, secret) = match self {
+            StoredAuth::Basic { password, .. } => ("NAS password", password),
+            StoredAuth::ApiKey { key, .. } | StoredAuth::ApiKeyBasic { key } => ("API key", key),
+        };
+        reject_placeholder(cfg, what, secret).kind(ErrorKind::Config)
+    }
+}
+
+/// The KeePass databases opened for a run, one per distinct file.
+pub struct Stores(Vec<(PathBuf, KeePassStore)>);
+
+impl Stores {
+    /// Opens each of `databases` with the master password `password` returns
+    /// for its path.
+    pub fn open(
+        databases: &[KeepassDatabase<'_>],
+        mut password: impl FnMut(&Path) -> Result<Zeroizing<String>>,
+    ) -> Result<Self> {
+        let stores = databases
+            .iter()
+            .map(|database| {
+                let master_password = password(database.path)?;
+                let store = KeePassStore::open(database.path, database.key_file, &master_password)
+                    .kind(ErrorKind::KeePass)?;
+                Ok((database.path.to_path_buf(), store))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Stores(stores))
+    }
+
+    pub fn get(&self, database: KeepassDatabase<'_>) -> Result<&KeePassStore> {
+        self.0
+            .iter()
+            .find(|(path, _)| path == database.path)
+            .map(|(_, store)| store)
+            .with_context(|| {
+                format!(
+                    "KeePass database {} was not opened",
+                    database.path.display()
+                )
+            })
+    }
+
+    /// The NAS credentials, from `auth_command` or its KeePass entry.
+    pub fn auth(&self, cfg: &Config, nas: &NasConfig) -> Result<StoredAuth> {
+        let stored_auth = match &nas.auth_command {
+            Some(command) => command_auth(nas, command).kind(ErrorKind::Auth)?,
+            None => resolve_auth(self.get(cfg.nas_database(nas))?, search_root(cfg, nas), nas)
+                .kind(ErrorKind::KeePass)?,
+        };
+        stored_auth.reject_placeholder(cfg)?;
+        Ok(stored_auth)
+    }
+
+    /// The secret that unlocks the volume's dataset, from `unlock_command` or
+    /// its KeePass entry, after any `derive` step.
+    pub fn unlock_secret(
+        &self,
+        cfg: &Config,
+        nas: &NasConfig,
+        volume: &VolumeConfig,
+    ) -> Result<Zeroizing<String>> {
+        let secret = match &volume.unlock_command {
+            Some(command) => {
+                let secret = command_secret("unlock_command", command)?;
+                ensure_non_empty(secret.as_str(), "unlock secret")?;
+                secret
+            }
+            None => load_unlock_secret(
+                self.get(cfg.volume_database(nas, volume))?,
+                search_root(cfg, nas),
+                volume,
+                &volume.unlock_entry,
+                &volume.unlock_field,
+                &volume.dataset,
+            )?,
+        };
+        prepare_secret(cfg, volume, &volume.dataset, secret)
+    }
+
+    /// The secret in another entry's `field` (the volume's `unlock_field` when
+    /// `None`), read and prepared as the volume's own secret would be.
+    pub fn entry_secret(
+        &self,
+        cfg: &Config,
+        nas: &NasConfig,
+        volume: &VolumeConfig,
+        entry: &str,
+        field: Option<&str>,
+    ) -> Result<Zeroizing<String>> {
+        let secret = load_unlock_secret(
+            self.get(cfg.volume_database(nas, volume))?,
+            search_root(cfg, nas),
+            volume,
+            entry,
+            field.unwrap_or(&volume.unlock_field),
+            &volume.dataset,
+        )?;
+        prepare_secret(cfg, volume, &volume.dataset, secret)
+    }
+
+    /// The secrets for the volume's `children`, in order.
+    pub fn child_secrets(
+        &self,
+        cfg: &Config,
+        nas: &NasConfig,
+        volume: &VolumeConfig,
+    ) -> Result<Vec<Zeroizing<String>>> {
+        if volume.children.is_empty() {
+            return Ok(Vec::new());
+        }
+        let store = self.get(cfg.volume_database(nas, volume))?;
+        volume
+            .children
+            .iter()
+            .map(|child| {
+                let secret = load_unlock_secret(
+                    store,
+                    search_root(cfg, nas),
+                    volume,
+                    &child.unlock_entry,
+                    &child.unlock_field,
+                    &child.dataset,
+                )?;
+                prepare_secret(cfg, volume, &child.dataset, secret)
+            })
+            .collect()
+    }
+}
+
+/// The distinct KeePass databases the named volumes need: their NAS's
+/// credentials unless `auth_command` provides them and, with `unlock`, their
+/// unlock secrets unless `unlock_command` does. Unknown names are skipped
+/// here and reported when the volume itself is resolved.
+pub fn databases<'c>(
+    cfg: &'c Config,
+    names: &[impl AsRef<str>],
+    unlock: bool,
+) -> Vec<KeepassDatabase<'c>> {
+    let mut databases: Vec<KeepassDatabase<'c>> = Vec::new();
+    for name in names {
+        let Ok((volume, nas)) = cfg.resolve_volume(name.as_ref()) else {
+            continue;
+        };
+        // A volume with `unlock_command` only reads its children's secrets
+        // from KeePass.
+        let from_keepass = volume.unlock_command.is_none() || !volume.children.is_empty();
+        let needed = [
+            nas.auth_command.is_none().then(|| cfg.nas_database(nas)),
+            (unlock && from_keepass).then(|| cfg.volume_database(nas, volume)),
+        ];
+        for database in needed.into_iter().flatten() {
+            if !databases.iter().any(|known| known.path == database.path) {
+                databases.push(database);
+            }
+        }
+    }
+    databases
+}
+
+/// Where the NAS's entry selectors are resolved: its own `base_group` or
+/// the one in `[keepass]`.
+pub fn search_root<'a>(cfg: &'a Config, nas: &'a NasConfig) -> SearchRoot<'a> {
+    SearchRoot {
+        base_group: nas
+            .base_group
+            .as_deref()
+            .or(cfg.keepass.base_group.as_deref()),
+        fallback: cfg.keepass.base_group_fallback,
+    }
+}
+
+/// Reads the secret for `dataset` from `entry_name`, in the volume's
+/// `unlock_mode`.
+fn load_unlock_secret(
+    store: &KeePassStore,
+    root: SearchRoot<'_>,
+    volume: &VolumeConfig,
+    entry_name: &str,
+    field: &str,
+    dataset: &str,
+) -> Result<Zeroizing<String>> {
+    let unlock_entry = require_entry(store, root, entry_name).kind(ErrorKind::KeePass)?;
+
+    if let config::UnlockMode::KeyFileAttachment = volume.unlock_mode {
+        let key_file = store
+            .attachment(unlock_entry, field)
+            .with_context(|| {
+                format!(
+                    "missing attachment '{}' in KeePass entry {}",
+                    field, entry_name
+                )
+            })
+            .kind(ErrorKind::KeePass)?;
+        return key_from_key_file(key_file, dataset).kind(ErrorKind::KeePass);
+    }
+
+    let unlock_secret_value =
+        required_field(store, unlock_entry, field, entry_name).kind(ErrorKind::KeePass)?;
+    ensure_non_empty(unlock_secret_value.as_str(), "unlock secret").kind(ErrorKind::KeePass)?;
+    Ok(unlock_secret_value)
+}
+
+/// Extracts the hex key from a key file: 32 raw bytes, a 64-character hex
+/// key, or a TrueNAS key export mapping dataset names to hex keys.
+fn key_from_key_file(content: &[u8], dataset: &str) -> Result<Zeroizing<String>> {
+    if content.len() == 32 {
+        return Ok(Zeroizing::new(hex::encode(content)));
+    }
+    let text = std::str::from_utf8(content)
+        .ok()
+        .map(str::trim)
+        .context("key file is neither a 32-byte raw key nor text")?;
+    if text.len() == 64 && text.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Ok(Zeroizing::new(text.to_string()));
+    }
+    let keys: serde_json::Map<String, serde_json::Value> = serde_json::from_str(text)
+        .context("key file is not a raw key, a hex key or a TrueNAS key export")?;
+    let key = keys
+        .get(dataset)
+        .with_context(|| format!("key file has no key for dataset {}", dataset))?
+        .as_str()
+        .with_context(|| format!("key for dataset {} in key file is not a string", dataset))?;
+    Ok(Zeroizing::new(key.to_string()))
+}
+
+/// Refuses template values such as `CHANGEME` that were never replaced with
+/// the real secret. Matching is case-insensitive on the whole value.
+fn reject_placeholder(cfg: &Config, what: &str, secret: &str) -> Result<()> {
+    let secret = secret.trim();
+    if let Some(placeholder) = cfg
+        .placeholder_secrets
+        .iter()
+        .find(|placeholder| placeholder.eq_ignore_ascii_case(secret))
+    {
+        bail!("{} appears to be a placeholder: {}", what, placeholder);
+    }
+    Ok(())
+}
+
+/// Rejects a placeholder secret, then applies the volume's `derive` step, if
+/// any, and checks the result against `unlock_mode`.
+pub fn prepare_secret(
+    cfg: &Config,
+    volume: &VolumeConfig,
+    dataset: &str,
+    unlock_secret_value: Zeroizing<String>,
+) -> Result<Zeroizing<String>> {
+    reject_placeholder(cfg, "unlock secret", &unlock_secret_value).kind(ErrorKind::Config)?;
+    let unlock_secret_value = match volume.unlock_mode {
+        config::UnlockMode::ChallengeResponse => {
+            challenge_response(&volume.challenge_response_command, &unlock_secret_value)?
+        }
+        _ => unlock_secret_value,
+    };
+    let secret = match &volume.derive {
+        Some(derive) => {
+            kdf::derive_passphrase(derive, unlock_secret_value.as_str()).kind(ErrorKind::Config)?
+        }
+        None => unlock_secret_value,
+    };
+    check_secret_shape(volume, dataset, secret.as_str()).kind(ErrorKind::Config)?;
+    Ok(secret)
+}
+
+/// Has `command`, typically `ykchalresp`, answer `challenge`. The challenge
+/// is written to the command's stdin, and its output with the trailing
+/// newline stripped, the HMAC-SHA1 response in hex, is the passphrase.
+fn challenge_response(command: &[String], challenge: &str) -> Result<Zeroizing<String>> {
+    let Some((program, args)) = command.split_first() else {
+        bail!("challenge_response_command must not be empty");
+    };
+    let mut child = std::process::Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::inherit())
+        .spawn()
+        .with_context(|| format!("failed to run challenge_response_command '{}'", program))?;
+    // A command that fails before reading the challenge closes the pipe;
+    // its exit status explains that better than the write error.
+    let written = child
+        .stdin
+        .take()
+        .context("challenge_response_command has no stdin")?
+        .write_all(challenge.as_bytes());
+    let output = child
+        .wait_with_output()
+        .with_context(|| format!("failed to run challenge_response_command '{}'", program))?;
+    let stdout = Zeroizing::new(output.stdout);
+    if !output.status.success() {
+        bail!(
+            "challenge_response_command '{}' exited with {}",
+            program,
+            output.status
+        );
+    }
+    written.with_context(|| format!("failed to send the challenge to '{}'", program))?;
+    let response = std::str::from_utf8(&stdout)
+        .with_context(|| {
+            format!(
+                "challenge_response_command '{}' printed invalid UTF-8",
+                program
+            )
+        })?
+        .trim_end_matches(['\r', '\n']);
+    ensure_non_empty(response, "challenge response")?;
+    Ok(Zeroizing::new(response.to_string()))
+}
+
+/// Catches an `unlock_mode` that does not match the stored secret before the
+/// API rejects it: raw keys are 64 hex characters (or base64 of 32 bytes),
+/// and a passphrase that looks exactly like one was probably meant as a key.
+fn check_secret_shape(volume: &VolumeConfig, dataset: &str, secret: &str) -> Result<()> {
+    let is_hex_key = secret.len() == 64 && secret.chars().all(|c| c.is_ascii_hexdigit());
+    let problem = match volume.unlock_mode {
+        config::UnlockMode::Key | config::UnlockMode::KeyFileAttachment => {
+            let is_base64_key = base64::engine::general_purpose::STANDARD
+                .decode(secret)
+                .is_ok_and(|bytes| bytes.len() == 32);
+            (!is_hex_key && !is_base64_key)
+                .then_some("unlock_mode is \"key\" but the secret is not a 64-character hex key")
+        }
+        config::UnlockMode::Passphrase => (is_hex_key && volume.derive.is_none()).then_some(
+            "unlock secret looks like a 64-character hex key; did you mean unlock_mode = \"key\"?",
+        ),
+        config::UnlockMode::ChallengeResponse => None,
+    };
+
+    let Some(problem) = problem else {
+        return Ok(());
+    };
+    if volume.strict_secret_check {
+        bail!("{} for dataset {}", problem, dataset);
+    }
+    tracing::warn!("{} for dataset {}", problem, dataset);
+    Ok(())
+}
+
+/// Runs `auth_command` and uses its stdout, without the trailing newline, as
+/// the API key. The command's stderr is passed through; its output is never
+/// echoed.
+fn command_auth(nas: &NasConfig, command: &[String]) -> Result<StoredAuth> {
+    let key = command_secret("auth_command", command)?;
+    ensure_non_empty(key.as_str(), "API key")?;
+
+    match nas.auth_method {
+        config::AuthMethod::Basic => {
+            bail!(
+                "auth_command provides an API key; set auth_method to \"api_key\" or \"api_key_basic\""
+            )
+        }
+        config::AuthMethod::ApiKey => Ok(StoredAuth::api_key(nas, key)),
+        config::AuthMethod::ApiKeyBasic => Ok(StoredAuth::ApiKeyBasic { key }),
+    }
+}
+
+/// Runs the `option` command, e.g. `auth_command`, and returns its stdout
+/// without the trailing newline. The command's stderr is passed through.
+fn command_secret(option: &str, command: &[String]) -> Result<Zeroizing<String>> {
+    let Some((program, args)) = command.split_first() else {
+        bail!("{} must not be empty", option);
+    };
+    let output = std::process::Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .with_context(|| format!("failed to run {} '{}'", option, program))?;
+    let stdout = Zeroizing::new(output.stdout);
+    if !output.status.success() {
+        bail!("{} '{}' exited with {}", option, program, output.status);
+    }
+
+    let text = std::str::from_utf8(&stdout)
+        .with_context(|| format!("{} '{}' printed invalid UTF-8", option, program))?;
+    Ok(Zeroizing::new(
+        text.trim_end_matches(['\r', '\n']).to_string(),
+    ))
+}
+
+fn resolve_auth(store: &KeePassStore, root: SearchRoot<'_>, nas: &NasConfig) -> Result<StoredAuth> {
+    if nas.auth_entry.is_empty() {
+        bail!("NAS config needs either auth_entry or auth_command");
+    }
+    let auth_entry = require_entry(store, root, &nas.auth_entry)?;
+
+    let stored_auth = match nas.auth_method {
+        config::AuthMethod::Basic => {
+            let username = required_field(store, auth_entry, &nas.username_field, &nas.auth_entry)?;
+            let password = required_field(store, auth_entry, &nas.password_field, &nas.auth_entry)?;
+            ensure_non_empty(username.as_str(), "NAS username")?;
+            ensure_non_empty(password.as_str(), "NAS password")?;
+            let totp = nas
+                .totp_field
+                .as_deref()
+                .map(|field| totp_seed(store, auth_entry, field, &nas.auth_entry))
+                .transpose()?;
+            StoredAuth::Basic {
+                username,
+                password,
+                totp,
+            }
+        }
+        config::AuthMethod::ApiKey => {
+            let key = required_field(store, auth_entry, &nas.password_field, &nas.auth_entry)?;
+            ensure_non_empty(key.as_str(), "API key")?;
+            StoredAuth::api_key(nas, key)
+        }
+        config::AuthMethod::ApiKeyBasic => {
+            let key = required_field(store, auth_entry, &nas.password_field, &nas.auth_entry)?;
+            ensure_non_empty(key.as_str(), "API key")?;
+            StoredAuth::ApiKeyBasic { key }
+        }
+    };
+
+    Ok(stored_auth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(extra: &str) -> Config {
+        let text = format!(
+            r#"
+[keepass]
+path = "/secrets/shared.kdbx"
+
+[nas.home]
+host = "nas-home.local"
+auth_entry = "home login"
+
+[nas.office]
+host = "nas-office.local"
+auth_entry = "office login"
+
+[volume.media]
+nas = "home"
+dataset = "tank/media"
+unlock_entry = "media"
+
+[volume.backup]
+nas = "office"
+dataset = "tank/backup"
+unlock_entry = "backup"
+{}"#,
+            extra
+        );
+        Config::from_table(toml::from_str(&text).unwrap()).unwrap()
+    }
+
+    fn paths(databases: &[KeepassDatabase<'_>]) -> Vec<PathBuf> {
+        databases.iter().map(|db| db.path.to_path_buf()).collect()
+    }
+
+    #[test]
+    fn volumes_on_several_nas_share_one_database() {
+        let cfg = config("");
+        for unlock in [true, false] {
+            let databases = databases(&cfg, &["media", "backup"], unlock);
+            assert_eq!(paths(&databases), [PathBuf::from("/secrets/shared.kdbx")]);
+        }
+    }
+
+    #[test]
+    fn nas_with_its_own_database_adds_one() {
+        let cfg = config(
+            r#"
+[nas.lab]
+host = "nas-lab.local"
+auth_entry = "lab login"
+keepass_path = "/secrets/lab.kdbx"
+
+[volume.scratch]
+nas = "lab"
+dataset = "tank/scratch"
+unlock_entry = "scratch"
+"#,
+        );
+        let databases = databases(&cfg, &["media", "scratch", "backup", "unknown"], true);
+        assert_eq!(
+            paths(&databases),
+            [
+                PathBuf::from("/secrets/shared.kdbx"),
+                PathBuf::from("/secrets/lab.kdbx"),
+            ]
+        );
+    }
+
+    #[test]
+    fn key_file_formats() {
+        let hex = "ab".repeat(32);
+        assert_eq!(*key_from_key_file(&[0xab; 32], "tank/a").unwrap(), hex);
+        assert_eq!(
+            *key_from_key_file(format!("{}\n", hex).as_bytes(), "tank/a").unwrap(),
+            hex
+        );
+
+        let export = format!(r#"{{"tank/a": "{}", "tank/b": "cd"}}"#, hex);
+        assert_eq!(
+            *key_from_key_file(export.as_bytes(), "tank/a").unwrap(),
+            hex
+        );
+        let single = format!(r#"{{"tank/a": "{}"}}"#, hex);
+        let err = key_from_key_file(single.as_bytes(), "tank/other").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "key file has no key for dataset tank/other"
+        );
+    }
+}
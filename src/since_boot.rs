@@ -0,0 +1,54 @@
+use anyhow::{Context, Result};
+use directories::BaseDirs;
+use std::fs;
+use std::path::PathBuf;
+
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+
+/// Records that a volume was unlocked during the current boot, keyed by the
+/// kernel boot id so markers from earlier boots never match.
+pub struct BootMarker {
+    path: PathBuf,
+}
+
+impl BootMarker {
+    pub fn for_volume(volume: &str) -> Result<Self> {
+        let boot_id = fs::read_to_string(BOOT_ID_PATH)
+            .with_context(|| format!("--since-boot needs a boot id from {}", BOOT_ID_PATH))?;
+        let boot_id = boot_id.trim();
+        let name: String = volume
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        Ok(Self {
+            path: state_dir()?
+                .join(format!("boot-{}", boot_id))
+                .join(format!("{}.unlocked", name)),
+        })
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.is_file()
+    }
+
+    pub fn record(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+        fs::write(&self.path, b"")
+            .with_context(|| format!("failed to write boot marker {}", self.path.display()))
+    }
+}
+
+fn state_dir() -> Result<PathBuf> {
+    let base = BaseDirs::new().context("unable to determine home directory")?;
+    let dir = base.runtime_dir().unwrap_or_else(|| base.cache_dir());
+    Ok(dir.join("naslock"))
+}
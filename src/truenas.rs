@@ -1,10 +1,21 @@
+use crate::config::Flavor;
+use crate::error::{self, ErrorKind};
 use anyhow::{Context, Result, bail};
-use reqwest::blocking::{Client, ClientBuilder};
-use reqwest::header::{ACCEPT, AUTHORIZATION, HeaderMap, HeaderValue};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use reqwest::StatusCode;
+use reqwest::blocking::{Body, Client, ClientBuilder, RequestBuilder, Response};
+use reqwest::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, HeaderMap, HeaderValue, LOCATION};
+use reqwest::redirect::Policy;
+use reqwest::{Identity, Proxy};
 use serde::Serialize;
 use serde_json::{Value, json};
-use std::time::Duration;
+use std::fmt;
+use std::io::IsTerminal;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use totp_rs::TOTP;
 use url::Url;
+use zeroize::Zeroizing;
 
 #[derive(Clone, Copy)]
 pub enum UnlockSecret<'a> {
@@ -24,16 +35,49 @@ pub enum Auth<'a> {
     Basic {
         username: &'a str,
         password: &'a str,
+        /// TOTP seed; the current code is computed and sent with the
+        /// websocket login.
+        totp: Option<&'a TOTP>,
     },
     ApiKey {
         key: &'a str,
+        /// Header to send the key in instead of `Authorization`.
+        header: Option<&'a str>,
+        /// Scheme in front of the key; see [`api_key_header`].
+        scheme: Option<&'a str>,
     },
+    ApiKeyBasic {
+        key: &'a str,
+    },
+    /// Session token from `auth.generate_token`.
+    Token {
+        token: &'a str,
+    },
+}
+
+/// The header name and scheme an API key is sent with. The scheme defaults
+/// to `Bearer` in the `Authorization` header and to none in a custom header;
+/// an empty scheme sends the bare key.
+pub fn api_key_header<'a>(
+    header: Option<&'a str>,
+    scheme: Option<&'a str>,
+) -> (&'a str, Option<&'a str>) {
+    let name = header.unwrap_or("Authorization");
+    let scheme = match scheme {
+        Some(scheme) => Some(scheme).filter(|scheme| !scheme.is_empty()),
+        None => name
+            .eq_ignore_ascii_case("authorization")
+            .then_some("Bearer"),
+    };
+    (name, scheme)
 }
 
 #[derive(Default)]
 pub struct UnlockResult {
     pub job_id: Option<i64>,
     pub unlocked: Vec<String>,
+    /// Datasets left alone because they were already unlocked.
+    pub skipped: Vec<String>,
     pub failed: Vec<(String, String)>,
     pub message: Option<String>,
 }
@@ -42,6 +86,7 @@ pub struct UnlockResult {
 pub struct LockResult {
     pub job_id: Option<i64>,
     pub locked: bool,
+    pub already_locked: bool,
     pub message: Option<String>,
 }
 
@@ -53,170 +98,1215 @@ pub struct JobInfo {
     pub exception: Option<String>,
     pub progress_percent: Option<f64>,
     pub progress_description: Option<String>,
+    pub time_started: Option<SystemTime>,
+    pub time_finished: Option<SystemTime>,
+    /// What the job returned; for an unlock, see [`unlock_details`].
+    pub result: Option<Value>,
+    /// Wall-clock time spent in `wait_for_job`, measured locally.
+    pub waited: Duration,
 }
 
-pub fn build_client(skip_tls_verify: bool) -> Result<Client> {
+/// The `User-Agent` sent when the NAS sets no `user_agent`.
+pub const DEFAULT_USER_AGENT: &str = concat!("naslock/", env!("CARGO_PKG_VERSION"));
+const REDACTED: &str = "<redacted>";
+pub const UNLOCK_PATH: &str = "api/v2.0/pool/dataset/unlock";
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize)]
+pub struct RenderedRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+#[derive(Clone, Copy)]
+pub struct ClientOptions<'a> {
+    pub skip_tls_verify: bool,
+    pub max_redirects: usize,
+    pub allow_cross_host_redirect: bool,
+    /// PEM file with the certificate to trust instead of the built-in roots.
+    pub ca_cert: Option<&'a Path>,
+    /// `http`, `https` or `socks5` proxy URL for every request, instead of
+    /// the `HTTPS_PROXY`/`HTTP_PROXY`/`ALL_PROXY` environment variables.
+    pub proxy: Option<&'a str>,
+    /// Certificate and private key PEM files for mutual TLS.
+    pub client_identity: Option<(&'a Path, &'a Path)>,
+    /// Overall limit per request, including reading the response.
+    pub timeout: Duration,
+    /// Limit for establishing the connection; capped at `timeout`.
+    pub connect_timeout: Duration,
+    pub user_agent: &'a str,
+}
+
+#[derive(Clone, Copy)]
+pub struct WaitOptions {
+    pub show_progress: bool,
+    pub poll_interval: Duration,
+    /// Fail once the job has run this long without finishing.
+    pub timeout: Option<Duration>,
+}
+
+/// A non-success HTTP status returned by the TrueNAS API, or a successful
+/// response whose body is a page from a proxy rather than the API.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    /// The response's `Content-Type`, if it had one.
+    pub content_type: Option<String>,
+    pub body: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, content_type: Option<String>, body: String) -> Self {
+        let error = ApiError {
+            status,
+            content_type,
+            body,
+        };
+        if error.is_proxy_page() {
+            tracing::debug!(%status, body = error.body.trim(), "non-JSON error response");
+        }
+        error
+    }
+
+    /// Whether the body is a page from something in front of the API, such
+    /// as a reverse proxy's HTML 502 page, rather than an error from TrueNAS.
+    /// Those bodies are left out of the message.
+    fn is_proxy_page(&self) -> bool {
+        is_proxy_page(self.content_type.as_deref(), &self.body)
+    }
+}
+
+/// Whether a response body is a page from something in front of the API
+/// rather than JSON or a short message from TrueNAS. Successful responses are
+/// checked too, since a proxy or single sign-on portal answers with a `200`
+/// login page.
+fn is_proxy_page(content_type: Option<&str>, body: &str) -> bool {
+    let body = body.trim_start();
+    match content_type.map(str::to_ascii_lowercase) {
+        Some(content_type) if content_type.contains("json") => false,
+        Some(content_type) if content_type.starts_with("text/plain") => {
+            body.len() > 200 || body.trim_end().contains('\n')
+        }
+        Some(_) => true,
+        None => body.starts_with('<'),
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let body = self.body.trim();
+        if body.is_empty() {
+            return write!(f, "TrueNAS API error ({}): empty response", self.status);
+        }
+        if self.is_proxy_page() {
+            return write!(
+                f,
+                "NAS returned a non-JSON {} response; the API may be down or proxied incorrectly",
+                self.status
+            );
+        }
+        write!(f, "TrueNAS API error ({}): {}", self.status, body)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+#[derive(Debug, Default, Clone)]
+pub struct DatasetInfo {
+    pub encrypted: bool,
+    pub locked: bool,
+    /// The dataset is a zvol (`"type": "VOLUME"`) rather than a filesystem.
+    pub zvol: bool,
+    /// Whether the filesystem is mounted, taken from `mounted` or, on
+    /// versions without it, from whether it has a `mountpoint`. `None` when
+    /// the NAS reports neither.
+    pub mounted: Option<bool>,
+    /// Encrypted datasets below this one that are still locked, taken from
+    /// the nested `children` the query returns.
+    pub locked_descendants: Vec<String>,
+}
+
+/// A connection to the TrueNAS API that can carry out the dataset operations.
+pub trait Transport {
+    /// Unlocks `dataset` together with any `children`, each of which carries
+    /// its own secret.
+    fn unlock(
+        &mut self,
+        dataset: &str,
+        secret: UnlockSecret<'_>,
+        children: &[(&str, UnlockSecret<'_>)],
+        options: UnlockOptions,
+    ) -> Result<UnlockResult>;
+    fn lock(&mut self, dataset: &str, force_umount: bool) -> Result<LockResult>;
+    /// Sets a new passphrase or key on the unlocked `dataset` and returns the
+    /// id of the job doing it.
+    fn change_key(&mut self, dataset: &str, secret: UnlockSecret<'_>) -> Result<i64>;
+    fn dataset(&mut self, dataset: &str) -> Result<DatasetInfo>;
+    fn wait_for_job(&mut self, job_id: i64, wait: WaitOptions) -> Result<JobInfo>;
+}
+
+/// The REST API under `/api/v2.0`, one HTTP request per call.
+pub struct Rest<'a> {
+    pub client: Client,
+    pub base_url: Url,
+    pub auth: Auth<'a>,
+    pub flavor: Flavor,
+    pub max_retries: u32,
+    pub session: RestSession,
+}
+
+/// How a [`Rest`] transport authenticates its requests. With basic auth the
+/// first call trades the username and password for a session token, so the
+/// password is not sent again with every job poll.
+pub enum RestSession {
+    /// Nothing sent yet; start here.
+    Pending,
+    /// Requests carry this token.
+    Token(Zeroizing<String>),
+    /// Requests carry the configured credentials, because they are an API key
+    /// or the NAS offers no token login.
+    Inline,
+}
+
+/// Seconds a session token stays valid without being used.
+const SESSION_TOKEN_TTL_SECS: u64 = 600;
+
+impl Rest<'_> {
+    /// Logs in for a session token on the first call with basic auth. A NAS
+    /// that refuses the login for any reason other than the credentials gets
+    /// them inline instead.
+    fn start_session(&mut self) -> Result<()> {
+        if !matches!(self.session, RestSession::Pending) {
+            return Ok(());
+        }
+        if !matches!(self.auth, Auth::Basic { .. }) {
+            self.session = RestSession::Inline;
+            return Ok(());
+        }
+        self.session = match generate_token(&self.client, &self.base_url, self.auth) {
+            Ok(token) => {
+                tracing::debug!("using a session token");
+                RestSession::Token(token)
+            }
+            Err(err) if matches!(error::classify(&err), ErrorKind::Auth | ErrorKind::Network) => {
+                return Err(err);
+            }
+            Err(err) => {
+                tracing::debug!(
+                    "no session token, sending credentials with each request: {:#}",
+                    err
+                );
+                RestSession::Inline
+            }
+        };
+        Ok(())
+    }
+
+    /// The credentials for the next request, after [`Rest::start_session`].
+    fn request_auth(&self) -> Auth<'_> {
+        match &self.session {
+            RestSession::Token(token) => Auth::Token { token },
+            RestSession::Pending | RestSession::Inline => self.auth,
+        }
+    }
+}
+
+impl Transport for Rest<'_> {
+    fn unlock(
+        &mut self,
+        dataset: &str,
+        secret: UnlockSecret<'_>,
+        children: &[(&str, UnlockSecret<'_>)],
+        options: UnlockOptions,
+    ) -> Result<UnlockResult> {
+        self.start_session()?;
+        unlock_dataset(
+            &self.client,
+            &self.base_url,
+            self.request_auth(),
+            dataset,
+            &build_unlock_body(dataset, secret, children, options),
+            self.flavor,
+            self.max_retries,
+        )
+    }
+
+    fn lock(&mut self, dataset: &str, force_umount: bool) -> Result<LockResult> {
+        self.start_session()?;
+        lock_dataset(
+            &self.client,
+            &self.base_url,
+            self.request_auth(),
+            dataset,
+            force_umount,
+            self.flavor,
+            self.max_retries,
+        )
+    }
+
+    fn change_key(&mut self, dataset: &str, secret: UnlockSecret<'_>) -> Result<i64> {
+        self.start_session()?;
+        change_key(
+            &self.client,
+            &self.base_url,
+            self.request_auth(),
+            dataset,
+            secret,
+        )
+    }
+
+    fn dataset(&mut self, dataset: &str) -> Result<DatasetInfo> {
+        self.start_session()?;
+        get_dataset(
+            &self.client,
+            &self.base_url,
+            self.request_auth(),
+            self.flavor,
+            dataset,
+        )
+    }
+
+    fn wait_for_job(&mut self, job_id: i64, wait: WaitOptions) -> Result<JobInfo> {
+        self.start_session()?;
+        wait_for_job(
+            &self.client,
+            &self.base_url,
+            self.request_auth(),
+            job_id,
+            wait,
+            self.flavor,
+            self.max_retries,
+        )
+    }
+}
+
+pub fn build_client(options: ClientOptions<'_>) -> Result<Client> {
     let mut builder = ClientBuilder::new()
-        .timeout(Duration::from_secs(30))
-        .user_agent("naslock/0.1");
-    if skip_tls_verify {
+        .timeout(options.timeout)
+        .connect_timeout(options.connect_timeout.min(options.timeout))
+        .user_agent(options.user_agent)
+        .redirect(redirect_policy(
+            options.max_redirects,
+            options.allow_cross_host_redirect,
+        ));
+    if options.skip_tls_verify {
         builder = builder.danger_accept_invalid_certs(true);
     }
+    if let Some(path) = options.ca_cert {
+        let pem = read_ca_pem(path)?;
+        let cert = reqwest::Certificate::from_pem(&pem)
+            .with_context(|| format!("invalid ca_cert {}", path.display()))?;
+        builder = builder
+            .tls_built_in_root_certs(false)
+            .add_root_certificate(cert);
+    }
+    if let Some((cert, key)) = options.client_identity {
+        builder = builder.identity(load_identity(cert, key)?);
+    }
+    if let Some(url) = options.proxy {
+        let proxy = Proxy::all(url).with_context(|| format!("invalid proxy {:?}", url))?;
+        builder = builder.proxy(proxy);
+    }
     Ok(builder.build()?)
 }
 
+fn load_identity(cert: &Path, key: &Path) -> Result<Identity> {
+    let (cert_pem, key_pem) = read_identity_pem(cert, key)?;
+    Identity::from_pkcs8_pem(&cert_pem, &key_pem).with_context(|| {
+        format!(
+            "invalid client certificate {} or key {} (the key must be PKCS#8 PEM)",
+            cert.display(),
+            key.display()
+        )
+    })
+}
+
+pub(crate) fn read_ca_pem(path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("failed to read ca_cert {}", path.display()))
+}
+
+/// Reads the client certificate and key PEM files for mutual TLS.
+pub(crate) fn read_identity_pem(
+    cert: &Path,
+    key: &Path,
+) -> Result<(Vec<u8>, zeroize::Zeroizing<Vec<u8>>)> {
+    let cert_pem = std::fs::read(cert)
+        .with_context(|| format!("failed to read client_cert {}", cert.display()))?;
+    let key_pem = zeroize::Zeroizing::new(
+        std::fs::read(key)
+            .with_context(|| format!("failed to read client_key {}", key.display()))?,
+    );
+    Ok((cert_pem, key_pem))
+}
+
+/// Follows at most `max_redirects` hops and, unless explicitly allowed,
+/// refuses to leave the origin of the original request (an http to https
+/// upgrade on the same host is fine) so credentials are never replayed
+/// against another host.
+fn redirect_policy(max_redirects: usize, allow_cross_host_redirect: bool) -> Policy {
+    Policy::custom(move |attempt| {
+        if attempt.previous().len() > max_redirects {
+            return attempt.error(format!(
+                "too many redirects (max_redirects = {})",
+                max_redirects
+            ));
+        }
+        let same_origin = attempt.previous().first().is_none_or(|first| {
+            let next = attempt.url();
+            first.origin() == next.origin()
+                || (first.host_str() == next.host_str()
+                    && first.scheme() == "http"
+                    && next.scheme() == "https")
+        });
+        if !same_origin && !allow_cross_host_redirect {
+            let target = attempt.url().origin().ascii_serialization();
+            return attempt.error(format!(
+                "refusing to follow redirect to a different origin ({}); set allow_cross_host_redirect = true to permit it",
+                target
+            ));
+        }
+        attempt.follow()
+    })
+}
+
+/// Normalizes the configured `host` to a base URL ending in `/`, so API
+/// paths can be joined onto it. A missing scheme defaults to `https`; a path
+/// such as the `/nas` of a reverse proxy is kept, and any query or fragment
+/// is dropped.
 pub fn parse_base_url(host: &str) -> Result<Url> {
     let trimmed = host.trim();
-    let mut host = if trimmed.starts_with("http://") || trimmed.starts_with("https://") {
-        trimmed.to_string()
-    } else {
-        format!("https://{}", trimmed)
+    if trimmed.is_empty() {
+        bail!("NAS host is empty");
+    }
+    // The URL parser silently drops tabs and newlines inside the input.
+    if trimmed.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        bail!("invalid NAS host URL {:?}: contains whitespace", trimmed);
+    }
+
+    let host = match trimmed.split_once("://") {
+        Some((scheme, _))
+            if scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https") =>
+        {
+            trimmed.to_string()
+        }
+        Some((scheme, _)) => bail!(
+            "invalid NAS host URL {:?}: unsupported scheme '{}'",
+            trimmed,
+            scheme
+        ),
+        // A bare IPv6 address needs brackets before a scheme can be added.
+        None if trimmed.parse::<std::net::Ipv6Addr>().is_ok() => format!("https://[{}]", trimmed),
+        None => format!("https://{}", trimmed),
+    };
+
+    let mut url =
+        Url::parse(&host).with_context(|| format!("invalid NAS host URL {:?}", trimmed))?;
+    if url.host_str().is_none_or(str::is_empty) {
+        bail!("invalid NAS host URL {:?}: missing host name", trimmed);
+    }
+    if !url.username().is_empty() || url.password().is_some() {
+        bail!(
+            "invalid NAS host URL {:?}: credentials belong in KeePass, not in the host",
+            trimmed
+        );
+    }
+    let path = format!("{}/", url.path().trim_end_matches('/'));
+    url.set_path(&path);
+    url.set_query(None);
+    url.set_fragment(None);
+    Ok(url)
+}
+
+/// Sends the unlock request `body` for `dataset`, retrying transient
+/// failures up to `max_retries` times. Before each retry the dataset is
+/// queried, and a dataset that is already unlocked counts as success.
+pub fn unlock_dataset(
+    client: &Client,
+    base_url: &Url,
+    auth: Auth<'_>,
+    dataset: &str,
+    body: &UnlockRequest<'_>,
+    flavor: Flavor,
+    max_retries: u32,
+) -> Result<UnlockResult> {
+    tracing::debug!(dataset, %base_url, "unlocking dataset");
+    let result = with_retries(
+        max_retries,
+        || send_unlock(client, base_url, auth, body),
+        || {
+            let info = get_dataset(client, base_url, auth, flavor, dataset).ok()?;
+            (!info.locked).then(|| UnlockResult {
+                unlocked: vec![dataset.to_string()],
+                ..UnlockResult::default()
+            })
+        },
+    );
+
+    match result {
+        Err(err) if is_conflict(&err) => {
+            let job_id = attach_to_running_job(
+                client,
+                base_url,
+                auth,
+                flavor,
+                "pool.dataset.unlock",
+                dataset,
+                err,
+            )?;
+            Ok(UnlockResult {
+                job_id: Some(job_id),
+                ..UnlockResult::default()
+            })
+        }
+        result => result,
+    }
+}
+
+fn send_unlock(
+    client: &Client,
+    base_url: &Url,
+    auth: Auth<'_>,
+    body: &UnlockRequest<'_>,
+) -> Result<UnlockResult> {
+    let request = unlock_request(client, base_url, auth)?
+        .header(CONTENT_TYPE, "application/json")
+        .body(secret_json_body(body)?);
+
+    let response = request.send().context("failed to send unlock request")?;
+    if let Some(err) = unfollowed_redirect(&response) {
+        return Err(err);
+    }
+    let status = response.status();
+    let content_type = response_content_type(&response);
+    let text = response
+        .text()
+        .context("failed to read unlock response body")?;
+
+    if !status.is_success() || is_proxy_page(content_type.as_deref(), &text) {
+        return Err(ApiError::new(status, content_type, text).into());
+    }
+
+    parse_unlock_response(&text)
+}
+
+/// Locks the dataset, retrying transient failures up to `max_retries` times.
+/// Before each retry the dataset is queried, and a dataset that is already
+/// locked counts as success, so a lock that timed out after the server
+/// applied it does not turn into an "already locked" error.
+pub fn lock_dataset(
+    client: &Client,
+    base_url: &Url,
+    auth: Auth<'_>,
+    dataset: &str,
+    force_umount: bool,
+    flavor: Flavor,
+    max_retries: u32,
+) -> Result<LockResult> {
+    tracing::debug!(dataset, %base_url, "locking dataset");
+    let result = with_retries(
+        max_retries,
+        || send_lock(client, base_url, auth, dataset, force_umount),
+        || {
+            let info = get_dataset(client, base_url, auth, flavor, dataset).ok()?;
+            info.locked.then(already_locked)
+        },
+    );
+
+    match result {
+        Err(err) if is_conflict(&err) => {
+            let job_id = attach_to_running_job(
+                client,
+                base_url,
+                auth,
+                flavor,
+                "pool.dataset.lock",
+                dataset,
+                err,
+            )?;
+            Ok(LockResult {
+                job_id: Some(job_id),
+                ..LockResult::default()
+            })
+        }
+        Err(err) if is_already_locked(&err) => Ok(already_locked()),
+        result => result,
+    }
+}
+
+/// Calls `send` until it succeeds, fails with an error that is not
+/// [ambiguous](is_ambiguous), or has been retried `max_retries` times,
+/// sleeping 1s, 2s, 4s, ... (at most 30s) in between. After each ambiguous
+/// failure `settled` may report that the request took effect anyway, which
+/// ends the loop with its result.
+fn with_retries<T>(
+    max_retries: u32,
+    mut send: impl FnMut() -> Result<T>,
+    mut settled: impl FnMut() -> Option<T>,
+) -> Result<T> {
+    let mut retry = 0;
+    loop {
+        let err = match send() {
+            Ok(result) => return Ok(result),
+            Err(err) => err,
+        };
+        if !is_ambiguous(&err) {
+            return Err(err);
+        }
+        if let Some(result) = settled() {
+            return Ok(result);
+        }
+        if retry >= max_retries {
+            return Err(err);
+        }
+
+        tracing::info!(
+            "retrying after transient error ({}/{}): {:#}",
+            retry + 1,
+            max_retries,
+            err
+        );
+        std::thread::sleep(Duration::from_secs(1 << retry.min(5)).min(Duration::from_secs(30)));
+        retry += 1;
+    }
+}
+
+fn send_lock(
+    client: &Client,
+    base_url: &Url,
+    auth: Auth<'_>,
+    dataset: &str,
+    force_umount: bool,
+) -> Result<LockResult> {
+    let body = build_lock_body(dataset, force_umount);
+    let request = lock_request(client, base_url, auth, &body)?;
+
+    let response = request.send().context("failed to send lock request")?;
+    let status = response.status();
+    let content_type = response_content_type(&response);
+    let text = response
+        .text()
+        .context("failed to read lock response body")?;
+
+    if !status.is_success() || is_proxy_page(content_type.as_deref(), &text) {
+        return Err(ApiError::new(status, content_type, text).into());
+    }
+
+    parse_lock_response(&text)
+}
+
+/// Changes the passphrase or key of `dataset` and returns the id of the job
+/// doing it. Unlike unlock and lock this is never retried: whether a request
+/// that failed midway took effect can only be told by trying both secrets.
+pub fn change_key(
+    client: &Client,
+    base_url: &Url,
+    auth: Auth<'_>,
+    dataset: &str,
+    secret: UnlockSecret<'_>,
+) -> Result<i64> {
+    tracing::debug!(dataset, %base_url, "changing dataset key");
+    let url = base_url
+        .join("api/v2.0/pool/dataset/change_key")
+        .context("failed to build API URL")?;
+    let request = client
+        .post(url)
+        .header(ACCEPT, "application/json")
+        .header(CONTENT_TYPE, "application/json")
+        .body(secret_json_body(&build_change_key_body(dataset, secret))?);
+
+    let response = apply_auth(request, auth)
+        .send()
+        .context("failed to send change_key request")?;
+    if let Some(err) = unfollowed_redirect(&response) {
+        return Err(err);
+    }
+    let status = response.status();
+    let content_type = response_content_type(&response);
+    let text = response
+        .text()
+        .context("failed to read change_key response body")?;
+
+    if !status.is_success() || is_proxy_page(content_type.as_deref(), &text) {
+        return Err(ApiError::new(status, content_type, text).into());
+    }
+
+    serde_json::from_str::<Value>(text.trim())
+        .ok()
+        .and_then(|value| value.as_i64())
+        .with_context(|| format!("change_key did not return a job id: {}", text.trim()))
+}
+
+/// The error for a 307 or 308 redirect of a request whose body holds a
+/// secret. Such a body is streamed from [`secret_json_body`] and cannot be
+/// sent again, so reqwest hands back the redirect instead of following it.
+fn unfollowed_redirect(response: &Response) -> Option<anyhow::Error> {
+    if !matches!(
+        response.status(),
+        StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT
+    ) {
+        return None;
+    }
+    let location = response
+        .headers()
+        .get(LOCATION)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("an unknown location");
+    Some(error::tag(
+        ErrorKind::Config,
+        anyhow::anyhow!(
+            "the NAS redirected the request to {} ({}); requests that carry a secret are not resent, so set host to the address it redirects to",
+            location,
+            response.status()
+        ),
+    ))
+}
+
+fn response_content_type(response: &Response) -> Option<String> {
+    let value = response.headers().get(CONTENT_TYPE)?;
+    value.to_str().ok().map(str::to_string)
+}
+
+pub(crate) fn already_locked() -> LockResult {
+    LockResult {
+        locked: true,
+        already_locked: true,
+        ..LockResult::default()
+    }
+}
+
+fn is_already_locked(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<ApiError>())
+        .any(|api| api.body.to_ascii_lowercase().contains("already locked"))
+}
+
+/// Whether the API refused the request because another job is already
+/// operating on the dataset.
+fn is_conflict(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<ApiError>())
+        .any(|api| {
+            if matches!(api.status.as_u16(), 409 | 423) {
+                return true;
+            }
+            let body = api.body.to_ascii_lowercase();
+            body.contains("in progress") || body.contains("already running")
+        })
+}
+
+/// Finds the job that caused a conflict so the caller can wait for it. If
+/// there is none, the conflict is reported as an operation in progress.
+fn attach_to_running_job(
+    client: &Client,
+    base_url: &Url,
+    auth: Auth<'_>,
+    flavor: Flavor,
+    method: &str,
+    dataset: &str,
+    err: anyhow::Error,
+) -> Result<i64> {
+    match find_job_by_method_arg(client, base_url, auth, flavor, method, dataset) {
+        Ok(Some(job_id)) => Ok(job_id),
+        _ => Err(error::tag(
+            ErrorKind::Rejected,
+            err.context(format!("operation already in progress on {}", dataset)),
+        )),
+    }
+}
+
+/// Returns the id of a running or waiting job for `method` whose first
+/// argument is `arg`.
+pub fn find_job_by_method_arg(
+    client: &Client,
+    base_url: &Url,
+    auth: Auth<'_>,
+    flavor: Flavor,
+    method: &str,
+    arg: &str,
+) -> Result<Option<i64>> {
+    let url = base_url
+        .join("api/v2.0/core/get_jobs")
+        .context("failed to build jobs API URL")?;
+
+    let mut request = client
+        .post(url)
+        .header(ACCEPT, "application/json")
+        .json(&running_jobs_query(flavor, method));
+    request = apply_auth(request, auth);
+
+    let response = request.send().context("failed to query jobs")?;
+    let status = response.status();
+    let content_type = response_content_type(&response);
+    let text = response
+        .text()
+        .context("failed to read jobs response body")?;
+
+    if !status.is_success() || is_proxy_page(content_type.as_deref(), &text) {
+        return Err(ApiError::new(status, content_type, text).into());
+    }
+
+    let value: Value = serde_json::from_str(text.trim())
+        .with_context(|| format!("failed to parse jobs response: {}", text.trim()))?;
+    Ok(find_job_with_arg(&value, arg))
+}
+
+/// `core.get_jobs` arguments selecting the running or waiting jobs of
+/// `method`.
+pub(crate) fn running_jobs_query(flavor: Flavor, method: &str) -> Value {
+    query_args(
+        flavor,
+        json!([
+            ["method", "=", method],
+            ["state", "in", ["RUNNING", "WAITING"]]
+        ]),
+    )
+}
+
+/// Arguments for a `*.query` style call with `filters`. SCALE takes them
+/// positionally; the CORE REST API wants the named `query-filters` form and
+/// answers the positional one as if nothing matched.
+pub(crate) fn query_args(flavor: Flavor, filters: Value) -> Value {
+    match flavor {
+        Flavor::Scale => json!([filters]),
+        Flavor::Core => json!({ "query-filters": filters, "query-options": {} }),
+    }
+}
+
+/// The id of the first job in a `core.get_jobs` result whose first argument
+/// is `arg`.
+pub(crate) fn find_job_with_arg(jobs: &Value, arg: &str) -> Option<i64> {
+    jobs.as_array()?.iter().find_map(|job| {
+        let first = job.get("arguments")?.as_array()?.first()?;
+        (first.as_str() == Some(arg)).then(|| job.get("id")?.as_i64())?
+    })
+}
+
+/// Whether the failure leaves it unknown if the NAS acted on the request:
+/// transport errors and server-side (5xx) errors.
+fn is_ambiguous(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        if let Some(api) = cause.downcast_ref::<ApiError>() {
+            return api.status.is_server_error();
+        }
+        cause.is::<reqwest::Error>()
+    })
+}
+
+/// Renders the unlock request exactly as it would be sent, with the secret
+/// replaced by a placeholder and the credentials in `Authorization` redacted.
+pub fn render_unlock_request(
+    client_options: ClientOptions<'_>,
+    base_url: &Url,
+    auth: Auth<'_>,
+    dataset: &str,
+    secret: UnlockSecret<'_>,
+    children: &[(&str, UnlockSecret<'_>)],
+    options: UnlockOptions,
+) -> Result<RenderedRequest> {
+    let placeholder = |secret| match secret {
+        UnlockSecret::Passphrase(_) => UnlockSecret::Passphrase(REDACTED),
+        UnlockSecret::Key(_) => UnlockSecret::Key(REDACTED),
     };
-    if host.ends_with('/') {
-        host.pop();
+    let children: Vec<_> = children
+        .iter()
+        .map(|&(name, secret)| (name, placeholder(secret)))
+        .collect();
+    let body = build_unlock_body(dataset, placeholder(secret), &children, options);
+    let client = build_client(client_options)?;
+    render(
+        unlock_request(&client, base_url, auth)?.json(&body),
+        client_options.user_agent,
+    )
+}
+
+/// Renders the lock request exactly as it would be sent, with the
+/// credentials in `Authorization` redacted.
+pub fn render_lock_request(
+    options: ClientOptions<'_>,
+    base_url: &Url,
+    auth: Auth<'_>,
+    dataset: &str,
+    force_umount: bool,
+) -> Result<RenderedRequest> {
+    let body = build_lock_body(dataset, force_umount);
+    let client = build_client(options)?;
+    render(
+        lock_request(&client, base_url, auth, &body)?,
+        options.user_agent,
+    )
+}
+
+pub fn wait_for_job(
+    client: &Client,
+    base_url: &Url,
+    auth: Auth<'_>,
+    job_id: i64,
+    wait: WaitOptions,
+    flavor: Flavor,
+    max_retries: u32,
+) -> Result<JobInfo> {
+    let mut watch = JobWatch::new(job_id, wait);
+    let mut query = None;
+    loop {
+        let job = with_retries(
+            max_retries,
+            || get_job(client, base_url, auth, flavor, job_id, &mut query),
+            || None,
+        )?;
+        let state = job.state.clone();
+        if let Some(result) = watch.update(job) {
+            return result;
+        }
+        std::thread::sleep(watch.next_check(state.as_deref())?);
+    }
+}
+
+/// Follows one job through its status updates, however they are obtained:
+/// shows progress changes, turns a finished job into the result and
+/// enforces the wait timeout.
+pub(crate) struct JobWatch {
+    job_id: i64,
+    wait: WaitOptions,
+    started: Instant,
+    last_progress: Option<(Option<f64>, Option<String>)>,
+    /// Progress bar on a terminal, started by the first percentage.
+    bar: Option<ProgressBar>,
+}
+
+impl JobWatch {
+    pub(crate) fn new(job_id: i64, wait: WaitOptions) -> Self {
+        JobWatch {
+            job_id,
+            wait,
+            started: Instant::now(),
+            last_progress: None,
+            bar: None,
+        }
+    }
+
+    /// Returns the outcome once `job` has finished, `None` while it runs.
+    pub(crate) fn update(&mut self, mut job: JobInfo) -> Option<Result<JobInfo>> {
+        let job_id = self.job_id;
+        match job.state.as_deref() {
+            Some("SUCCESS") => {
+                if let Some(bar) = self.bar.take() {
+                    bar.set_position(100);
+                    if let Some(desc) = &job.progress_description {
+                        bar.set_message(desc.clone());
+                    }
+                    bar.finish();
+                }
+                job.waited = self.started.elapsed();
+                return Some(Ok(job));
+            }
+            Some("FAILED" | "ABORTED") => {
+                let detail = job
+                    .error
+                    .or(job.exception)
+                    .unwrap_or_else(|| "job failed".to_string());
+                return Some(Err(error::tag(
+                    ErrorKind::Job,
+                    anyhow::anyhow!("job {} failed: {}", job_id, detail.trim()),
+                )));
+            }
+            _ => {}
+        }
+
+        let progress = (job.progress_percent, job.progress_description);
+        if self.wait.show_progress && self.last_progress.as_ref() != Some(&progress) {
+            self.show_progress(progress.0, progress.1.as_deref());
+            self.last_progress = Some(progress);
+        }
+        None
+    }
+
+    /// Moves the progress bar when stdout is a terminal and the job reports
+    /// a percentage, and prints a `job N: ...` line otherwise.
+    fn show_progress(&mut self, percent: Option<f64>, desc: Option<&str>) {
+        if let Some(percent) = percent {
+            if self.bar.is_none() && std::io::stdout().is_terminal() {
+                self.bar = Some(progress_bar(self.job_id));
+            }
+            if let Some(bar) = &self.bar {
+                bar.set_position(percent.clamp(0.0, 100.0) as u64);
+                bar.set_message(desc.unwrap_or_default().to_string());
+                return;
+            }
+        }
+        let line = match (percent, desc) {
+            (Some(percent), Some(desc)) => format!("job {}: {:.0}% {}", self.job_id, percent, desc),
+            (Some(percent), None) => format!("job {}: {:.0}%", self.job_id, percent),
+            (None, Some(desc)) => format!("job {}: {}", self.job_id, desc),
+            (None, None) => return,
+        };
+        match &self.bar {
+            Some(bar) => bar.println(line),
+            None => println!("{}", line),
+        }
+    }
+
+    /// How long to wait before checking on the job again, or the timeout
+    /// error if the job has run out of time; `state` is its last known state.
+    pub(crate) fn next_check(&self, state: Option<&str>) -> Result<Duration> {
+        let Some(timeout) = self.wait.timeout else {
+            return Ok(self.wait.poll_interval);
+        };
+        let remaining = timeout.saturating_sub(self.started.elapsed());
+        if remaining.is_zero() {
+            return Err(error::tag(
+                ErrorKind::Job,
+                anyhow::anyhow!(
+                    "job {} did not finish within {}s (state: {})",
+                    self.job_id,
+                    timeout.as_secs(),
+                    state.unwrap_or("unknown")
+                ),
+            ));
+        }
+        Ok(self.wait.poll_interval.min(remaining))
+    }
+}
+
+impl Drop for JobWatch {
+    /// Clears the bar of a job that failed, timed out or could not be
+    /// queried, so the error is printed on a clean line.
+    fn drop(&mut self) {
+        if let Some(bar) = self.bar.take() {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+fn progress_bar(job_id: i64) -> ProgressBar {
+    let style = ProgressStyle::with_template("job {prefix}: [{bar:30}] {pos:>3}% {wide_msg}")
+        .expect("progress bar template is valid")
+        .progress_chars("=> ");
+    ProgressBar::with_draw_target(Some(100), ProgressDrawTarget::stdout())
+        .with_style(style)
+        .with_prefix(job_id.to_string())
+}
+
+pub fn get_dataset(
+    client: &Client,
+    base_url: &Url,
+    auth: Auth<'_>,
+    flavor: Flavor,
+    dataset: &str,
+) -> Result<DatasetInfo> {
+    let url = base_url
+        .join("api/v2.0/pool/dataset/query")
+        .context("failed to build API URL")?;
+
+    let mut request = client
+        .post(url)
+        .header(ACCEPT, "application/json")
+        .json(&query_args(flavor, json!([["id", "=", dataset]])));
+    request = apply_auth(request, auth);
+
+    let response = request.send().context("failed to query dataset")?;
+    let status = response.status();
+    let content_type = response_content_type(&response);
+    let text = response
+        .text()
+        .context("failed to read dataset query response body")?;
+
+    if !status.is_success() || is_proxy_page(content_type.as_deref(), &text) {
+        return Err(ApiError::new(status, content_type, text).into());
+    }
+
+    parse_dataset_response(&text, dataset)
+}
+
+/// Trades the credentials in `auth` for a session token with
+/// `auth/generate_token`.
+fn generate_token(client: &Client, base_url: &Url, auth: Auth<'_>) -> Result<Zeroizing<String>> {
+    let url = base_url
+        .join("api/v2.0/auth/generate_token")
+        .context("failed to build API URL")?;
+
+    let mut request = client
+        .post(url)
+        .header(ACCEPT, "application/json")
+        .json(&json!({ "ttl": SESSION_TOKEN_TTL_SECS }));
+    request = apply_auth(request, auth);
+
+    let response = request
+        .send()
+        .context("failed to request a session token")?;
+    let status = response.status();
+    let content_type = response_content_type(&response);
+    let text = Zeroizing::new(
+        response
+            .text()
+            .context("failed to read session token response body")?,
+    );
+
+    if !status.is_success() || is_proxy_page(content_type.as_deref(), &text) {
+        return Err(ApiError::new(status, content_type, text.to_string()).into());
     }
-    let mut url = Url::parse(&host).context("invalid NAS host URL")?;
-    url.set_path("/");
-    url.set_query(None);
-    url.set_fragment(None);
-    Ok(url)
+
+    let token: String =
+        serde_json::from_str(text.trim()).context("failed to parse session token response")?;
+    if token.is_empty() {
+        bail!("TrueNAS returned an empty session token");
+    }
+    Ok(Zeroizing::new(token))
 }
 
-pub fn unlock_dataset(
-    client: &Client,
-    base_url: &Url,
-    auth: Auth<'_>,
-    dataset: &str,
-    secret: UnlockSecret<'_>,
+pub fn build_unlock_body<'a>(
+    dataset: &'a str,
+    secret: UnlockSecret<'a>,
+    children: &[(&'a str, UnlockSecret<'a>)],
     options: UnlockOptions,
-) -> Result<UnlockResult> {
-    let url = base_url
-        .join("api/v2.0/pool/dataset/unlock")
-        .context("failed to build API URL")?;
-
-    let (passphrase, key) = match secret {
-        UnlockSecret::Passphrase(value) => (Some(value), None),
-        UnlockSecret::Key(value) => (None, Some(value)),
+) -> UnlockRequest<'a> {
+    let entry = |name, secret| {
+        let (passphrase, key) = match secret {
+            UnlockSecret::Passphrase(value) => (Some(value), None),
+            UnlockSecret::Key(value) => (None, Some(value)),
+        };
+        UnlockDataset {
+            name,
+            passphrase,
+            key,
+        }
     };
 
-    let body = UnlockRequest {
+    UnlockRequest {
         id: dataset,
         unlock_options: UnlockOptionsBody {
             recursive: options.recursive,
             force: options.force,
             toggle_attachments: options.toggle_attachments,
+            // `key_file` means the keys arrive as a separately uploaded file;
+            // key material read from a key file is sent inline as `key`.
             key_file: false,
-            datasets: vec![UnlockDataset {
-                name: dataset,
-                passphrase,
-                key,
-            }],
+            datasets: std::iter::once(entry(dataset, secret))
+                .chain(children.iter().map(|&(name, secret)| entry(name, secret)))
+                .collect(),
         },
+    }
+}
+
+pub(crate) fn build_lock_body(dataset: &str, force_umount: bool) -> LockRequest<'_> {
+    LockRequest {
+        id: dataset,
+        lock_options: LockOptionsBody { force_umount },
+    }
+}
+
+pub(crate) fn build_change_key_body<'a>(
+    dataset: &'a str,
+    secret: UnlockSecret<'a>,
+) -> ChangeKeyRequest<'a> {
+    let (passphrase, key) = match secret {
+        UnlockSecret::Passphrase(passphrase) => (Some(passphrase), None),
+        UnlockSecret::Key(key) => (None, Some(key)),
     };
+    ChangeKeyRequest {
+        id: dataset,
+        change_key_options: ChangeKeyOptionsBody {
+            generate_key: false,
+            key_file: false,
+            passphrase,
+            key,
+        },
+    }
+}
+
+/// The unlock request without its body, which carries the secrets and is
+/// added by the caller.
+fn unlock_request(client: &Client, base_url: &Url, auth: Auth<'_>) -> Result<RequestBuilder> {
+    let url = base_url
+        .join(UNLOCK_PATH)
+        .context("failed to build API URL")?;
 
     let mut headers = HeaderMap::new();
     headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
 
-    let mut request = client.post(url).headers(headers).json(&body);
-    request = apply_auth(request, auth);
+    let request = client.post(url).headers(headers);
+    Ok(apply_auth(request, auth))
+}
 
-    let response = request.send().context("failed to send unlock request")?;
-    let status = response.status();
-    let text = response
-        .text()
-        .context("failed to read unlock response body")?;
+/// Serializes a request body that holds secrets into a buffer of exactly
+/// the right size, so it is never reallocated and left behind. That buffer
+/// is zeroed when reqwest drops the body; the chunks reqwest copies out of
+/// it while sending are not. The body is streamed, so reqwest cannot resend
+/// it after a 307 or 308 redirect; see [`unfollowed_redirect`].
+fn secret_json_body(body: &impl Serialize) -> Result<Body> {
+    let mut size = ByteCount(0);
+    serde_json::to_writer(&mut size, body).context("failed to serialize request body")?;
+    let mut buffer = Zeroizing::new(Vec::with_capacity(size.0));
+    serde_json::to_writer(&mut *buffer, body).context("failed to serialize request body")?;
+    let len = buffer.len() as u64;
+    Ok(Body::sized(std::io::Cursor::new(buffer), len))
+}
 
-    if !status.is_success() {
-        bail!("TrueNAS API error ({}): {}", status, text.trim());
+/// A writer that only counts the bytes written to it.
+struct ByteCount(usize);
+
+impl std::io::Write for ByteCount {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
     }
 
-    parse_unlock_response(&text)
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
-pub fn lock_dataset(
+fn lock_request(
     client: &Client,
     base_url: &Url,
     auth: Auth<'_>,
-    dataset: &str,
-    force_umount: bool,
-) -> Result<LockResult> {
+    body: &LockRequest<'_>,
+) -> Result<RequestBuilder> {
     let url = base_url
         .join("api/v2.0/pool/dataset/lock")
         .context("failed to build API URL")?;
 
-    let body = LockRequest {
-        id: dataset,
-        lock_options: LockOptionsBody { force_umount },
-    };
-
     let mut headers = HeaderMap::new();
     headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
 
-    let mut request = client.post(url).headers(headers).json(&body);
-    request = apply_auth(request, auth);
-
-    let response = request.send().context("failed to send lock request")?;
-    let status = response.status();
-    let text = response
-        .text()
-        .context("failed to read lock response body")?;
-
-    if !status.is_success() {
-        bail!("TrueNAS API error ({}): {}", status, text.trim());
-    }
-
-    parse_lock_response(&text)
+    let request = client.post(url).headers(headers).json(body);
+    Ok(apply_auth(request, auth))
 }
 
-pub fn wait_for_job(
-    client: &Client,
-    base_url: &Url,
-    auth: Auth<'_>,
-    job_id: i64,
-) -> Result<JobInfo> {
-    let poll_interval = Duration::from_secs(1);
-    let mut last_progress: Option<(Option<f64>, Option<String>)> = None;
-
-    loop {
-        let job = get_job(client, base_url, auth, job_id)?;
-
-        if let Some(state) = job.state.as_deref() {
-            match state {
-                "SUCCESS" => return Ok(job),
-                "FAILED" | "ABORTED" => {
-                    let detail = job
-                        .error
-                        .clone()
-                        .or(job.exception.clone())
-                        .unwrap_or_else(|| "job failed".to_string());
-                    bail!("job {} failed: {}", job_id, detail.trim());
-                }
-                _ => {}
-            }
-        }
-
-        let progress = (job.progress_percent, job.progress_description.clone());
-        if progress.0.is_some() || progress.1.is_some() {
-            if last_progress.as_ref() != Some(&progress) {
-                if let Some(percent) = progress.0 {
-                    if let Some(desc) = progress.1.as_deref() {
-                        println!("job {}: {:.0}% {}", job_id, percent, desc);
-                    } else {
-                        println!("job {}: {:.0}%", job_id, percent);
-                    }
-                } else if let Some(desc) = progress.1.as_deref() {
-                    println!("job {}: {}", job_id, desc);
-                }
-                last_progress = Some(progress);
+fn render(request: RequestBuilder, user_agent: &str) -> Result<RenderedRequest> {
+    let request = request.build().context("failed to build request")?;
+    let mut headers = vec![("user-agent".to_string(), user_agent.to_string())];
+    for (name, value) in request.headers() {
+        let value = if name == AUTHORIZATION || value.is_sensitive() {
+            let raw = value.to_str().unwrap_or_default();
+            match raw.split_once(' ') {
+                Some((scheme, _)) => format!("{} {}", scheme, REDACTED),
+                None => REDACTED.to_string(),
             }
-        }
-
-        std::thread::sleep(poll_interval);
+        } else {
+            value.to_str().unwrap_or_default().to_string()
+        };
+        headers.push((name.as_str().to_string(), value));
     }
+    let body = request
+        .body()
+        .and_then(|body| body.as_bytes())
+        .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+        .unwrap_or_default();
+
+    Ok(RenderedRequest {
+        method: request.method().to_string(),
+        url: request.url().to_string(),
+        headers,
+        body,
+    })
 }
 
+/// The JSON body of an unlock request, from [`build_unlock_body`].
 #[derive(Serialize)]
-struct UnlockRequest<'a> {
+pub struct UnlockRequest<'a> {
     id: &'a str,
     unlock_options: UnlockOptionsBody<'a>,
 }
@@ -240,7 +1330,7 @@ struct UnlockDataset<'a> {
 }
 
 #[derive(Serialize)]
-struct LockRequest<'a> {
+pub(crate) struct LockRequest<'a> {
     id: &'a str,
     lock_options: LockOptionsBody,
 }
@@ -250,6 +1340,22 @@ struct LockOptionsBody {
     force_umount: bool,
 }
 
+#[derive(Serialize)]
+pub(crate) struct ChangeKeyRequest<'a> {
+    id: &'a str,
+    change_key_options: ChangeKeyOptionsBody<'a>,
+}
+
+#[derive(Serialize)]
+struct ChangeKeyOptionsBody<'a> {
+    generate_key: bool,
+    key_file: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    passphrase: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<&'a str>,
+}
+
 fn parse_unlock_response(text: &str) -> Result<UnlockResult> {
     let trimmed = text.trim();
     if trimmed.is_empty() {
@@ -258,29 +1364,8 @@ fn parse_unlock_response(text: &str) -> Result<UnlockResult> {
 
     let mut result = UnlockResult::default();
     match serde_json::from_str::<Value>(trimmed) {
-        Ok(Value::Object(map)) => {
-            if let Some(job_id) = map.get("job_id").and_then(|v| v.as_i64()) {
-                result.job_id = Some(job_id);
-            }
-            if let Some(unlocked) = map.get("unlocked").and_then(|v| v.as_array()) {
-                for value in unlocked {
-                    if let Some(item) = value.as_str() {
-                        result.unlocked.push(item.to_string());
-                    }
-                }
-            }
-            if let Some(failed) = map.get("failed").and_then(|v| v.as_object()) {
-                for (name, reason) in failed {
-                    let reason = reason
-                        .as_str()
-                        .map(|s| s.to_string())
-                        .unwrap_or_else(|| reason.to_string());
-                    result.failed.push((name.to_string(), reason));
-                }
-            }
-            if let Some(message) = map.get("message").and_then(|v| v.as_str()) {
-                result.message = Some(message.to_string());
-            }
+        Ok(value @ Value::Object(_)) => {
+            result = unlock_details(&value);
         }
         Ok(Value::Number(num)) => {
             if let Some(job_id) = num.as_i64() {
@@ -310,6 +1395,86 @@ fn parse_unlock_response(text: &str) -> Result<UnlockResult> {
     Ok(result)
 }
 
+/// Reads which datasets an unlock unlocked, skipped or failed on, from the
+/// response or the job result: `{"unlocked": [...], "failed": {name:
+/// reason}}`. Datasets listed under `skipped` or `already_unlocked`, and
+/// `failed` entries whose reason is that the dataset is not locked, count as
+/// skipped rather than failed.
+pub(crate) fn unlock_details(value: &Value) -> UnlockResult {
+    let names = |key| {
+        value
+            .get(key)
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .map(str::to_string)
+    };
+    let mut result = UnlockResult {
+        job_id: value.get("job_id").and_then(Value::as_i64),
+        unlocked: names("unlocked").collect(),
+        skipped: names("skipped").chain(names("already_unlocked")).collect(),
+        message: value
+            .get("message")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        ..UnlockResult::default()
+    };
+    if let Some(failed) = value.get("failed").and_then(Value::as_object) {
+        for (name, reason) in failed {
+            let reason = failure_reason(reason);
+            if is_already_unlocked(&reason) {
+                result.skipped.push(name.to_string());
+            } else {
+                result.failed.push((name.to_string(), reason));
+            }
+        }
+    }
+    result
+}
+
+/// Whether a `failed` reason says the dataset was unlocked to begin with,
+/// e.g. "Dataset is not locked", "tank/x is already unlocked" or "isn't
+/// locked". Case, punctuation and spacing are ignored.
+fn is_already_unlocked(reason: &str) -> bool {
+    let words: Vec<String> = reason
+        .split(|c: char| !c.is_alphanumeric() && !matches!(c, '\'' | '’'))
+        .map(|word| word.to_lowercase().replace(['\'', '’'], ""))
+        .filter(|word| !word.is_empty())
+        .collect();
+    words.windows(2).any(|pair| {
+        matches!(
+            (pair[0].as_str(), pair[1].as_str()),
+            ("already", "unlocked" | "decrypted")
+                | ("unlocked", "already")
+                | ("not" | "isnt" | "wasnt", "locked")
+        )
+    })
+}
+
+/// Turns a `failed` entry into readable text. Older releases give a plain
+/// string; newer ones an object whose `reason` (or `error`, or `message`)
+/// may itself be nested. Anything else is shown as JSON.
+fn failure_reason(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.trim().to_string(),
+        Value::Object(map) => ["reason", "error", "message"]
+            .iter()
+            .filter_map(|key| map.get(*key))
+            .filter(|value| !value.is_null())
+            .map(failure_reason)
+            .find(|reason| !reason.is_empty())
+            .unwrap_or_else(|| value.to_string()),
+        Value::Array(items) if !items.is_empty() => items
+            .iter()
+            .map(failure_reason)
+            .collect::<Vec<_>>()
+            .join("; "),
+        Value::Null => "no reason given".to_string(),
+        other => other.to_string(),
+    }
+}
+
 fn parse_lock_response(text: &str) -> Result<LockResult> {
     let trimmed = text.trim();
     if trimmed.is_empty() {
@@ -364,56 +1529,199 @@ fn parse_lock_response(text: &str) -> Result<LockResult> {
     Ok(result)
 }
 
+pub(crate) fn parse_dataset_response(text: &str, dataset: &str) -> Result<DatasetInfo> {
+    let trimmed = text.trim();
+    let value: Value = serde_json::from_str(trimmed)
+        .with_context(|| format!("failed to parse dataset query response: {}", trimmed))?;
+
+    let found = match &value {
+        Value::Array(items) => items
+            .iter()
+            .find(|item| item.get("id").and_then(|v| v.as_str()) == Some(dataset)),
+        Value::Object(_) => Some(&value),
+        _ => None,
+    };
+    let Some(obj) = found.and_then(|v| v.as_object()) else {
+        bail!("dataset {} not found", dataset);
+    };
+
+    let locked = obj.get("locked").and_then(|v| v.as_bool()).unwrap_or(false);
+    let mounted = match (obj.get("mounted"), obj.get("mountpoint")) {
+        (Some(mounted), _) => mounted.as_bool(),
+        (None, Some(mountpoint)) => Some(!locked && mountpoint.is_string()),
+        (None, None) => None,
+    };
+    Ok(DatasetInfo {
+        encrypted: obj
+            .get("encrypted")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false),
+        locked,
+        zvol: obj.get("type").and_then(|v| v.as_str()) == Some("VOLUME"),
+        mounted,
+        locked_descendants: locked_descendants(obj),
+    })
+}
+
+/// Walks the `children` of a dataset query result and returns the id of
+/// every encrypted descendant that is locked.
+fn locked_descendants(obj: &serde_json::Map<String, Value>) -> Vec<String> {
+    let mut locked = Vec::new();
+    let mut pending: Vec<&Value> = obj
+        .get("children")
+        .and_then(Value::as_array)
+        .map(|children| children.iter().collect())
+        .unwrap_or_default();
+    while let Some(child) = pending.pop() {
+        let flag = |key| child.get(key).and_then(Value::as_bool).unwrap_or(false);
+        if flag("encrypted") && flag("locked") {
+            if let Some(id) = child.get("id").and_then(Value::as_str) {
+                locked.push(id.to_string());
+            }
+        }
+        if let Some(children) = child.get("children").and_then(Value::as_array) {
+            pending.extend(children);
+        }
+    }
+    locked.sort();
+    locked
+}
+
 fn apply_auth(
     request: reqwest::blocking::RequestBuilder,
     auth: Auth<'_>,
 ) -> reqwest::blocking::RequestBuilder {
     match auth {
-        Auth::Basic { username, password } => request.basic_auth(username, Some(password)),
-        Auth::ApiKey { key } => {
-            let value = format!("Bearer {}", key);
-            request.header(AUTHORIZATION, value)
+        // The REST API takes no one-time code; `totp_field` is limited to the
+        // websocket transport when the config is loaded.
+        Auth::Basic {
+            username, password, ..
+        } => request.basic_auth(username, Some(password)),
+        Auth::ApiKey {
+            key,
+            header,
+            scheme,
+        } => {
+            let (name, scheme) = api_key_header(header, scheme);
+            let value = match scheme {
+                Some(scheme) => format!("{} {}", scheme, key),
+                None => key.to_string(),
+            };
+            match HeaderValue::from_str(&value) {
+                Ok(mut value) => {
+                    value.set_sensitive(true);
+                    request.header(name, value)
+                }
+                // Leaves the builder with an invalid header value error.
+                Err(_) => request.header(name, value),
+            }
+        }
+        Auth::ApiKeyBasic { key } => request.basic_auth(key, None::<&str>),
+        Auth::Token { token } => match HeaderValue::from_str(&format!("Token {}", token)) {
+            Ok(mut value) => {
+                value.set_sensitive(true);
+                request.header(AUTHORIZATION, value)
+            }
+            Err(_) => request.header(AUTHORIZATION, format!("Token {}", token)),
+        },
+    }
+}
+
+/// The form of `core/get_jobs` request that last worked on the NAS.
+#[derive(Clone, Copy)]
+enum JobQuery {
+    Post,
+    Get,
+}
+
+impl JobQuery {
+    fn method(self) -> &'static str {
+        match self {
+            JobQuery::Post => "POST",
+            JobQuery::Get => "GET",
         }
     }
 }
 
-fn get_job(client: &Client, base_url: &Url, auth: Auth<'_>, job_id: i64) -> Result<JobInfo> {
+/// Queries the job, trying the form in `query` first and the other one if
+/// it fails. `query` starts out empty, which tries POST first, and is set to
+/// the form that answered so later polls send a single request.
+fn get_job(
+    client: &Client,
+    base_url: &Url,
+    auth: Auth<'_>,
+    flavor: Flavor,
+    job_id: i64,
+    query: &mut Option<JobQuery>,
+) -> Result<JobInfo> {
     let url = base_url
         .join("api/v2.0/core/get_jobs")
         .context("failed to build jobs API URL")?;
 
-    let post_result = fetch_job_via_post(client, url.clone(), auth, job_id);
-    if let Ok(job) = post_result {
-        return Ok(job);
-    }
+    let order = match query {
+        Some(JobQuery::Get) => [JobQuery::Get, JobQuery::Post],
+        _ => [JobQuery::Post, JobQuery::Get],
+    };
+    let fetch = |form| match form {
+        JobQuery::Post => fetch_job_via_post(client, url.clone(), auth, flavor, job_id),
+        JobQuery::Get => fetch_job_via_get(client, url.clone(), auth, job_id),
+    };
 
-    let get_result = fetch_job_via_get(client, url, auth, job_id);
-    match (post_result.err(), get_result) {
-        (_, Ok(job)) => Ok(job),
-        (Some(post_err), Err(get_err)) => Err(anyhow::anyhow!(
-            "failed to query job status: post error: {}; get error: {}",
-            post_err,
-            get_err
-        )),
-        (None, Err(get_err)) => Err(get_err),
+    let first_err = match fetch(order[0]) {
+        Ok(job) => {
+            tracing::debug!(job_id, state = ?job.state, "fetched job status via {}", order[0].method());
+            *query = Some(order[0]);
+            return Ok(job);
+        }
+        Err(err) => err,
+    };
+    tracing::debug!(
+        job_id,
+        "job status query via {} failed: {:#}",
+        order[0].method(),
+        first_err
+    );
+    match fetch(order[1]) {
+        Ok(job) => {
+            tracing::debug!(job_id, state = ?job.state, "fetched job status via {}", order[1].method());
+            *query = Some(order[1]);
+            Ok(job)
+        }
+        // Keep the second error's chain so transient failures can be retried.
+        Err(err) => {
+            *query = None;
+            Err(err.context(format!(
+                "failed to query job status: {} error: {:#}; {} error",
+                order[0].method(),
+                first_err,
+                order[1].method()
+            )))
+        }
     }
 }
 
-fn fetch_job_via_post(client: &Client, url: Url, auth: Auth<'_>, job_id: i64) -> Result<JobInfo> {
+fn fetch_job_via_post(
+    client: &Client,
+    url: Url,
+    auth: Auth<'_>,
+    flavor: Flavor,
+    job_id: i64,
+) -> Result<JobInfo> {
     let mut request = client
         .post(url)
         .header(ACCEPT, "application/json")
-        .json(&json!([[["id", "=", job_id]]]));
+        .json(&query_args(flavor, json!([["id", "=", job_id]])));
     request = apply_auth(request, auth);
 
     let response = request.send().context("failed to query job status")?;
     let status = response.status();
+    let content_type = response_content_type(&response);
     let text = response
         .text()
         .context("failed to read job status response body")?;
 
-    if !status.is_success() {
-        bail!("TrueNAS API error ({}): {}", status, text.trim());
+    if !status.is_success() || is_proxy_page(content_type.as_deref(), &text) {
+        return Err(ApiError::new(status, content_type, text).into());
     }
 
     parse_job_response(&text, job_id)
@@ -432,12 +1740,13 @@ fn fetch_job_via_get(
 
     let response = request.send().context("failed to query job status")?;
     let status = response.status();
+    let content_type = response_content_type(&response);
     let text = response
         .text()
         .context("failed to read job status response body")?;
 
-    if !status.is_success() {
-        bail!("TrueNAS API error ({}): {}", status, text.trim());
+    if !status.is_success() || is_proxy_page(content_type.as_deref(), &text) {
+        return Err(ApiError::new(status, content_type, text).into());
     }
 
     parse_job_response(&text, job_id)
@@ -455,7 +1764,7 @@ fn parse_job_response(text: &str, job_id: i64) -> Result<JobInfo> {
     bail!("job {} not found in response", job_id);
 }
 
-fn extract_job(value: &Value, job_id: i64) -> Option<JobInfo> {
+pub(crate) fn extract_job(value: &Value, job_id: i64) -> Option<JobInfo> {
     match value {
         Value::Array(items) => items
             .iter()
@@ -501,5 +1810,609 @@ fn parse_job_info(value: &Value) -> Option<JobInfo> {
         exception,
         progress_percent,
         progress_description,
+        time_started: obj.get("time_started").and_then(parse_job_time),
+        time_finished: obj.get("time_finished").and_then(parse_job_time),
+        result: obj.get("result").filter(|v| !v.is_null()).cloned(),
+        waited: Duration::ZERO,
     })
 }
+
+/// Job timestamps are milliseconds since the epoch, usually wrapped as
+/// `{"$date": ...}` by the middleware's JSON encoder.
+fn parse_job_time(value: &Value) -> Option<SystemTime> {
+    let millis = match value {
+        Value::Object(map) => map.get("$date")?.as_u64()?,
+        other => other.as_u64()?,
+    };
+    UNIX_EPOCH.checked_add(Duration::from_millis(millis))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Matcher;
+    use proptest::prelude::*;
+
+    const BASIC: Auth<'static> = Auth::Basic {
+        username: "root",
+        password: "secret",
+        totp: None,
+    };
+    const API_KEY: Auth<'static> = Auth::ApiKey {
+        key: "1-abc",
+        header: None,
+        scheme: None,
+    };
+
+    fn options() -> UnlockOptions {
+        UnlockOptions {
+            recursive: true,
+            force: false,
+            toggle_attachments: true,
+        }
+    }
+
+    fn server_base_url(server: &mockito::Server) -> Url {
+        parse_base_url(&format!("{}/nas", server.url())).unwrap()
+    }
+
+    #[test]
+    fn unlock_request_with_basic_auth() {
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("POST", "/nas/api/v2.0/pool/dataset/unlock")
+            .match_header("authorization", "Basic cm9vdDpzZWNyZXQ=")
+            .match_header("content-type", "application/json")
+            .match_body(Matcher::Json(json!({
+                "id": "tank/media",
+                "unlock_options": {
+                    "recursive": true,
+                    "force": false,
+                    "toggle_attachments": true,
+                    "key_file": false,
+                    "datasets": [{"name": "tank/media", "passphrase": "hunter2"}],
+                },
+            })))
+            .with_body("17")
+            .create();
+
+        let body = build_unlock_body(
+            "tank/media",
+            UnlockSecret::Passphrase("hunter2"),
+            &[],
+            options(),
+        );
+        let result = unlock_dataset(
+            &Client::new(),
+            &server_base_url(&server),
+            BASIC,
+            "tank/media",
+            &body,
+            Flavor::Scale,
+            0,
+        )
+        .unwrap();
+        mock.assert();
+        assert_eq!(result.job_id, Some(17));
+    }
+
+    #[test]
+    fn unlock_request_with_api_key() {
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("POST", "/nas/api/v2.0/pool/dataset/unlock")
+            .match_header("authorization", "Bearer 1-abc")
+            .match_body(Matcher::PartialJson(json!({
+                "unlock_options": {"datasets": [{"name": "tank/vm", "key": "00ff"}]},
+            })))
+            .with_body(r#"{"unlocked": ["tank/vm"], "failed": {}}"#)
+            .create();
+
+        let body = build_unlock_body("tank/vm", UnlockSecret::Key("00ff"), &[], options());
+        let result = unlock_dataset(
+            &Client::new(),
+            &server_base_url(&server),
+            API_KEY,
+            "tank/vm",
+            &body,
+            Flavor::Scale,
+            0,
+        )
+        .unwrap();
+        mock.assert();
+        assert_eq!(result.unlocked, ["tank/vm"]);
+    }
+
+    #[test]
+    fn api_key_in_a_custom_header() {
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("POST", "/nas/api/v2.0/pool/dataset/lock")
+            .match_header("x-api-key", "1-abc")
+            .match_header("authorization", Matcher::Missing)
+            .with_body("true")
+            .create();
+
+        let auth = Auth::ApiKey {
+            key: "1-abc",
+            header: Some("X-Api-Key"),
+            scheme: None,
+        };
+        let result = lock_dataset(
+            &Client::new(),
+            &server_base_url(&server),
+            auth,
+            "tank/media",
+            false,
+            Flavor::Scale,
+            0,
+        )
+        .unwrap();
+        mock.assert();
+        assert!(result.locked);
+    }
+
+    #[test]
+    fn lock_request_with_basic_and_api_key_auth() {
+        for (auth, header) in [(BASIC, "Basic cm9vdDpzZWNyZXQ="), (API_KEY, "Bearer 1-abc")] {
+            let mut server = mockito::Server::new();
+            let mock = server
+                .mock("POST", "/nas/api/v2.0/pool/dataset/lock")
+                .match_header("authorization", header)
+                .match_body(Matcher::Json(json!({
+                    "id": "tank/media",
+                    "lock_options": {"force_umount": true},
+                })))
+                .with_body("23")
+                .create();
+
+            let result = lock_dataset(
+                &Client::new(),
+                &server_base_url(&server),
+                auth,
+                "tank/media",
+                true,
+                Flavor::Scale,
+                0,
+            )
+            .unwrap();
+            mock.assert();
+            assert_eq!(result.job_id, Some(23));
+        }
+    }
+
+    #[test]
+    fn unlock_401_is_an_auth_error() {
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/nas/api/v2.0/pool/dataset/unlock")
+            .with_status(401)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"message": "Invalid credentials"}"#)
+            .create();
+
+        let body = build_unlock_body("tank/x", UnlockSecret::Passphrase("pw"), &[], options());
+        let err = unlock_dataset(
+            &Client::new(),
+            &server_base_url(&server),
+            BASIC,
+            "tank/x",
+            &body,
+            Flavor::Scale,
+            0,
+        )
+        .err()
+        .expect("a 401 response is an error");
+        assert_eq!(error::classify(&err), ErrorKind::Auth);
+    }
+
+    #[test]
+    fn unlock_200_login_page_is_an_error() {
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/nas/api/v2.0/pool/dataset/unlock")
+            .with_status(200)
+            .with_header("content-type", "text/html; charset=utf-8")
+            .with_body("<!DOCTYPE html><html><body>Sign in</body></html>")
+            .create();
+
+        let body = build_unlock_body("tank/x", UnlockSecret::Passphrase("pw"), &[], options());
+        let err = unlock_dataset(
+            &Client::new(),
+            &server_base_url(&server),
+            BASIC,
+            "tank/x",
+            &body,
+            Flavor::Scale,
+            0,
+        )
+        .err()
+        .expect("an HTML page is not an unlock result");
+        assert_eq!(
+            err.to_string(),
+            "NAS returned a non-JSON 200 OK response; the API may be down or proxied incorrectly"
+        );
+        assert_eq!(error::classify(&err), ErrorKind::Api);
+    }
+
+    #[test]
+    fn api_error_leaves_out_proxy_pages() {
+        let long_text = "upstream failed\n".repeat(20);
+        for (status, content_type, body, proxy_page) in [
+            (
+                502,
+                Some("text/html"),
+                "<html><h1>502 Bad Gateway</h1></html>",
+                true,
+            ),
+            (502, None, "<html><h1>502 Bad Gateway</h1></html>", true),
+            (500, Some("text/plain"), "dataset is busy", false),
+            (500, Some("text/plain"), long_text.as_str(), true),
+            (
+                422,
+                Some("application/json"),
+                r#"{"message": "Invalid key"}"#,
+                false,
+            ),
+            (422, None, r#"{"message": "Invalid key"}"#, false),
+        ] {
+            let status = StatusCode::from_u16(status).unwrap();
+            let error = ApiError::new(status, content_type.map(str::to_string), body.to_string());
+            assert_eq!(error.is_proxy_page(), proxy_page, "{:?}", body);
+            let expected = if proxy_page {
+                format!(
+                    "NAS returned a non-JSON {} response; the API may be down or proxied incorrectly",
+                    status
+                )
+            } else {
+                format!("TrueNAS API error ({}): {}", status, body)
+            };
+            assert_eq!(error.to_string(), expected);
+        }
+        let empty = ApiError::new(StatusCode::BAD_GATEWAY, None, " \n".to_string());
+        assert_eq!(
+            empty.to_string(),
+            "TrueNAS API error (502 Bad Gateway): empty response"
+        );
+    }
+
+    #[test]
+    fn streamed_unlock_body_arrives_intact() {
+        // Large enough to be sent in several chunks.
+        let secret = "pässwörd \"quoted\" \\ ".repeat(8192);
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("POST", "/nas/api/v2.0/pool/dataset/unlock")
+            .match_body(Matcher::Json(json!({
+                "id": "tank/x",
+                "unlock_options": {
+                    "recursive": true,
+                    "force": false,
+                    "toggle_attachments": true,
+                    "key_file": false,
+                    "datasets": [{"name": "tank/x", "passphrase": secret}],
+                },
+            })))
+            .with_body("5")
+            .create();
+
+        let body = build_unlock_body("tank/x", UnlockSecret::Passphrase(&secret), &[], options());
+        unlock_dataset(
+            &Client::new(),
+            &server_base_url(&server),
+            BASIC,
+            "tank/x",
+            &body,
+            Flavor::Scale,
+            0,
+        )
+        .unwrap();
+        mock.assert();
+    }
+
+    #[test]
+    fn unlock_is_not_resent_after_a_307() {
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/nas/api/v2.0/pool/dataset/unlock")
+            .with_status(307)
+            .with_header("location", "/other/api/v2.0/pool/dataset/unlock")
+            .create();
+        let other = server
+            .mock("POST", "/other/api/v2.0/pool/dataset/unlock")
+            .expect(0)
+            .create();
+
+        let body = build_unlock_body("tank/x", UnlockSecret::Passphrase("pw"), &[], options());
+        let err = unlock_dataset(
+            &Client::new(),
+            &server_base_url(&server),
+            BASIC,
+            "tank/x",
+            &body,
+            Flavor::Scale,
+            0,
+        )
+        .err()
+        .expect("a 307 is not followed");
+        other.assert();
+        assert_eq!(error::classify(&err), ErrorKind::Config);
+        assert!(err.to_string().contains("/other/api"), "{}", err);
+    }
+
+    #[test]
+    fn unlock_body_passphrase_mode() {
+        let body = serde_json::to_value(build_unlock_body(
+            "tank/x",
+            UnlockSecret::Passphrase("pw"),
+            &[("tank/x/child", UnlockSecret::Passphrase("pw2"))],
+            options(),
+        ))
+        .unwrap();
+        let datasets = &body["unlock_options"]["datasets"];
+        assert_eq!(datasets[0], json!({"name": "tank/x", "passphrase": "pw"}));
+        assert_eq!(
+            datasets[1],
+            json!({"name": "tank/x/child", "passphrase": "pw2"})
+        );
+        assert!(datasets[0].get("key").is_none());
+    }
+
+    #[test]
+    fn unlock_body_key_mode() {
+        let body = serde_json::to_value(build_unlock_body(
+            "tank/x",
+            UnlockSecret::Key("00ff"),
+            &[],
+            options(),
+        ))
+        .unwrap();
+        let dataset = &body["unlock_options"]["datasets"][0];
+        assert_eq!(dataset, &json!({"name": "tank/x", "key": "00ff"}));
+        assert!(dataset.get("passphrase").is_none());
+        assert_eq!(body["unlock_options"]["key_file"], false);
+    }
+
+    #[test]
+    fn unlock_body_options_map_through() {
+        for (recursive, force, toggle_attachments) in [
+            (true, false, true),
+            (false, true, false),
+            (false, false, false),
+            (true, true, true),
+        ] {
+            let options = UnlockOptions {
+                recursive,
+                force,
+                toggle_attachments,
+            };
+            let body = serde_json::to_value(build_unlock_body(
+                "tank/x",
+                UnlockSecret::Passphrase("pw"),
+                &[],
+                options,
+            ))
+            .unwrap();
+            assert_eq!(body["id"], "tank/x");
+            let unlock_options = &body["unlock_options"];
+            assert_eq!(unlock_options["recursive"], recursive);
+            assert_eq!(unlock_options["force"], force);
+            assert_eq!(unlock_options["toggle_attachments"], toggle_attachments);
+        }
+    }
+
+    #[test]
+    fn change_key_body_passphrase_mode() {
+        let body = serde_json::to_value(build_change_key_body(
+            "tank/x",
+            UnlockSecret::Passphrase("new pw"),
+        ))
+        .unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "id": "tank/x",
+                "change_key_options": {
+                    "generate_key": false,
+                    "key_file": false,
+                    "passphrase": "new pw",
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn change_key_body_key_mode() {
+        let key = "0f".repeat(32);
+        let body =
+            serde_json::to_value(build_change_key_body("tank/x", UnlockSecret::Key(&key))).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "id": "tank/x",
+                "change_key_options": {
+                    "generate_key": false,
+                    "key_file": false,
+                    "key": key,
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn lock_body_force_umount() {
+        for force_umount in [true, false] {
+            let body = serde_json::to_value(build_lock_body("tank/x", force_umount)).unwrap();
+            assert_eq!(
+                body,
+                json!({"id": "tank/x", "lock_options": {"force_umount": force_umount}})
+            );
+        }
+    }
+
+    #[test]
+    fn parse_unlock_response_job_id() {
+        for text in ["42", "\"42\"", " 42\n"] {
+            let result = parse_unlock_response(text).unwrap();
+            assert_eq!(result.job_id, Some(42), "{:?}", text);
+            assert!(result.unlocked.is_empty() && result.failed.is_empty());
+        }
+        let result = parse_unlock_response(r#"{"job_id": 7}"#).unwrap();
+        assert_eq!(result.job_id, Some(7));
+    }
+
+    #[test]
+    fn parse_unlock_response_unlocked_list() {
+        let result =
+            parse_unlock_response(r#"{"unlocked": ["tank/a", "tank/a/b"], "failed": {}}"#).unwrap();
+        assert_eq!(result.job_id, None);
+        assert_eq!(result.unlocked, ["tank/a", "tank/a/b"]);
+        assert!(result.failed.is_empty());
+    }
+
+    #[test]
+    fn parse_unlock_response_failed_map() {
+        let result = parse_unlock_response(
+            r#"{
+                "unlocked": ["tank/a"],
+                "failed": {
+                    "tank/b": {"error": "Invalid Key", "skipped": []},
+                    "tank/c": "Dataset is not locked"
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(result.unlocked, ["tank/a"]);
+        assert_eq!(
+            result.failed,
+            [("tank/b".to_string(), "Invalid Key".to_string())]
+        );
+        assert_eq!(result.skipped, ["tank/c"]);
+    }
+
+    #[test]
+    fn parse_unlock_response_other_text() {
+        let result = parse_unlock_response("").unwrap();
+        assert!(result.job_id.is_none() && result.message.is_none());
+        let result = parse_unlock_response("\"done\"").unwrap();
+        assert_eq!(result.message.as_deref(), Some("done"));
+    }
+
+    #[test]
+    fn is_already_unlocked_reasons() {
+        for (reason, expected) in [
+            ("Dataset is not locked", true),
+            ("tank/x is already unlocked", true),
+            ("Dataset isn't locked", true),
+            ("DATASET ISN’T LOCKED.", true),
+            ("wasn't locked", true),
+            ("already   decrypted", true),
+            ("unlocked already", true),
+            ("tank/x could not be unlocked", false),
+            ("parent is not unlocked", false),
+            ("Invalid Key", false),
+            ("dataset is locked", false),
+            ("", false),
+        ] {
+            assert_eq!(is_already_unlocked(reason), expected, "{:?}", reason);
+        }
+    }
+
+    #[test]
+    fn parse_base_url_keeps_port_and_prefix() {
+        for (host, base) in [
+            ("nas:8443", "https://nas:8443/"),
+            ("nas.local", "https://nas.local/"),
+            ("http://nas.local", "http://nas.local/"),
+            ("https://host/nas", "https://host/nas/"),
+            ("https://host/nas/", "https://host/nas/"),
+            ("https://host/nas//", "https://host/nas/"),
+            ("https://host:8443/a/b?x=1#top", "https://host:8443/a/b/"),
+            ("[::1]:443", "https://[::1]/"),
+            ("[::1]:8443", "https://[::1]:8443/"),
+            ("::1", "https://[::1]/"),
+        ] {
+            let url = parse_base_url(host).unwrap();
+            assert_eq!(url.as_str(), base, "{:?}", host);
+            assert_eq!(
+                url.join(UNLOCK_PATH).unwrap().as_str(),
+                format!("{}{}", base, UNLOCK_PATH),
+                "{:?}",
+                host
+            );
+        }
+    }
+
+    #[test]
+    fn parse_base_url_rejects_bad_hosts() {
+        for host in [
+            "",
+            "  ",
+            "ftp://nas",
+            "https://",
+            "https://user:pw@nas",
+            "nas\n.local",
+        ] {
+            assert!(parse_base_url(host).is_err(), "{:?} was accepted", host);
+        }
+    }
+
+    #[test]
+    fn dataset_response_lists_locked_descendants() {
+        let text = json!([{
+            "id": "tank/data",
+            "encrypted": true,
+            "locked": false,
+            "children": [
+                {"id": "tank/data/a", "encrypted": true, "locked": false, "children": [
+                    {"id": "tank/data/a/deep", "encrypted": true, "locked": true, "children": []}
+                ]},
+                {"id": "tank/data/b", "encrypted": true, "locked": true, "children": []},
+                {"id": "tank/data/plain", "encrypted": false, "locked": true},
+            ]
+        }])
+        .to_string();
+        let info = parse_dataset_response(&text, "tank/data").unwrap();
+        assert!(!info.locked);
+        assert_eq!(info.locked_descendants, ["tank/data/a/deep", "tank/data/b"]);
+
+        let bare = json!([{"id": "tank/data", "encrypted": true, "locked": false}]).to_string();
+        let info = parse_dataset_response(&bare, "tank/data").unwrap();
+        assert!(info.locked_descendants.is_empty());
+    }
+
+    /// Checks what every command relies on: a base URL without query or
+    /// fragment, whose path ends in `/` so API paths are appended to it.
+    fn assert_joinable(host: &str) {
+        let Ok(url) = parse_base_url(host) else {
+            return;
+        };
+        assert_eq!(url.query(), None, "{:?}", host);
+        assert_eq!(url.fragment(), None, "{:?}", host);
+        assert!(url.path().ends_with('/'), "{:?} gave {}", host, url);
+        assert!(url.host_str().is_some_and(|h| !h.is_empty()), "{:?}", host);
+        let joined = url.join(UNLOCK_PATH).unwrap();
+        assert_eq!(joined.origin(), url.origin(), "{:?}", host);
+        assert_eq!(
+            joined.path(),
+            format!("{}{}", url.path(), UNLOCK_PATH),
+            "{:?} gave {}",
+            host,
+            url
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn parse_base_url_arbitrary_input(host in any::<String>()) {
+            assert_joinable(&host);
+        }
+
+        #[test]
+        fn parse_base_url_url_like_input(
+            host in "(https?://|HTTP://|ftp://|//)?(\\[[0-9a-f:]{0,12}\\]|[a-z0-9.@:-]{0,16})(:[0-9]{0,6})?(/[a-z0-9./%;@:-]{0,8}){0,3}/?(\\?[a-z=&]{0,6})?(#[a-z/]{0,6})?"
+        ) {
+            assert_joinable(&host);
+        }
+    }
+}
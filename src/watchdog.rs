@@ -0,0 +1,85 @@
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+static WATCHDOG: OnceLock<Watchdog> = OnceLock::new();
+
+struct Watchdog {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+struct State {
+    /// Time left on the budget when the clock was last stopped or started.
+    remaining: Duration,
+    /// When the clock was last (re)started; `None` while paused.
+    running_since: Option<Instant>,
+}
+
+/// Starts a watchdog thread that calls `on_timeout` and exits the process
+/// with the status it returns once `limit` of wall-clock time has been spent
+/// outside of [`paused`] sections. Only the first call has any effect.
+pub fn start(limit: Duration, on_timeout: impl FnOnce() -> u8 + Send + 'static) {
+    let watchdog = Watchdog {
+        state: Mutex::new(State {
+            remaining: limit,
+            running_since: Some(Instant::now()),
+        }),
+        changed: Condvar::new(),
+    };
+    if WATCHDOG.set(watchdog).is_err() {
+        return;
+    }
+
+    std::thread::spawn(move || {
+        let watchdog = WATCHDOG.get().expect("watchdog initialized");
+        let mut state = watchdog.state.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            state = match state.running_since {
+                None => watchdog
+                    .changed
+                    .wait(state)
+                    .unwrap_or_else(|e| e.into_inner()),
+                Some(since) => {
+                    let left = state.remaining.saturating_sub(since.elapsed());
+                    if left.is_zero() {
+                        break;
+                    }
+                    watchdog
+                        .changed
+                        .wait_timeout(state, left)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+            };
+        }
+        let code = on_timeout();
+        std::process::exit(code.into());
+    });
+}
+
+/// Stops the watchdog clock until the returned guard is dropped. Used around
+/// interactive prompts, where the wait is on a human rather than on naslock.
+pub fn paused() -> PauseGuard {
+    if let Some(watchdog) = WATCHDOG.get() {
+        let mut state = watchdog.state.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(since) = state.running_since.take() {
+            state.remaining = state.remaining.saturating_sub(since.elapsed());
+        }
+        watchdog.changed.notify_all();
+    }
+    PauseGuard { _private: () }
+}
+
+pub struct PauseGuard {
+    _private: (),
+}
+
+impl Drop for PauseGuard {
+    fn drop(&mut self) {
+        if let Some(watchdog) = WATCHDOG.get() {
+            let mut state = watchdog.state.lock().unwrap_or_else(|e| e.into_inner());
+            state.running_since = Some(Instant::now());
+            watchdog.changed.notify_all();
+        }
+    }
+}
@@ -0,0 +1,558 @@
+use crate::config::Flavor;
+use crate::error::{self, ErrorKind, ResultExt};
+use crate::truenas::{
+    self, Auth, ClientOptions, DatasetInfo, JobInfo, JobWatch, LockResult, Transport,
+    UnlockOptions, UnlockResult, UnlockSecret, WaitOptions,
+};
+use anyhow::{Context, Result, bail};
+use serde_json::{Value, json};
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+use tungstenite::client::IntoClientRequest;
+use tungstenite::http::HeaderValue;
+use tungstenite::http::header::USER_AGENT;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Connector, HandshakeError, Message, WebSocket};
+use url::Url;
+use zeroize::Zeroizing;
+
+/// Path of the JSON-RPC endpoint relative to the NAS base URL.
+const API_PATH: &str = "api/current";
+
+/// An error returned by a JSON-RPC call.
+#[derive(Debug)]
+pub struct RpcError {
+    pub method: String,
+    /// Symbolic error name such as `EINVAL` or `ENOTAUTHENTICATED`, if given.
+    pub errname: Option<String>,
+    pub reason: String,
+}
+
+impl RpcError {
+    pub fn is_auth(&self) -> bool {
+        matches!(
+            self.errname.as_deref(),
+            Some("ENOTAUTHENTICATED" | "EACCES" | "EPERM")
+        )
+    }
+
+    fn from_value(method: &str, value: &Value) -> Self {
+        let data = value.get("data");
+        let reason = data
+            .and_then(|data| data.get("reason"))
+            .or_else(|| value.get("message"))
+            .and_then(Value::as_str)
+            .unwrap_or("unknown error");
+        RpcError {
+            method: method.to_string(),
+            errname: data
+                .and_then(|data| data.get("errname"))
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            reason: reason.trim().to_string(),
+        }
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TrueNAS API error ({}): {}", self.method, self.reason)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+/// The JSON-RPC 2.0 API that newer TrueNAS releases serve over a websocket
+/// at `/api/current`. One authenticated connection carries every call, and
+/// job progress arrives as `core.get_jobs` events instead of being polled.
+pub struct Session {
+    socket: WebSocket<MaybeTlsStream<TcpStream>>,
+    next_id: u64,
+    timeout: Duration,
+    /// Job events that arrived while waiting for the response to a call.
+    job_events: Vec<Value>,
+}
+
+impl Session {
+    pub fn connect(base_url: &Url, options: ClientOptions<'_>, auth: Auth<'_>) -> Result<Self> {
+        let mut url = base_url
+            .join(API_PATH)
+            .context("failed to build websocket URL")?;
+        let tls = url.scheme() == "https";
+        url.set_scheme(if tls { "wss" } else { "ws" })
+            .map_err(|()| anyhow::anyhow!("failed to build websocket URL from {}", base_url))?;
+
+        let addrs = url
+            .socket_addrs(|| None)
+            .with_context(|| format!("failed to resolve {}", url))
+            .kind(ErrorKind::Network)?;
+        let stream = connect_tcp(&addrs, options.connect_timeout.min(options.timeout))
+            .with_context(|| format!("failed to connect to {}", url))
+            .kind(ErrorKind::Network)?;
+        tracing::debug!(%url, "connecting over websocket");
+        stream.set_read_timeout(Some(options.timeout))?;
+        stream.set_write_timeout(Some(options.timeout))?;
+
+        let connector = if tls {
+            let mut builder = native_tls::TlsConnector::builder();
+            builder.danger_accept_invalid_certs(options.skip_tls_verify);
+            if let Some(path) = options.ca_cert {
+                let pem = truenas::read_ca_pem(path)?;
+                let cert = native_tls::Certificate::from_pem(&pem)
+                    .with_context(|| format!("invalid ca_cert {}", path.display()))?;
+                builder
+                    .disable_built_in_roots(true)
+                    .add_root_certificate(cert);
+            }
+            if let Some((cert, key)) = options.client_identity {
+                let (cert_pem, key_pem) = truenas::read_identity_pem(cert, key)?;
+                builder.identity(
+                    native_tls::Identity::from_pkcs8(&cert_pem, &key_pem).with_context(|| {
+                        format!(
+                            "invalid client certificate {} or key {} (the key must be PKCS#8 PEM)",
+                            cert.display(),
+                            key.display()
+                        )
+                    })?,
+                );
+            }
+            Connector::NativeTls(builder.build().context("failed to set up TLS")?)
+        } else {
+            Connector::Plain
+        };
+
+        let mut request = url
+            .as_str()
+            .into_client_request()
+            .context("failed to build websocket request")?;
+        request.headers_mut().insert(
+            USER_AGENT,
+            HeaderValue::from_str(options.user_agent).context("invalid user_agent")?,
+        );
+        let (socket, _) =
+            tungstenite::client_tls_with_config(request, stream, None, Some(connector))
+                .map_err(|err| match err {
+                    HandshakeError::Failure(err) => anyhow::Error::new(err),
+                    HandshakeError::Interrupted(_) => {
+                        anyhow::anyhow!("websocket handshake timed out")
+                    }
+                })
+                .with_context(|| format!("failed to open websocket {}", url))
+                .kind(ErrorKind::Network)?;
+
+        let mut session = Session {
+            socket,
+            next_id: 1,
+            timeout: options.timeout,
+            job_events: Vec::new(),
+        };
+        session.login(auth)?;
+        Ok(session)
+    }
+
+    fn login(&mut self, auth: Auth<'_>) -> Result<()> {
+        let accepted = match auth {
+            Auth::Basic {
+                username,
+                password,
+                totp: None,
+            } => self.call("auth.login", json!([username, password]))?,
+            Auth::Basic {
+                username,
+                password,
+                totp: Some(totp),
+            } => {
+                // Computed here rather than with the other secrets, so hooks
+                // and queued volumes cannot outlast the code's time step.
+                let otp = Zeroizing::new(
+                    totp.generate_current()
+                        .context("system clock is before the Unix epoch")?,
+                );
+                self.call("auth.login", json!([username, password, otp.as_str()]))?
+            }
+            Auth::ApiKey { key, .. } | Auth::ApiKeyBasic { key } => {
+                self.call("auth.login_with_api_key", json!([key]))?
+            }
+            Auth::Token { token } => self.call("auth.login_with_token", json!([token]))?,
+        };
+        if accepted != Value::Bool(true) {
+            return Err(error::tag(
+                ErrorKind::Auth,
+                anyhow::anyhow!("TrueNAS rejected the credentials"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Sends one request and reads messages until its response arrives,
+    /// keeping any job events that come in between.
+    fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id;
+        self.next_id += 1;
+        // Parameters can carry passwords and passphrases, so only the method is logged.
+        tracing::debug!(id, method, "websocket call");
+        let request = json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params});
+        self.socket
+            .send(Message::text(request.to_string()))
+            .with_context(|| format!("failed to send {} request", method))?;
+
+        self.set_read_timeout(self.timeout)?;
+        loop {
+            let Some(message) = self.read()? else {
+                bail!("timed out waiting for the response to {}", method);
+            };
+            if message.get("id").and_then(Value::as_u64) != Some(id) {
+                self.keep_job_event(message);
+                continue;
+            }
+            if let Some(err) = message.get("error") {
+                return Err(RpcError::from_value(method, err).into());
+            }
+            return Ok(message.get("result").cloned().unwrap_or(Value::Null));
+        }
+    }
+
+    /// Reads the next JSON message, or `None` if the read timeout expired.
+    fn read(&mut self) -> Result<Option<Value>> {
+        loop {
+            match self.socket.read() {
+                Ok(Message::Text(text)) => {
+                    let value = serde_json::from_str(text.as_str()).with_context(|| {
+                        format!("failed to parse websocket message: {}", text.as_str())
+                    })?;
+                    return Ok(Some(value));
+                }
+                Ok(Message::Close(_)) => {
+                    return Err(error::tag(
+                        ErrorKind::Network,
+                        anyhow::anyhow!("the NAS closed the websocket connection"),
+                    ));
+                }
+                // Pings are answered by tungstenite itself.
+                Ok(_) => {}
+                Err(tungstenite::Error::Io(err))
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Ok(None);
+                }
+                Err(err) => return Err(anyhow::Error::new(err)),
+            }
+        }
+    }
+
+    fn set_read_timeout(&mut self, timeout: Duration) -> Result<()> {
+        // A zero timeout means "block forever" to the socket.
+        let timeout = Some(timeout.max(Duration::from_millis(1)));
+        match self.socket.get_mut() {
+            MaybeTlsStream::Plain(stream) => stream.set_read_timeout(timeout)?,
+            MaybeTlsStream::NativeTls(stream) => stream.get_ref().set_read_timeout(timeout)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Keeps the job fields of a `core.get_jobs` collection update.
+    fn keep_job_event(&mut self, message: Value) {
+        let Some(params) = message.get("params") else {
+            return;
+        };
+        if message.get("method").and_then(Value::as_str) != Some("collection_update")
+            || params.get("collection").and_then(Value::as_str) != Some("core.get_jobs")
+        {
+            return;
+        }
+        let mut fields = params.get("fields").cloned().unwrap_or(Value::Null);
+        if let (Some(object), Some(id)) = (fields.as_object_mut(), params.get("id")) {
+            object.entry("id").or_insert_with(|| id.clone());
+            self.job_events.push(fields);
+        }
+    }
+
+    /// Waits up to `timeout` for an event about `job_id` and returns the most
+    /// recent state it reports.
+    fn next_job_event(&mut self, job_id: i64, timeout: Duration) -> Result<Option<JobInfo>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let latest = self
+                .job_events
+                .drain(..)
+                .filter_map(|event| truenas::extract_job(&event, job_id))
+                .next_back();
+            if latest.is_some() {
+                return Ok(latest);
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            self.set_read_timeout(remaining)?;
+            match self.read()? {
+                Some(message) => self.keep_job_event(message),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn query_job(&mut self, job_id: i64) -> Result<JobInfo> {
+        let jobs = self.call("core.get_jobs", json!([[["id", "=", job_id]]]))?;
+        truenas::extract_job(&jobs, job_id)
+            .with_context(|| format!("job {} not found in response", job_id))
+    }
+
+    /// Finds the job that caused a conflict so the caller can wait for it,
+    /// like the REST transport does.
+    fn attach_to_running_job(
+        &mut self,
+        method: &str,
+        dataset: &str,
+        err: anyhow::Error,
+    ) -> Result<i64> {
+        // `/api/current` only exists on SCALE.
+        let job_id = self
+            .call(
+                "core.get_jobs",
+                truenas::running_jobs_query(Flavor::Scale, method),
+            )
+            .ok()
+            .and_then(|jobs| truenas::find_job_with_arg(&jobs, dataset));
+        job_id.ok_or_else(|| {
+            error::tag(
+                ErrorKind::Rejected,
+                err.context(format!("operation already in progress on {}", dataset)),
+            )
+        })
+    }
+}
+
+impl Transport for Session {
+    fn unlock(
+        &mut self,
+        dataset: &str,
+        secret: UnlockSecret<'_>,
+        children: &[(&str, UnlockSecret<'_>)],
+        options: UnlockOptions,
+    ) -> Result<UnlockResult> {
+        let body = serde_json::to_value(truenas::build_unlock_body(
+            dataset, secret, children, options,
+        ))?;
+        match self.call(
+            "pool.dataset.unlock",
+            json!([body["id"], body["unlock_options"]]),
+        ) {
+            Ok(job_id) => Ok(UnlockResult {
+                job_id: Some(job_id.as_i64().context("unlock did not return a job id")?),
+                ..UnlockResult::default()
+            }),
+            Err(err) if rpc_reason_contains(&err, &["in progress", "already running"]) => {
+                let job_id = self.attach_to_running_job("pool.dataset.unlock", dataset, err)?;
+                Ok(UnlockResult {
+                    job_id: Some(job_id),
+                    ..UnlockResult::default()
+                })
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn lock(&mut self, dataset: &str, force_umount: bool) -> Result<LockResult> {
+        let body = serde_json::to_value(truenas::build_lock_body(dataset, force_umount))?;
+        match self.call(
+            "pool.dataset.lock",
+            json!([body["id"], body["lock_options"]]),
+        ) {
+            Ok(job_id) => Ok(LockResult {
+                job_id: Some(job_id.as_i64().context("lock did not return a job id")?),
+                ..LockResult::default()
+            }),
+            Err(err) if rpc_reason_contains(&err, &["already locked"]) => {
+                Ok(truenas::already_locked())
+            }
+            Err(err) if rpc_reason_contains(&err, &["in progress", "already running"]) => {
+                let job_id = self.attach_to_running_job("pool.dataset.lock", dataset, err)?;
+                Ok(LockResult {
+                    job_id: Some(job_id),
+                    ..LockResult::default()
+                })
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn change_key(&mut self, dataset: &str, secret: UnlockSecret<'_>) -> Result<i64> {
+        let body = serde_json::to_value(truenas::build_change_key_body(dataset, secret))?;
+        let job_id = self.call(
+            "pool.dataset.change_key",
+            json!([body["id"], body["change_key_options"]]),
+        )?;
+        job_id
+            .as_i64()
+            .context("change_key did not return a job id")
+    }
+
+    fn dataset(&mut self, dataset: &str) -> Result<DatasetInfo> {
+        let found = self.call("pool.dataset.query", json!([[["id", "=", dataset]]]))?;
+        truenas::parse_dataset_response(&found.to_string(), dataset)
+    }
+
+    /// Subscribes to job events and follows them, querying the job directly
+    /// whenever a poll interval passes without an event.
+    fn wait_for_job(&mut self, job_id: i64, wait: WaitOptions) -> Result<JobInfo> {
+        self.call("core.subscribe", json!(["core.get_jobs"]))?;
+        let mut watch = JobWatch::new(job_id, wait);
+        let mut job = self.query_job(job_id)?;
+        loop {
+            let state = job.state.clone();
+            if let Some(result) = watch.update(job) {
+                return result;
+            }
+            let next_check = watch.next_check(state.as_deref())?;
+            job = match self.next_job_event(job_id, next_check)? {
+                Some(job) => job,
+                None => self.query_job(job_id)?,
+            };
+        }
+    }
+}
+
+fn connect_tcp(addrs: &[SocketAddr], timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no addresses found");
+    for addr in addrs {
+        match TcpStream::connect_timeout(addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+fn rpc_reason_contains(err: &anyhow::Error, needles: &[&str]) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<RpcError>())
+        .any(|rpc| {
+            let reason = rpc.reason.to_ascii_lowercase();
+            needles.iter().any(|needle| reason.contains(needle))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn options() -> ClientOptions<'static> {
+        ClientOptions {
+            skip_tls_verify: false,
+            max_redirects: 0,
+            allow_cross_host_redirect: false,
+            ca_cert: None,
+            proxy: None,
+            client_identity: None,
+            timeout: Duration::from_secs(5),
+            connect_timeout: Duration::from_secs(5),
+            user_agent: "naslock-test",
+        }
+    }
+
+    /// Serves one websocket connection on a loopback port, answering each
+    /// request with `respond(method, params)`, which returns the `result` or
+    /// `error` member of the response. Returns the base URL and the requests
+    /// the server received, once the client hangs up.
+    fn serve(
+        respond: impl Fn(&str, &Value) -> (&'static str, Value) + Send + 'static,
+    ) -> (Url, thread::JoinHandle<Vec<Value>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            let mut requests = Vec::new();
+            while let Ok(message) = socket.read() {
+                let Message::Text(text) = message else {
+                    continue;
+                };
+                let request: Value = serde_json::from_str(text.as_str()).unwrap();
+                let method = request["method"].as_str().unwrap().to_string();
+                // A job event ahead of the response, as the NAS may send.
+                let event = json!({"msg": "changed", "collection": "core.get_jobs",
+                    "fields": {"id": 7, "state": "RUNNING"}});
+                socket.send(Message::text(event.to_string())).unwrap();
+                let (member, value) = respond(&method, &request["params"]);
+                let response = json!({"jsonrpc": "2.0", "id": request["id"], member: value});
+                socket.send(Message::text(response.to_string())).unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn logs_in_and_queries_a_dataset() {
+        let (url, server) = serve(|method, _| match method {
+            "auth.login" => ("result", json!(true)),
+            "pool.dataset.query" => (
+                "result",
+                json!([{"id": "tank/media", "encrypted": true, "locked": true}]),
+            ),
+            _ => (
+                "error",
+                json!({"code": -32601, "message": "Method not found"}),
+            ),
+        });
+        let auth = Auth::Basic {
+            username: "root",
+            password: "secret",
+            totp: None,
+        };
+        let mut session = Session::connect(&url, options(), auth).unwrap();
+        let info = session.dataset("tank/media").unwrap();
+        assert!(info.encrypted && info.locked);
+        drop(session);
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0]["method"], "auth.login");
+        assert_eq!(requests[0]["params"], json!(["root", "secret"]));
+        assert_eq!(requests[1]["method"], "pool.dataset.query");
+        assert_eq!(requests[1]["params"], json!([[["id", "=", "tank/media"]]]));
+        assert_ne!(requests[0]["id"], requests[1]["id"]);
+    }
+
+    #[test]
+    fn rejected_login_is_an_auth_error() {
+        let (url, server) = serve(|_, _| ("result", json!(false)));
+        let auth = Auth::ApiKeyBasic { key: "1-abc" };
+        let err = Session::connect(&url, options(), auth).err().unwrap();
+        assert_eq!(error::classify(&err), ErrorKind::Auth);
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0]["method"], "auth.login_with_api_key");
+        assert_eq!(requests[0]["params"], json!(["1-abc"]));
+    }
+
+    #[test]
+    fn rpc_error_carries_its_errname() {
+        let (url, server) = serve(|method, _| match method {
+            "auth.login_with_token" => ("result", json!(true)),
+            _ => (
+                "error",
+                json!({"code": -32001, "message": "Method call error",
+                    "data": {"errname": "EINVAL", "reason": "tank/nope does not exist\n"}}),
+            ),
+        });
+        let mut session = Session::connect(&url, options(), Auth::Token { token: "t" }).unwrap();
+        let err = session.dataset("tank/nope").unwrap_err();
+        let rpc = err.downcast_ref::<RpcError>().unwrap();
+        assert_eq!(rpc.method, "pool.dataset.query");
+        assert_eq!(rpc.errname.as_deref(), Some("EINVAL"));
+        assert_eq!(rpc.reason, "tank/nope does not exist");
+        drop(session);
+        server.join().unwrap();
+    }
+}